@@ -0,0 +1,160 @@
+// Used to stress test the API relay. Start the relay, then run e.g.
+//
+//     cargo run --release --example loadtest -- --url http://127.0.0.1:8080/spins/get \
+//         --concurrency 64 --duration 30
+//
+// Each worker sends requests back to back over its own keep-alive connection
+// and the run ends with throughput and latency percentiles. Pass --revalidate to
+// send the ETag from the first response back in If-None-Match, like a polling
+// browser would.
+
+use std::{
+    collections::BTreeMap,
+    env, process,
+    time::{Duration, Instant},
+};
+
+struct Options {
+    url: String,
+    concurrency: usize,
+    duration: Duration,
+    revalidate: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: loadtest --url <url> [--concurrency <n>] [--duration <secs>] [--revalidate]");
+    process::exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        url: String::new(),
+        concurrency: 32,
+        duration: Duration::from_secs(10),
+        revalidate: false,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--url" => options.url = value(),
+            "--concurrency" => options.concurrency = value().parse().unwrap_or_else(|_| usage()),
+            "--duration" => {
+                options.duration = Duration::from_secs(value().parse().unwrap_or_else(|_| usage()))
+            }
+            "--revalidate" => options.revalidate = true,
+            _ => usage(),
+        }
+    }
+    if options.url.is_empty() || options.concurrency == 0 {
+        usage();
+    }
+    options
+}
+
+#[derive(Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, u64>,
+    errors: u64,
+}
+
+async fn worker(client: reqwest::Client, url: String, until: Instant, revalidate: bool) -> Results {
+    let mut results = Results::default();
+    let mut etag: Option<String> = None;
+    while Instant::now() < until {
+        let mut request = client.get(&url);
+        if let Some(etag) = &etag {
+            request = request.header("If-None-Match", etag);
+        }
+
+        let start = Instant::now();
+        let resp = match request.send().await {
+            Ok(resp) => resp,
+            Err(_) => {
+                results.errors += 1;
+                continue;
+            }
+        };
+        let status = resp.status().as_u16();
+        if revalidate && etag.is_none() {
+            etag = resp
+                .headers()
+                .get("etag")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+        }
+        // Read the whole body so the timing includes the transfer
+        if resp.bytes().await.is_err() {
+            results.errors += 1;
+            continue;
+        }
+        results.latencies.push(start.elapsed());
+        *results.statuses.entry(status).or_default() += 1;
+    }
+    results
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_args();
+    let client = reqwest::Client::builder()
+        .pool_max_idle_per_host(options.concurrency)
+        .build()
+        .unwrap();
+
+    println!(
+        "{} workers requesting {} for {}s",
+        options.concurrency,
+        options.url,
+        options.duration.as_secs()
+    );
+    let start = Instant::now();
+    let until = start + options.duration;
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|_| {
+            tokio::spawn(worker(
+                client.clone(),
+                options.url.clone(),
+                until,
+                options.revalidate,
+            ))
+        })
+        .collect();
+
+    let mut total = Results::default();
+    for worker in workers {
+        let results = worker.await.unwrap();
+        total.latencies.extend(results.latencies);
+        total.errors += results.errors;
+        for (status, count) in results.statuses {
+            *total.statuses.entry(status).or_default() += count;
+        }
+    }
+    let elapsed = start.elapsed();
+    total.latencies.sort();
+
+    println!(
+        "{} requests in {:.1}s: {:.0} req/s",
+        total.latencies.len(),
+        elapsed.as_secs_f64(),
+        total.latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    for (name, p) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+        println!("  {}: {:?}", name, percentile(&total.latencies, p));
+    }
+    for (status, count) in &total.statuses {
+        println!("  {}: {}", status, count);
+    }
+    if total.errors > 0 {
+        println!("  errors: {}", total.errors);
+    }
+}
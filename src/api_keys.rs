@@ -0,0 +1,548 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use warp::{reject::Reject, Filter, Rejection};
+
+use crate::config::Config;
+
+// A key can be sent in either of these; the header wins if both are present
+const KEY_HEADER: &str = "x-api-key";
+const KEY_QUERY: &str = "api_key";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientKey {
+    // Identifies the client in usage reports; must be unique
+    pub name: String,
+    pub key: String,
+    // Route names this key may call, e.g. "spins/get". Every route if omitted.
+    pub routes: Option<Vec<String>>,
+    // Requests allowed per UTC day
+    pub daily_quota: Option<u64>,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default, rename = "key")]
+    keys: Vec<ClientKey>,
+}
+
+/// Parses a keys file into a map from key to client.
+pub fn parse_keys(contents: &str) -> Result<HashMap<String, ClientKey>, String> {
+    let file: KeysFile = toml::from_str(contents).map_err(|e| e.to_string())?;
+    let mut names = HashSet::new();
+    let mut keys = HashMap::new();
+    for client in file.keys {
+        if !names.insert(client.name.clone()) {
+            return Err(format!("duplicate client name \"{}\"", client.name));
+        }
+        if let Some(other) = keys.insert(client.key.clone(), client) {
+            return Err(format!("\"{}\" reuses another client's key", other.name));
+        }
+    }
+    Ok(keys)
+}
+
+/// Why a request's key was refused. Turned into a response by
+/// `filters::handle_rejection`.
+#[derive(Debug, PartialEq)]
+pub enum KeyRejection {
+    Missing,
+    Invalid,
+    RouteNotAllowed,
+    QuotaExceeded { retry_after: Duration },
+}
+
+impl Reject for KeyRejection {}
+
+#[derive(Default, Serialize)]
+struct Usage {
+    total: u64,
+    // Requests refused for route or quota reasons
+    rejected: u64,
+    routes: HashMap<&'static str, u64>,
+    #[serde(skip)]
+    day: Option<NaiveDate>,
+    today: u64,
+    last_used: Option<DateTime<Utc>>,
+}
+
+struct Inner {
+    required: bool,
+    admin_token: Option<String>,
+    keys: RwLock<HashMap<String, ClientKey>>,
+    // Keyed by client name so counts survive a key being rotated
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+/// The set of client keys and their usage. Cloning shares both. A registry built
+/// without an `[api_keys]` section lets every request through.
+#[derive(Clone)]
+pub struct KeyRegistry {
+    inner: Option<Arc<Inner>>,
+}
+
+impl KeyRegistry {
+    /// Loads the keys file and starts watching it for changes.
+    pub fn from_config(config: &Config) -> KeyRegistry {
+        let api_keys = match &config.api_keys {
+            Some(api_keys) => api_keys,
+            None => return KeyRegistry { inner: None },
+        };
+
+        let contents = fs::read_to_string(&api_keys.keys_path).unwrap_or_else(|e| {
+            panic!(
+                "Couldn't read keys file {}: {}",
+                api_keys.keys_path.display(),
+                e
+            )
+        });
+        let keys = parse_keys(&contents).unwrap_or_else(|e| {
+            panic!("Invalid keys file {}: {}", api_keys.keys_path.display(), e)
+        });
+
+        let registry = KeyRegistry::new(keys, api_keys.required, api_keys.admin_token.clone());
+        tokio::spawn(registry.clone().watch(
+            api_keys.keys_path.clone(),
+            Duration::from_secs(api_keys.reload_interval_secs),
+            contents,
+        ));
+        registry
+    }
+
+    pub fn new(
+        keys: HashMap<String, ClientKey>,
+        required: bool,
+        admin_token: Option<String>,
+    ) -> KeyRegistry {
+        KeyRegistry {
+            inner: Some(Arc::new(Inner {
+                required,
+                admin_token,
+                keys: RwLock::new(keys),
+                usage: Mutex::new(HashMap::new()),
+            })),
+        }
+    }
+
+    // Re-reads the keys file whenever its contents change, so keys can be added
+    // or revoked without a restart. A file that fails to parse is ignored.
+    async fn watch(self, path: PathBuf, interval: Duration, mut last: String) {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!("Couldn't read keys file {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if contents == last {
+                continue;
+            }
+
+            match parse_keys(&contents) {
+                Ok(keys) => {
+                    info!(
+                        "Reloaded {} client keys from {}",
+                        keys.len(),
+                        path.display()
+                    );
+                    self.replace_keys(keys);
+                }
+                Err(e) => warn!(
+                    "Invalid keys file {}, keeping current keys: {}",
+                    path.display(),
+                    e
+                ),
+            }
+            last = contents;
+        }
+    }
+
+    pub fn replace_keys(&self, keys: HashMap<String, ClientKey>) {
+        if let Some(inner) = &self.inner {
+            *inner.keys.write().unwrap() = keys;
+        }
+    }
+
+    fn authorize(
+        &self,
+        route: &'static str,
+        key: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<(), KeyRejection> {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return Ok(()),
+        };
+        let key = match key {
+            Some(key) => key,
+            None if inner.required => return Err(KeyRejection::Missing),
+            None => return Ok(()),
+        };
+
+        let keys = inner.keys.read().unwrap();
+        let client = match keys.get(key) {
+            Some(client) if !client.revoked => client,
+            _ => return Err(KeyRejection::Invalid),
+        };
+
+        let mut usage = inner.usage.lock().unwrap();
+        let usage = usage.entry(client.name.clone()).or_default();
+
+        if let Some(routes) = &client.routes {
+            if !routes.iter().any(|allowed| allowed == route) {
+                usage.rejected += 1;
+                return Err(KeyRejection::RouteNotAllowed);
+            }
+        }
+
+        let today = now.date_naive();
+        if usage.day != Some(today) {
+            usage.day = Some(today);
+            usage.today = 0;
+        }
+        if let Some(quota) = client.daily_quota {
+            if usage.today >= quota {
+                usage.rejected += 1;
+                let midnight = today.succ_opt().unwrap().and_hms_opt(0, 0, 0).unwrap();
+                let retry_after = (midnight.and_utc() - now).to_std().unwrap_or_default();
+                return Err(KeyRejection::QuotaExceeded { retry_after });
+            }
+        }
+
+        usage.today += 1;
+        usage.total += 1;
+        *usage.routes.entry(route).or_default() += 1;
+        usage.last_used = Some(now);
+        Ok(())
+    }
+
+    /// Whether client keys are configured, required or not.
+    pub fn enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// Whether the admin endpoints are turned on at all.
+    pub fn admin_enabled(&self) -> bool {
+        matches!(&self.inner, Some(inner) if inner.admin_token.is_some())
+    }
+
+    /// Checks an `Authorization: Bearer <token>` header against the admin token.
+    pub fn is_admin(&self, authorization: Option<&str>) -> bool {
+        let token = match self
+            .inner
+            .as_ref()
+            .and_then(|inner| inner.admin_token.as_ref())
+        {
+            Some(token) => token,
+            None => return false,
+        };
+        bearer_matches(authorization, token)
+    }
+
+    /// Usage counters for every configured client, including revoked ones.
+    pub fn usage_report(&self) -> Value {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return json!({ "clients": {} }),
+        };
+        let keys = inner.keys.read().unwrap();
+        let usage = inner.usage.lock().unwrap();
+
+        let mut clients = serde_json::Map::new();
+        for client in keys.values() {
+            let mut entry = match usage.get(&client.name) {
+                Some(usage) => serde_json::to_value(usage).unwrap(),
+                None => serde_json::to_value(Usage::default()).unwrap(),
+            };
+            entry["revoked"] = json!(client.revoked);
+            entry["daily_quota"] = json!(client.daily_quota);
+            clients.insert(client.name.clone(), entry);
+        }
+        json!({ "clients": clients })
+    }
+}
+
+/// Whether an `Authorization` header is `Bearer <token>`.
+pub fn bearer_matches(authorization: Option<&str>, token: &str) -> bool {
+    match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+        Some(given) => constant_time_eq(given.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks the request's client key against the registry and counts the request
+/// against it. Like `rate_limit::limit`, this belongs after the route's path match.
+pub fn authorize(
+    registry: KeyRegistry,
+    route: &'static str,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>(KEY_HEADER)
+        .and(
+            warp::query::<HashMap<String, String>>()
+                .or(warp::any().map(HashMap::new))
+                .unify(),
+        )
+        .and_then(
+            move |header: Option<String>, query: HashMap<String, String>| {
+                let key = header
+                    .as_deref()
+                    .or(query.get(KEY_QUERY).map(String::as_str));
+                let result = registry.authorize(route, key, Utc::now());
+                async move { result.map_err(warp::reject::custom) }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use warp::{http::StatusCode, test::request, Filter};
+
+    use crate::config;
+    use crate::filters;
+    use crate::rate_limit::RateLimiter;
+
+    use super::{authorize, parse_keys, KeyRegistry, KeyRejection};
+
+    const KEYS: &str = r#"
+        [[key]]
+        name = "newspaper"
+        key = "np-secret"
+
+        [[key]]
+        name = "display-vendor"
+        key = "dv-secret"
+        routes = ["shows/get"]
+        daily_quota = 2
+
+        [[key]]
+        name = "old-partner"
+        key = "op-secret"
+        revoked = true
+    "#;
+
+    fn registry(required: bool) -> KeyRegistry {
+        KeyRegistry::new(
+            parse_keys(KEYS).unwrap(),
+            required,
+            Some("admin-token".to_string()),
+        )
+    }
+
+    #[test]
+    fn test_parse_rejects_duplicates() {
+        let duplicate = r#"
+            [[key]]
+            name = "a"
+            key = "same"
+
+            [[key]]
+            name = "b"
+            key = "same"
+        "#;
+        assert!(parse_keys(duplicate).is_err());
+    }
+
+    #[test]
+    fn test_authorize() {
+        let registry = registry(false);
+        let now = Utc.with_ymd_and_hms(2026, 10, 18, 23, 0, 0).unwrap();
+
+        assert_eq!(registry.authorize("spins/get", None, now), Ok(()));
+        assert_eq!(
+            registry.authorize("spins/get", Some("np-secret"), now),
+            Ok(())
+        );
+        assert_eq!(
+            registry.authorize("spins/get", Some("wrong"), now),
+            Err(KeyRejection::Invalid)
+        );
+        assert_eq!(
+            registry.authorize("spins/get", Some("op-secret"), now),
+            Err(KeyRejection::Invalid)
+        );
+        assert_eq!(
+            registry.authorize("spins/get", Some("dv-secret"), now),
+            Err(KeyRejection::RouteNotAllowed)
+        );
+
+        assert!(registry
+            .authorize("shows/get", Some("dv-secret"), now)
+            .is_ok());
+        assert!(registry
+            .authorize("shows/get", Some("dv-secret"), now)
+            .is_ok());
+        assert_eq!(
+            registry.authorize("shows/get", Some("dv-secret"), now),
+            Err(KeyRejection::QuotaExceeded {
+                retry_after: Duration::from_secs(3600)
+            })
+        );
+        // Quota resets at midnight UTC
+        let tomorrow = Utc.with_ymd_and_hms(2026, 10, 19, 0, 0, 1).unwrap();
+        assert!(registry
+            .authorize("shows/get", Some("dv-secret"), tomorrow)
+            .is_ok());
+
+        let report = registry.usage_report();
+        assert_eq!(report["clients"]["display-vendor"]["total"], 3);
+        assert_eq!(report["clients"]["display-vendor"]["rejected"], 2);
+        assert_eq!(report["clients"]["newspaper"]["routes"]["spins/get"], 1);
+        assert_eq!(report["clients"]["old-partner"]["revoked"], true);
+    }
+
+    #[test]
+    fn test_revoke_without_restart() {
+        let registry = registry(true);
+        let now = Utc::now();
+        assert_eq!(
+            registry.authorize("spins/get", None, now),
+            Err(KeyRejection::Missing)
+        );
+        assert!(registry
+            .authorize("spins/get", Some("np-secret"), now)
+            .is_ok());
+
+        registry.replace_keys(parse_keys(&KEYS.replace("np-secret", "np-rotated")).unwrap());
+        assert_eq!(
+            registry.authorize("spins/get", Some("np-secret"), now),
+            Err(KeyRejection::Invalid)
+        );
+        assert!(registry
+            .authorize("spins/get", Some("np-rotated"), now)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_key_from_header_or_query() {
+        let api = warp::path!("spins" / "get")
+            .and(authorize(registry(true), "spins/get"))
+            .map(warp::reply)
+            .recover(filters::handle_rejection);
+
+        let resp = request()
+            .path("/spins/get")
+            .header("X-API-Key", "np-secret")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = request()
+            .path("/spins/get?api_key=np-secret")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = request().path("/spins/get").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = request()
+            .path("/spins/get?api_key=dv-secret")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_keyed_routes_cached_privately() {
+        let spin_db = crate::models::blank_db();
+        spin_db.update(json!(null), json!({"spins": [{"id": 1}]}));
+        let limiter = RateLimiter::from_config(config::get());
+        let keyed = filters::get_spins_v2(spin_db.clone(), registry(false), limiter.clone())
+            .recover(filters::handle_rejection);
+        let open = filters::get_spins_v2(spin_db, KeyRegistry::from_config(config::get()), limiter);
+
+        let resp = request()
+            .path("/v2/spins")
+            .header("X-API-Key", "np-secret")
+            .reply(&keyed)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["cache-control"], "private, max-age=5");
+
+        // Without keys configured, shared caches may keep responses
+        let resp = request().path("/v2/spins").reply(&open).await;
+        assert_eq!(resp.headers()["cache-control"], "public, max-age=5");
+    }
+
+    #[tokio::test]
+    async fn test_admin_usage_endpoint() {
+        let api = filters::admin_usage(registry(false)).recover(filters::handle_rejection);
+
+        let resp = request().path("/admin/usage").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = request()
+            .path("/admin/usage")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["clients"]["newspaper"]["total"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_admin_metrics_endpoint() {
+        // The metrics token works without any client keys
+        let open = KeyRegistry::from_config(config::get());
+        let api = filters::admin_metrics(open.clone(), Some("scrape-me".to_string()))
+            .recover(filters::handle_rejection);
+        let resp = request().path("/admin/metrics").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = request()
+            .path("/admin/metrics")
+            .header("Authorization", "Bearer scrape-me")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // So does the admin token
+        let api = filters::admin_metrics(registry(false), Some("scrape-me".to_string()))
+            .recover(filters::handle_rejection);
+        let resp = request()
+            .path("/admin/metrics")
+            .header("Authorization", "Bearer admin-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Neither configured
+        let api = filters::admin_metrics(open, None).recover(filters::handle_rejection);
+        let resp = request().path("/admin/metrics").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_admin_token() {
+        let registry = registry(false);
+        assert!(registry.admin_enabled());
+        assert!(registry.is_admin(Some("Bearer admin-token")));
+        assert!(!registry.is_admin(Some("Bearer admin-tokeN")));
+        assert!(!registry.is_admin(Some("admin-token")));
+        assert!(!registry.is_admin(None));
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use warp::{Filter, Rejection};
+
+use crate::config::IpRange;
+
+/// Works out which address a request really came from. X-Forwarded-For is only
+/// believed when the connection comes from a trusted proxy, and only back to the
+/// first hop that isn't itself trusted, so clients can't spoof their address.
+pub fn resolve(
+    remote: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted: &[IpRange],
+) -> Option<IpAddr> {
+    let mut ip = remote?;
+    if let Some(header) = forwarded_for {
+        for hop in header.rsplit(',') {
+            if !trusted.iter().any(|range| range.contains(&ip)) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(hop_ip) => ip = hop_ip,
+                Err(_) => break,
+            }
+        }
+    }
+    Some(ip)
+}
+
+/// Extracts the client's address, or `None` if the connection has no peer
+/// address (e.g. in tests).
+pub fn filter(
+    trusted: Arc<[IpRange]>,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            move |remote: Option<SocketAddr>, forwarded_for: Option<String>| {
+                resolve(
+                    remote.map(|addr| addr.ip()),
+                    forwarded_for.as_deref(),
+                    &trusted,
+                )
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::config::IpRange;
+
+    use super::resolve;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_header() {
+        let trusted = [IpRange::try_from("10.0.0.0/8".to_string()).unwrap()];
+
+        assert_eq!(
+            resolve(ip("198.51.100.4"), Some("1.2.3.4"), &trusted),
+            ip("198.51.100.4")
+        );
+    }
+
+    #[test]
+    fn test_trusted_proxy_chain() {
+        let trusted = [IpRange::try_from("10.0.0.0/8".to_string()).unwrap()];
+
+        // Client spoofed 1.2.3.4; the first untrusted hop is the real client
+        assert_eq!(
+            resolve(
+                ip("10.0.0.2"),
+                Some("1.2.3.4, 198.51.100.4, 10.0.0.1"),
+                &trusted
+            ),
+            ip("198.51.100.4")
+        );
+        assert_eq!(resolve(ip("10.0.0.2"), None, &trusted), ip("10.0.0.2"));
+        assert_eq!(resolve(None, Some("1.2.3.4"), &trusted), None);
+    }
+}
@@ -0,0 +1,212 @@
+use std::io::Write;
+
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use warp::{
+    http::{header, HeaderValue},
+    reply::Response,
+    Filter, Rejection,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    fn header_value(self) -> Option<HeaderValue> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some(HeaderValue::from_static("gzip")),
+            Encoding::Brotli => Some(HeaderValue::from_static("br")),
+        }
+    }
+}
+
+/// The compressed encodings a client accepts, most preferred first. Identity
+/// is always acceptable, so it isn't listed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Accepted(Vec<Encoding>);
+
+impl Accepted {
+    pub fn parse(accept_encoding: &str) -> Accepted {
+        let mut brotli = None;
+        let mut gzip = None;
+        let mut any = None;
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let q = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map(|q| q.parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+            match name.as_str() {
+                "br" => brotli = Some(q),
+                "gzip" | "x-gzip" => gzip = Some(q),
+                "*" => any = Some(q),
+                _ => {}
+            }
+        }
+
+        let mut accepted: Vec<(Encoding, f32)> = [
+            (Encoding::Brotli, brotli.or(any).unwrap_or(0.0)),
+            (Encoding::Gzip, gzip.or(any).unwrap_or(0.0)),
+        ]
+        .into_iter()
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+        // Stable, so brotli wins a tie since it's usually smaller
+        accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Accepted(accepted.into_iter().map(|(encoding, _)| encoding).collect())
+    }
+}
+
+pub fn accepted() -> impl Filter<Extract = (Accepted,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept-encoding").map(|header: Option<String>| {
+        header
+            .map(|header| Accepted::parse(&header))
+            .unwrap_or_default()
+    })
+}
+
+/// A response body along with its compressed variants, built once when the
+/// body changes rather than on every request.
+pub struct Encoded {
+    pub identity: Bytes,
+    // None when compressing didn't make the body any smaller
+    gzip: Option<Bytes>,
+    brotli: Option<Bytes>,
+}
+
+impl Encoded {
+    pub fn new(identity: Bytes) -> Encoded {
+        let smaller = |compressed: Vec<u8>| {
+            if compressed.len() < identity.len() {
+                Some(Bytes::from(compressed))
+            } else {
+                None
+            }
+        };
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        gzip.write_all(&identity).unwrap();
+        let gzip = smaller(gzip.finish().unwrap());
+
+        let mut brotli = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+        brotli.write_all(&identity).unwrap();
+        let brotli = smaller(brotli.into_inner());
+
+        Encoded {
+            identity,
+            gzip,
+            brotli,
+        }
+    }
+
+    fn variant(&self, encoding: Encoding) -> Option<&Bytes> {
+        match encoding {
+            Encoding::Identity => Some(&self.identity),
+            Encoding::Gzip => self.gzip.as_ref(),
+            Encoding::Brotli => self.brotli.as_ref(),
+        }
+    }
+
+    /// The client's most preferred variant we have.
+    pub fn select(&self, accepted: &Accepted) -> (Encoding, Bytes) {
+        accepted
+            .0
+            .iter()
+            .find_map(|&encoding| Some((encoding, self.variant(encoding)?.clone())))
+            .unwrap_or((Encoding::Identity, self.identity.clone()))
+    }
+
+    pub fn response(&self, accepted: &Accepted, content_type: &'static str) -> Response {
+        let (encoding, body) = self.select(accepted);
+        let mut resp = Response::new(body.into());
+        let headers = resp.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        if let Some(value) = encoding.header_value() {
+            headers.insert(header::CONTENT_ENCODING, value);
+        }
+        resp
+    }
+}
+
+/// Marks a response (full or 304) as varying by Accept-Encoding. When the
+/// body would be compressed, its ETag is made weak: the bytes differ from the
+/// identity body's, but the content is the same, so either satisfies a
+/// conditional request.
+pub fn vary(resp: &mut Response, encoded: &Encoded, accepted: &Accepted) {
+    let headers = resp.headers_mut();
+    headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    if encoded.select(accepted).0 == Encoding::Identity {
+        return;
+    }
+    if let Some(etag) = headers.get(header::ETAG) {
+        let etag = etag.to_str().unwrap_or_default();
+        if !etag.starts_with("W/") {
+            let weak = HeaderValue::from_str(&format!("W/{}", etag)).unwrap();
+            headers.insert(header::ETAG, weak);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use bytes::Bytes;
+    use flate2::read::GzDecoder;
+
+    use super::{Accepted, Encoded, Encoding};
+
+    #[test]
+    fn test_parse_accept_encoding() {
+        assert_eq!(
+            Accepted::parse("gzip, deflate, br"),
+            Accepted(vec![Encoding::Brotli, Encoding::Gzip])
+        );
+        assert_eq!(
+            Accepted::parse("br;q=0.5, gzip"),
+            Accepted(vec![Encoding::Gzip, Encoding::Brotli])
+        );
+        assert_eq!(
+            Accepted::parse("*;q=0.1, br;q=0"),
+            Accepted(vec![Encoding::Gzip])
+        );
+        assert_eq!(Accepted::parse("identity"), Accepted(vec![]));
+    }
+
+    #[test]
+    fn test_select_variant() {
+        let body = Bytes::from(r#"{"spin-0":{"artist":"Nina Simone"}}"#.repeat(20));
+        let encoded = Encoded::new(body.clone());
+
+        let (encoding, gzip) = encoded.select(&Accepted::parse("gzip"));
+        assert_eq!(encoding, Encoding::Gzip);
+        let mut decoded = Vec::new();
+        GzDecoder::new(&gzip[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+
+        let (encoding, brotli) = encoded.select(&Accepted::parse("gzip, br"));
+        assert_eq!(encoding, Encoding::Brotli);
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(&brotli[..], 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+        assert_eq!(
+            encoded.select(&Accepted::default()),
+            (Encoding::Identity, body)
+        );
+
+        // Too small to gain anything from compression
+        let tiny = Encoded::new(Bytes::from_static(b"null"));
+        assert_eq!(
+            tiny.select(&Accepted::parse("br, gzip")).0,
+            Encoding::Identity
+        );
+    }
+}
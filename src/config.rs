@@ -0,0 +1,1296 @@
+use std::{collections::HashMap, env, fs, net::IpAddr, path::PathBuf, sync::OnceLock};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+// Path used when RELAY_CONFIG isn't set. A missing default file is not an error.
+const DEFAULT_CONFIG_PATH: &str = "relay.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings read from the relay's TOML config file. Every section is optional,
+/// so an absent file gives the same behaviour as before the file existed, bar
+/// the log: its lines gain a timestamp and target, requests are logged under
+/// "access", and the file rolls at 10 MB.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Proxies/load balancers whose X-Forwarded-For header is believed
+    pub trusted_proxies: Vec<IpRange>,
+    pub tls: Option<TlsConfig>,
+    pub logging: LoggingConfig,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub api_keys: Option<ApiKeysConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub cors: CorsConfig,
+    pub cache: CacheConfig,
+    pub fields: FieldsConfig,
+    pub feeds: FeedsConfig,
+    pub now: NowConfig,
+    pub widget: WidgetConfig,
+    pub images: ImagesConfig,
+    pub sinks: SinksConfig,
+    pub enrichment: EnrichmentConfig,
+}
+
+/// A single address ("203.0.113.7") or a CIDR block ("10.0.0.0/8").
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange(pub IpNet);
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(IpRange(net));
+        }
+        s.parse::<IpAddr>()
+            .map(|ip| IpRange(IpNet::from(ip)))
+            .map_err(|_| format!("invalid IP address or CIDR block \"{}\"", s))
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    // Port the HTTPS listener binds to
+    #[serde(default = "default_https_port")]
+    pub port: u16,
+    // If set, a plain-HTTP listener on this port redirects everything to HTTPS
+    pub redirect_port: Option<u16>,
+    // How often the certificate and key are checked for changes on disk
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval_secs: u64,
+}
+
+fn default_https_port() -> u16 {
+    443
+}
+
+fn default_reload_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+    pub format: LogFormat,
+    // Log one line per HTTP request under the "access" target
+    pub access_log: bool,
+    pub sinks: Vec<LogSink>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            // Text, as before the config file existed
+            format: LogFormat::Text,
+            access_log: true,
+            sinks: vec![LogSink::File {
+                path: PathBuf::from("log/output.log"),
+                max_size_mb: Some(10),
+                max_age: None,
+                keep: default_keep(),
+            }],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum LogSink {
+    Stdout,
+    File {
+        path: PathBuf,
+        // Roll the file once it reaches this size...
+        max_size_mb: Option<u64>,
+        // ...or once it's this old, e.g. "1 day" or "12 hours". Only one may be set.
+        max_age: Option<String>,
+        // Number of rolled files to keep
+        #[serde(default = "default_keep")]
+        keep: u32,
+    },
+}
+
+fn default_keep() -> u32 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // Used for any route without its own entry in `routes`
+    #[serde(default = "default_limit")]
+    pub default: Limit,
+    // Keyed by route name, e.g. "spins/get"
+    #[serde(default)]
+    pub routes: HashMap<String, Limit>,
+    // Clients that are never limited, e.g. our own servers
+    #[serde(default)]
+    pub exempt: Vec<IpRange>,
+}
+
+/// Token bucket: `burst` requests at once, refilled at `per_second`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+fn default_limit() -> Limit {
+    Limit {
+        per_second: 5.0,
+        burst: 20,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeysConfig {
+    // TOML file listing client keys, re-read when it changes
+    pub keys_path: PathBuf,
+    // Reject requests that don't carry a key at all
+    #[serde(default)]
+    pub required: bool,
+    // Bearer token for the /admin endpoints; they're disabled without one
+    pub admin_token: Option<String>,
+    #[serde(default = "default_keys_reload_interval")]
+    pub reload_interval_secs: u64,
+}
+
+fn default_keys_reload_interval() -> u64 {
+    30
+}
+
+/// Access to /admin/metrics without client API keys. The api_keys admin
+/// token is accepted too.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    // Bearer token for /admin/metrics, e.g. the one Prometheus scrapes with
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // "*", exact origins like "https://kscu.org", or subdomain wildcards
+    // like "https://*.kscu.org"
+    pub allowed_origins: Vec<String>,
+    pub allow_credentials: bool,
+    // Request headers browsers may send
+    pub allowed_headers: Vec<String>,
+    // Response headers scripts may read
+    pub exposed_headers: Vec<String>,
+    // How long browsers may cache a preflight response
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allow_credentials: false,
+            allowed_headers: vec![
+                "Content-Type".to_string(),
+                "Authorization".to_string(),
+                "X-API-Key".to_string(),
+            ],
+            exposed_headers: vec![
+                "Retry-After".to_string(),
+                "ETag".to_string(),
+                "Last-Modified".to_string(),
+            ],
+            max_age_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    // Cache-Control max-age for routes without their own entry in `routes`
+    pub max_age_secs: u64,
+    // Keyed by route name, e.g. "shows/get"
+    pub routes: HashMap<String, u64>,
+}
+
+impl CacheConfig {
+    pub fn max_age(&self, route: &str) -> u64 {
+        *self.routes.get(route).unwrap_or(&self.max_age_secs)
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_age_secs: 5,
+            routes: HashMap::new(),
+        }
+    }
+}
+
+/// Which Spinitron fields are relayed, and under what names, per record type.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldsConfig {
+    pub spin: RecordFields,
+    pub show: RecordFields,
+    pub dj: RecordFields,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordFields {
+    // Only these fields are relayed; everything is when unset
+    pub allow: Option<Vec<String>>,
+    // Never relayed, even if allowed, e.g. "email"
+    pub deny: Vec<String>,
+    // Spinitron name -> relayed name, e.g. "song" = "title"
+    pub rename: HashMap<String, String>,
+}
+
+/// Channel details for the /spins/feed.rss and /spins/feed.atom feeds.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeedsConfig {
+    pub title: String,
+    pub description: String,
+    // The station's website
+    pub link: Option<String>,
+}
+
+impl Default for FeedsConfig {
+    fn default() -> Self {
+        FeedsConfig {
+            title: "Recent spins".to_string(),
+            description: "What's been playing on air.".to_string(),
+            link: None,
+        }
+    }
+}
+
+/// How /now tells a live show from automation.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NowConfig {
+    // Spinitron show categories that mean no one's live, compared ignoring case
+    pub automation_categories: Vec<String>,
+}
+
+impl Default for NowConfig {
+    fn default() -> Self {
+        NowConfig {
+            automation_categories: vec!["automation".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WidgetConfig {
+    // HTML template for /widget/now-playing, replacing the built-in one
+    pub template_path: Option<PathBuf>,
+}
+
+/// The /images proxy, which resizes spin art and DJ photos.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImagesConfig {
+    // Resized images are kept here, and the least recently served removed
+    // once they take up more than `cache_size_mb`
+    pub cache_dir: PathBuf,
+    pub cache_size_mb: u64,
+    // Wider images are scaled down to this, whatever `w` asks for
+    pub max_width: u32,
+    // JPEG quality, 1 to 100
+    pub quality: u8,
+    // Cache-Control max-age. The image for a spin rarely changes.
+    pub max_age_secs: u64,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        ImagesConfig {
+            cache_dir: PathBuf::from("image-cache"),
+            cache_size_mb: 256,
+            max_width: 1200,
+            quality: 85,
+            max_age_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Details looked up for each spin and added to it. Nothing is looked up
+/// unless configured.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnrichmentConfig {
+    pub musicbrainz: Option<MusicBrainzConfig>,
+    pub links: Option<LinksConfig>,
+}
+
+/// MusicBrainz recording and release IDs, with cover art from the Cover
+/// Art Archive.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MusicBrainzConfig {
+    // An email address or URL, sent in the User-Agent as MusicBrainz asks
+    pub contact: String,
+    #[serde(default = "default_musicbrainz_url")]
+    pub url: String,
+    #[serde(default = "default_cover_art_url")]
+    pub cover_art_url: String,
+    // Keeps lookups across restarts
+    pub cache_path: Option<PathBuf>,
+    // Lookups remembered, oldest forgotten first
+    #[serde(default = "default_enrichment_cache_size")]
+    pub cache_size: usize,
+}
+
+/// "Listen on" links added to each spin, one per provider.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinksConfig {
+    pub providers: Vec<LinkProvider>,
+    pub cache_path: Option<PathBuf>,
+    #[serde(default = "default_enrichment_cache_size")]
+    pub cache_size: usize,
+}
+
+/// Where a link goes. Spotify and Apple Music links are resolved to the
+/// track where possible, and are searches otherwise.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LinkProvider {
+    Spotify {
+        // Client credentials for the Web API; links are searches without them
+        client_id: Option<String>,
+        client_secret: Option<String>,
+        #[serde(default = "default_spotify_api_url")]
+        api_url: String,
+        #[serde(default = "default_spotify_accounts_url")]
+        accounts_url: String,
+    },
+    AppleMusic {
+        // The storefront, e.g. "us" or "gb"
+        #[serde(default = "default_apple_music_country")]
+        country: String,
+        #[serde(default = "default_itunes_url")]
+        api_url: String,
+    },
+    // Any other service, e.g. "https://bandcamp.com/search?q={query}". The
+    // URL takes {query} ("artist song"), {artist} and {song}.
+    Search {
+        name: String,
+        url: String,
+    },
+}
+
+impl LinkProvider {
+    /// Its key in a spin's `links`.
+    pub fn name(&self) -> &str {
+        match self {
+            LinkProvider::Spotify { .. } => "spotify",
+            LinkProvider::AppleMusic { .. } => "apple_music",
+            LinkProvider::Search { name, .. } => name,
+        }
+    }
+}
+
+fn default_spotify_api_url() -> String {
+    "https://api.spotify.com".to_string()
+}
+
+fn default_spotify_accounts_url() -> String {
+    "https://accounts.spotify.com".to_string()
+}
+
+fn default_apple_music_country() -> String {
+    "us".to_string()
+}
+
+fn default_itunes_url() -> String {
+    "https://itunes.apple.com".to_string()
+}
+
+fn default_musicbrainz_url() -> String {
+    "https://musicbrainz.org".to_string()
+}
+
+fn default_cover_art_url() -> String {
+    "https://coverartarchive.org".to_string()
+}
+
+fn default_enrichment_cache_size() -> usize {
+    10_000
+}
+
+/// Outputs that are sent what's playing as it changes.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinksConfig {
+    pub stream_metadata: Vec<StreamMetadataConfig>,
+    pub rds: Vec<RdsConfig>,
+    pub listenbrainz: Vec<ListenBrainzConfig>,
+    pub lastfm: Vec<LastFmConfig>,
+    pub chat: Vec<ChatConfig>,
+    pub mastodon: Vec<MastodonConfig>,
+    pub mqtt: Vec<MqttConfig>,
+    pub http_push: Vec<HttpPushConfig>,
+}
+
+/// A streaming server whose stream title is set to each new spin.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StreamMetadataConfig {
+    Icecast {
+        // e.g. "http://localhost:8000"
+        url: String,
+        mount: String,
+        #[serde(default = "default_icecast_username")]
+        username: String,
+        password: String,
+        // Spin fields in braces, e.g. "{artist} - {song}"
+        #[serde(default = "default_title_template")]
+        template: String,
+    },
+    Shoutcast {
+        url: String,
+        // The admin password
+        password: String,
+        // Stream ID on a Shoutcast 2 server; leave out for Shoutcast 1
+        sid: Option<u32>,
+        #[serde(default = "default_title_template")]
+        template: String,
+    },
+}
+
+impl StreamMetadataConfig {
+    pub fn url(&self) -> &str {
+        match self {
+            StreamMetadataConfig::Icecast { url, .. } => url,
+            StreamMetadataConfig::Shoutcast { url, .. } => url,
+        }
+    }
+}
+
+/// An RDS encoder sent RadioText and PS for the current spin and show.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RdsConfig {
+    pub target: RdsTarget,
+    // RadioText, cut to 64 characters. {artist} and {song} are tagged for RT+.
+    #[serde(default = "default_title_template")]
+    pub template: String,
+    // RadioText before any spin has been seen
+    #[serde(default = "default_rds_idle_template")]
+    pub idle_template: String,
+    // PS, cut to 8 characters. Not sent when unset.
+    pub ps_template: Option<String>,
+}
+
+/// Where RDS goes: "tcp://host:port", "udp://host:port" or "file:///path".
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum RdsTarget {
+    Tcp(String),
+    Udp(String),
+    File(PathBuf),
+}
+
+impl TryFrom<String> for RdsTarget {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.split_once("://") {
+            Some(("tcp", addr)) if !addr.is_empty() => Ok(RdsTarget::Tcp(addr.to_string())),
+            Some(("udp", addr)) if !addr.is_empty() => Ok(RdsTarget::Udp(addr.to_string())),
+            Some(("file", path)) if !path.is_empty() => Ok(RdsTarget::File(PathBuf::from(path))),
+            _ => Err(format!(
+                "invalid RDS target \"{}\", expected tcp://, udp:// or file://",
+                s
+            )),
+        }
+    }
+}
+
+/// A ListenBrainz account each new spin is submitted to as a listen.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenBrainzConfig {
+    // The account's user token
+    pub token: String,
+    #[serde(default = "default_listenbrainz_url")]
+    pub url: String,
+    // Remembers submitted spins across restarts, so none is submitted twice
+    pub state_path: Option<PathBuf>,
+}
+
+/// A Last.fm account each new spin is scrobbled to.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LastFmConfig {
+    pub api_key: String,
+    pub api_secret: String,
+    // From authorizing the station's account for the API key
+    pub session_key: String,
+    #[serde(default = "default_lastfm_url")]
+    pub url: String,
+    pub state_path: Option<PathBuf>,
+}
+
+/// A chat channel's incoming webhook, posted to when a show starts and,
+/// optionally, on every spin.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChatConfig {
+    pub service: ChatService,
+    pub webhook_url: String,
+    #[serde(default)]
+    pub events: EventFilter,
+    // Show fields in braces
+    #[serde(default = "default_show_post_template")]
+    pub show_template: String,
+    // Spin fields in braces, with the show's under "show"
+    #[serde(default = "default_title_template")]
+    pub spin_template: String,
+    // Posts beyond this wait their turn
+    #[serde(default = "default_chat_limit")]
+    pub rate_limit: Limit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatService {
+    Discord,
+    Slack,
+    Mattermost,
+}
+
+/// Which changes are posted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventFilter {
+    #[default]
+    Shows,
+    Spins,
+    All,
+}
+
+impl EventFilter {
+    pub fn shows(self) -> bool {
+        self != EventFilter::Spins
+    }
+
+    pub fn spins(self) -> bool {
+        self != EventFilter::Shows
+    }
+}
+
+/// A Mastodon (or compatible) account that posts show starts and spins.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MastodonConfig {
+    // The instance, e.g. "https://mastodon.social"
+    pub url: String,
+    // With the write:statuses scope
+    pub access_token: String,
+    #[serde(default)]
+    pub events: EventFilter,
+    #[serde(default = "default_show_post_template")]
+    pub show_template: String,
+    #[serde(default = "default_title_template")]
+    pub spin_template: String,
+    #[serde(default)]
+    pub visibility: Visibility,
+    // Added to every post, without the #
+    #[serde(default)]
+    pub hashtags: Vec<String>,
+    // Fields turned into hashtags, e.g. "genre" or "show.title"
+    #[serde(default)]
+    pub hashtag_fields: Vec<String>,
+    // Seconds. Spins posted sooner after the last post are skipped, and
+    // show starts wait.
+    #[serde(default)]
+    pub min_interval: u64,
+    // Log posts instead of posting them
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Unlisted,
+    Private,
+    Direct,
+}
+
+/// A directory or aggregator sent each new spin over HTTP, in the format of
+/// one of the presets.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "preset", rename_all = "lowercase", deny_unknown_fields)]
+pub enum HttpPushConfig {
+    // TuneIn's AIR API
+    Tunein {
+        // Names the target in logs and metrics
+        name: String,
+        partner_id: String,
+        partner_key: String,
+        // e.g. "s12345"
+        station_id: String,
+        #[serde(default = "default_tunein_url")]
+        url: String,
+        // Seconds between pushes. A spin that comes sooner waits, and is
+        // dropped if another comes in the meantime.
+        #[serde(default)]
+        min_interval: u64,
+    },
+    // A POST of `body` as JSON, with spin fields in braces in its strings
+    Json {
+        name: String,
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        body: serde_json::Value,
+        #[serde(default)]
+        min_interval: u64,
+    },
+}
+
+impl HttpPushConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            HttpPushConfig::Tunein { name, .. } => name,
+            HttpPushConfig::Json { name, .. } => name,
+        }
+    }
+
+    pub fn min_interval(&self) -> u64 {
+        match self {
+            HttpPushConfig::Tunein { min_interval, .. } => *min_interval,
+            HttpPushConfig::Json { min_interval, .. } => *min_interval,
+        }
+    }
+}
+
+/// An MQTT broker sent the current spin and show as retained messages.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub broker: MqttBroker,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: MqttQos,
+    // Spins go to "{prefix}/now/spin", shows to "{prefix}/now/show"
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    // PEM CA certificates for an mqtts:// broker; the system's otherwise
+    pub ca_path: Option<PathBuf>,
+}
+
+/// "mqtt://host:port", or "mqtts://host:port" for TLS. The port defaults
+/// to 1883 and 8883 respectively.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MqttBroker {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+}
+
+impl TryFrom<String> for MqttBroker {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid MQTT broker \"{}\", expected mqtt://host:port", s);
+        let (tls, address) = match s.split_once("://") {
+            Some(("mqtt", address)) => (false, address),
+            Some(("mqtts", address)) => (true, address),
+            _ => return Err(invalid()),
+        };
+        let default_port = if tls { 8883 } else { 1883 };
+        // IPv6 addresses are in brackets, e.g. "[::1]:1883"
+        let (host, port) = match address.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => (host, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+                None => return Err(invalid()),
+            },
+            None => match address.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(MqttBroker {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+}
+
+/// 0 (at most once), 1 (at least once) or 2 (exactly once).
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "u8")]
+pub struct MqttQos(pub u8);
+
+impl TryFrom<u8> for MqttQos {
+    type Error = String;
+
+    fn try_from(qos: u8) -> Result<Self, Self::Error> {
+        match qos {
+            0..=2 => Ok(MqttQos(qos)),
+            _ => Err(format!("invalid MQTT QoS {}, expected 0, 1 or 2", qos)),
+        }
+    }
+}
+
+fn default_tunein_url() -> String {
+    "https://air.radiotime.com/Playing.ashx".to_string()
+}
+
+fn default_mqtt_client_id() -> String {
+    "api-relay".to_string()
+}
+
+fn default_mqtt_qos() -> MqttQos {
+    MqttQos(1)
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "station".to_string()
+}
+
+fn default_show_post_template() -> String {
+    "Now on air: {title} with {djs}".to_string()
+}
+
+// Discord allows 30 posts a minute to a channel's webhooks
+fn default_chat_limit() -> Limit {
+    Limit {
+        per_second: 0.5,
+        burst: 5,
+    }
+}
+
+fn default_listenbrainz_url() -> String {
+    "https://api.listenbrainz.org".to_string()
+}
+
+fn default_lastfm_url() -> String {
+    "https://ws.audioscrobbler.com".to_string()
+}
+
+fn default_rds_idle_template() -> String {
+    "{show.title}".to_string()
+}
+
+fn default_icecast_username() -> String {
+    "admin".to_string()
+}
+
+fn default_title_template() -> String {
+    "{artist} - {song}".to_string()
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(load)
+}
+
+fn load() -> Config {
+    let (path, required) = match env::var("RELAY_CONFIG") {
+        Ok(path) => (path, true),
+        Err(_) => (DEFAULT_CONFIG_PATH.to_string(), false),
+    };
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if required => panic!("Couldn't read config file {}: {}", path, e),
+        Err(_) => return Config::default(),
+    };
+
+    parse(&contents).unwrap_or_else(|e| panic!("Invalid config file {}: {}", path, e))
+}
+
+pub fn parse(contents: &str) -> Result<Config, toml::de::Error> {
+    toml::from_str(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse, ChatService, EventFilter, HttpPushConfig, Limit, LinkProvider, LogFormat, LogSink,
+        MqttBroker, MqttQos, RdsTarget, StreamMetadataConfig, Visibility,
+    };
+
+    #[test]
+    fn test_empty_config() {
+        let config = parse("").unwrap();
+        assert!(config.tls.is_none());
+        assert_eq!(config.cors.allowed_origins, ["*"]);
+        assert_eq!(config.cache.max_age("spins/get"), 5);
+        assert_eq!(config.feeds.title, "Recent spins");
+        assert!(config.enrichment.musicbrainz.is_none());
+        assert_eq!(config.images.max_width, 1200);
+        assert_eq!(config.now.automation_categories, ["automation"]);
+    }
+
+    #[test]
+    fn test_now() {
+        let config = parse(
+            r#"
+            [now]
+            automation_categories = ["Automation", "Overnight Rotation"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.now.automation_categories,
+            ["Automation", "Overnight Rotation"]
+        );
+    }
+
+    #[test]
+    fn test_metrics() {
+        let config = parse("[metrics]\ntoken = \"scrape-me\"").unwrap();
+        assert_eq!(config.metrics.unwrap().token, "scrape-me");
+        assert!(parse("").unwrap().metrics.is_none());
+        assert!(parse("[metrics]").is_err());
+    }
+
+    #[test]
+    fn test_images() {
+        let config = parse(
+            r#"
+            [images]
+            cache_dir = "/var/cache/relay/images"
+            cache_size_mb = 64
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.images.cache_dir.to_str(),
+            Some("/var/cache/relay/images")
+        );
+        assert_eq!(config.images.cache_size_mb, 64);
+        assert_eq!(config.images.quality, 85);
+        assert!(parse("[images]\nformat = \"webp\"").is_err());
+    }
+
+    #[test]
+    fn test_tls_defaults() {
+        let config = parse(
+            r#"
+            [tls]
+            cert_path = "/etc/relay/cert.pem"
+            key_path = "/etc/relay/key.pem"
+            "#,
+        )
+        .unwrap();
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.port, 443);
+        assert_eq!(tls.redirect_port, None);
+        assert_eq!(tls.reload_interval_secs, 60);
+    }
+
+    #[test]
+    fn test_logging_sinks() {
+        let config = parse(
+            r#"
+            [logging]
+            level = "debug"
+            format = "text"
+
+            [[logging.sinks]]
+            kind = "stdout"
+
+            [[logging.sinks]]
+            kind = "file"
+            path = "log/relay.log"
+            max_age = "1 day"
+            "#,
+        )
+        .unwrap();
+        let logging = config.logging;
+        assert_eq!(parse("").unwrap().logging.format, LogFormat::Text);
+        assert_eq!(logging.level, "debug");
+        assert_eq!(logging.format, LogFormat::Text);
+        assert!(logging.access_log);
+        assert_eq!(logging.sinks[0], LogSink::Stdout);
+        assert_eq!(
+            logging.sinks[1],
+            LogSink::File {
+                path: "log/relay.log".into(),
+                max_size_mb: None,
+                max_age: Some("1 day".to_string()),
+                keep: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let config = parse(
+            r#"
+            trusted_proxies = ["10.0.0.0/8", "192.168.1.1"]
+
+            [rate_limit]
+            exempt = ["203.0.113.7"]
+
+            [rate_limit.routes]
+            "spins/get" = { per_second = 1.5, burst = 3 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.trusted_proxies.len(), 2);
+        assert!(config.trusted_proxies[1].contains(&"192.168.1.1".parse().unwrap()));
+
+        let rate_limit = config.rate_limit.unwrap();
+        assert_eq!(
+            rate_limit.routes["spins/get"],
+            Limit {
+                per_second: 1.5,
+                burst: 3
+            }
+        );
+        assert_eq!(rate_limit.default.burst, 20);
+        assert!(parse("trusted_proxies = [\"not an ip\"]").is_err());
+    }
+
+    #[test]
+    fn test_fields() {
+        let config = parse(
+            r#"
+            [fields.spin]
+            allow = ["artist", "song"]
+
+            [fields.dj]
+            deny = ["email"]
+            rename = { website = "homepage" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.fields.spin.allow.unwrap(),
+            vec!["artist".to_string(), "song".to_string()]
+        );
+        assert!(config.fields.dj.allow.is_none());
+        assert_eq!(config.fields.dj.deny, vec!["email".to_string()]);
+        assert_eq!(config.fields.dj.rename["website"], "homepage");
+    }
+
+    #[test]
+    fn test_fields_read_by_relay_not_renamed() {
+        let config = parse(
+            r#"
+            [fields.spin]
+            deny = ["image"]
+            rename = { artist = "performer" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            crate::fields::Policy::new(&config.fields).err(),
+            Some("[fields.spin]: \"artist\" can't be renamed to \"performer\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sinks() {
+        let config = parse(
+            r#"
+            [[sinks.stream_metadata]]
+            kind = "icecast"
+            url = "http://localhost:8000"
+            mount = "/live"
+            password = "hackme"
+
+            [[sinks.stream_metadata]]
+            kind = "shoutcast"
+            url = "http://localhost:8001"
+            password = "hackme"
+            sid = 1
+            template = "{song}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.sinks.stream_metadata[0],
+            StreamMetadataConfig::Icecast {
+                url: "http://localhost:8000".to_string(),
+                mount: "/live".to_string(),
+                username: "admin".to_string(),
+                password: "hackme".to_string(),
+                template: "{artist} - {song}".to_string(),
+            }
+        );
+        assert_eq!(
+            config.sinks.stream_metadata[1].url(),
+            "http://localhost:8001"
+        );
+    }
+
+    #[test]
+    fn test_rds_targets() {
+        let config = parse(
+            r#"
+            [[sinks.rds]]
+            target = "udp://192.168.1.50:5000"
+
+            [[sinks.rds]]
+            target = "file:///var/lib/rds/rt.txt"
+            ps_template = "KSCU"
+            "#,
+        )
+        .unwrap();
+        let rds = &config.sinks.rds;
+        assert_eq!(
+            rds[0].target,
+            RdsTarget::Udp("192.168.1.50:5000".to_string())
+        );
+        assert_eq!(rds[0].idle_template, "{show.title}");
+        assert_eq!(rds[1].target, RdsTarget::File("/var/lib/rds/rt.txt".into()));
+        assert!(parse("[[sinks.rds]]\ntarget = \"192.168.1.50:5000\"").is_err());
+    }
+
+    #[test]
+    fn test_scrobblers() {
+        let config = parse(
+            r#"
+            [[sinks.listenbrainz]]
+            token = "token"
+
+            [[sinks.lastfm]]
+            api_key = "key"
+            api_secret = "secret"
+            session_key = "session"
+            state_path = "/var/lib/relay/lastfm.json"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.sinks.listenbrainz[0].url,
+            "https://api.listenbrainz.org"
+        );
+        assert!(config.sinks.listenbrainz[0].state_path.is_none());
+        assert_eq!(config.sinks.lastfm[0].url, "https://ws.audioscrobbler.com");
+    }
+
+    #[test]
+    fn test_chat() {
+        let config = parse(
+            r#"
+            [[sinks.chat]]
+            service = "discord"
+            webhook_url = "https://discord.com/api/webhooks/1/abc"
+
+            [[sinks.chat]]
+            service = "slack"
+            webhook_url = "https://hooks.slack.com/services/T/B/X"
+            events = "all"
+            rate_limit = { per_second = 1.0, burst = 1 }
+            "#,
+        )
+        .unwrap();
+        let chat = &config.sinks.chat;
+        assert_eq!(chat[0].service, ChatService::Discord);
+        assert_eq!(chat[0].events, EventFilter::Shows);
+        assert_eq!(chat[0].rate_limit.burst, 5);
+        assert_eq!(chat[1].events, EventFilter::All);
+        assert_eq!(chat[1].rate_limit.per_second, 1.0);
+        assert!(parse("[[sinks.chat]]\nservice = \"irc\"\nwebhook_url = \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_mastodon() {
+        let config = parse(
+            r#"
+            [[sinks.mastodon]]
+            url = "https://mastodon.social"
+            access_token = "token"
+            events = "all"
+            visibility = "unlisted"
+            hashtags = ["NowPlaying"]
+            "#,
+        )
+        .unwrap();
+        let mastodon = &config.sinks.mastodon[0];
+        assert_eq!(mastodon.visibility, Visibility::Unlisted);
+        assert!(mastodon.events.shows() && mastodon.events.spins());
+        assert_eq!(mastodon.min_interval, 0);
+        assert!(!mastodon.dry_run);
+        assert!(!EventFilter::Shows.spins());
+        assert!(!EventFilter::Spins.shows());
+    }
+
+    #[test]
+    fn test_http_push() {
+        let config = parse(
+            r#"
+            [[sinks.http_push]]
+            preset = "tunein"
+            name = "tunein"
+            partner_id = "id"
+            partner_key = "key"
+            station_id = "s12345"
+            min_interval = 30
+
+            [[sinks.http_push]]
+            preset = "json"
+            name = "directory"
+            url = "https://directory.example/now"
+            headers = { Authorization = "Bearer token" }
+
+            [sinks.http_push.body]
+            station = "kscu"
+            track = { artist = "{artist}", title = "{song}" }
+            "#,
+        )
+        .unwrap();
+        let pushes = &config.sinks.http_push;
+
+        assert_eq!(pushes[0].name(), "tunein");
+        assert_eq!(pushes[0].min_interval(), 30);
+        match &pushes[0] {
+            HttpPushConfig::Tunein { url, .. } => {
+                assert_eq!(url, "https://air.radiotime.com/Playing.ashx")
+            }
+            other => panic!("{:?}", other),
+        }
+        match &pushes[1] {
+            HttpPushConfig::Json { headers, body, .. } => {
+                assert_eq!(headers["Authorization"], "Bearer token");
+                assert_eq!(body["track"]["title"], "{song}");
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(parse("[[sinks.http_push]]\npreset = \"icecast\"\nname = \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_mqtt() {
+        let config = parse(
+            r#"
+            [[sinks.mqtt]]
+            broker = "mqtt://localhost"
+
+            [[sinks.mqtt]]
+            broker = "mqtts://[::1]:8884"
+            qos = 2
+            topic_prefix = "kscu"
+            "#,
+        )
+        .unwrap();
+        let mqtt = &config.sinks.mqtt;
+        assert_eq!(
+            mqtt[0].broker,
+            MqttBroker {
+                host: "localhost".to_string(),
+                port: 1883,
+                tls: false
+            }
+        );
+        assert_eq!(mqtt[0].qos, MqttQos(1));
+        assert_eq!(mqtt[0].topic_prefix, "station");
+        assert_eq!(mqtt[1].broker.host, "::1");
+        assert_eq!(mqtt[1].broker.port, 8884);
+        assert!(mqtt[1].broker.tls);
+        assert_eq!(
+            MqttBroker::try_from("mqtts://broker.local".to_string())
+                .unwrap()
+                .port,
+            8883
+        );
+        assert!(MqttBroker::try_from("tcp://localhost:1883".to_string()).is_err());
+        assert!(MqttBroker::try_from("mqtt://localhost:port".to_string()).is_err());
+        assert!(parse("[[sinks.mqtt]]\nbroker = \"mqtt://localhost\"\nqos = 3").is_err());
+    }
+
+    #[test]
+    fn test_musicbrainz() {
+        let config = parse(
+            r#"
+            [enrichment.musicbrainz]
+            contact = "ops@kscu.org"
+            cache_path = "/var/lib/relay/musicbrainz.json"
+            "#,
+        )
+        .unwrap();
+        let musicbrainz = config.enrichment.musicbrainz.unwrap();
+        assert_eq!(musicbrainz.url, "https://musicbrainz.org");
+        assert_eq!(musicbrainz.cover_art_url, "https://coverartarchive.org");
+        assert_eq!(musicbrainz.cache_size, 10_000);
+        assert!(parse("[enrichment.musicbrainz]\nurl = \"http://localhost\"").is_err());
+    }
+
+    #[test]
+    fn test_links() {
+        let config = parse(
+            r#"
+            [[enrichment.links.providers]]
+            kind = "spotify"
+            client_id = "id"
+            client_secret = "secret"
+
+            [[enrichment.links.providers]]
+            kind = "apple_music"
+
+            [[enrichment.links.providers]]
+            kind = "search"
+            name = "bandcamp"
+            url = "https://bandcamp.com/search?q={query}"
+            "#,
+        )
+        .unwrap();
+        let providers = config.enrichment.links.unwrap().providers;
+        let names: Vec<&str> = providers.iter().map(LinkProvider::name).collect();
+        assert_eq!(names, ["spotify", "apple_music", "bandcamp"]);
+        assert_eq!(
+            providers[1],
+            LinkProvider::AppleMusic {
+                country: "us".to_string(),
+                api_url: "https://itunes.apple.com".to_string()
+            }
+        );
+        assert!(parse("[enrichment.links]\nproviders = [{ kind = \"tidal\" }]").is_err());
+    }
+
+    #[test]
+    fn test_unknown_field_rejected() {
+        assert!(parse("[tls]\ncert = \"cert.pem\"").is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use std::sync::Arc;
+
+use warp::{
+    filters::path::FullPath,
+    http::{header, HeaderValue, Method, StatusCode},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use crate::config::CorsConfig;
+
+enum OriginPattern {
+    Any,
+    Exact(String),
+    // "https://*.kscu.org" matches any subdomain of kscu.org over https, but not kscu.org itself
+    Subdomains { scheme: String, suffix: String },
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Result<OriginPattern, String> {
+        if pattern == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        if let Some((scheme, rest)) = pattern.split_once("://") {
+            if let Some(domain) = rest.strip_prefix("*.") {
+                if !domain.is_empty() && !domain.contains('*') {
+                    return Ok(OriginPattern::Subdomains {
+                        scheme: format!("{}://", scheme.to_ascii_lowercase()),
+                        suffix: format!(".{}", domain.to_ascii_lowercase()),
+                    });
+                }
+            }
+        }
+        if pattern.contains('*') || !pattern.contains("://") {
+            return Err(format!("unsupported origin pattern \"{}\"", pattern));
+        }
+        Ok(OriginPattern::Exact(
+            pattern.trim_end_matches('/').to_string(),
+        ))
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            OriginPattern::Subdomains { scheme, suffix } => {
+                let origin = origin.to_ascii_lowercase();
+                match origin.strip_prefix(scheme.as_str()) {
+                    Some(host) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+pub struct CorsPolicy {
+    origins: Vec<OriginPattern>,
+    allow_credentials: bool,
+    allowed_headers: HeaderValue,
+    exposed_headers: Option<HeaderValue>,
+    max_age: HeaderValue,
+    // (path, method) for every route. `{name}` path segments match any value.
+    routes: Vec<(&'static str, &'static str)>,
+}
+
+impl CorsPolicy {
+    pub fn new(
+        config: &CorsConfig,
+        routes: impl IntoIterator<Item = (&'static str, &'static str)>,
+    ) -> CorsPolicy {
+        let origins = config
+            .allowed_origins
+            .iter()
+            .map(|pattern| OriginPattern::parse(pattern))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|e| panic!("Invalid CORS config: {}", e));
+        let header_list = |names: &[String]| {
+            HeaderValue::from_str(&names.join(", "))
+                .unwrap_or_else(|e| panic!("Invalid CORS config: {}", e))
+        };
+
+        CorsPolicy {
+            origins,
+            allow_credentials: config.allow_credentials,
+            allowed_headers: header_list(&config.allowed_headers),
+            exposed_headers: if config.exposed_headers.is_empty() {
+                None
+            } else {
+                Some(header_list(&config.exposed_headers))
+            },
+            max_age: HeaderValue::from(config.max_age_secs),
+            routes: routes.into_iter().collect(),
+        }
+    }
+
+    // The Access-Control-Allow-Origin value for an origin, if it's allowed at all.
+    // Browsers refuse "*" on credentialed requests, so the origin is echoed then.
+    fn allow_origin(&self, origin: &str) -> Option<HeaderValue> {
+        if !self.origins.iter().any(|pattern| pattern.matches(origin)) {
+            return None;
+        }
+        let any = self
+            .origins
+            .iter()
+            .any(|pattern| matches!(pattern, OriginPattern::Any));
+        if any && !self.allow_credentials {
+            Some(HeaderValue::from_static("*"))
+        } else {
+            HeaderValue::from_str(origin).ok()
+        }
+    }
+
+    fn methods_for(&self, path: &str) -> Vec<&'static str> {
+        self.routes
+            .iter()
+            .filter(|(pattern, _)| path_matches(pattern, path))
+            .map(|(_, method)| *method)
+            .collect()
+    }
+
+    fn decorate(&self, resp: &mut Response, origin: Option<&str>) {
+        let allow_origin = origin.and_then(|origin| self.allow_origin(origin));
+        let headers = resp.headers_mut();
+        // The response depends on Origin, so shared caches must key on it
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        if let Some(allow_origin) = allow_origin {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            if self.allow_credentials {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    HeaderValue::from_static("true"),
+                );
+            }
+            if let Some(exposed) = &self.exposed_headers {
+                headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed.clone());
+            }
+        }
+    }
+
+    fn preflight(
+        &self,
+        path: &str,
+        origin: Option<&str>,
+        request_method: Option<&str>,
+    ) -> Response {
+        let methods = self.methods_for(path);
+        if methods.is_empty() {
+            return status(StatusCode::NOT_FOUND);
+        }
+        let mut allow = methods.join(", ");
+        allow.push_str(", OPTIONS");
+        let allow = HeaderValue::from_str(&allow).unwrap();
+
+        let (origin, request_method) = match (origin, request_method) {
+            (Some(origin), Some(request_method)) => (origin, request_method),
+            // A plain OPTIONS request rather than a CORS preflight
+            _ => {
+                let mut resp = status(StatusCode::NO_CONTENT);
+                resp.headers_mut().insert(header::ALLOW, allow);
+                self.decorate(&mut resp, origin);
+                return resp;
+            }
+        };
+
+        let allow_origin = match self.allow_origin(origin) {
+            Some(allow_origin) if methods.contains(&request_method) => allow_origin,
+            _ => {
+                let mut resp = status(StatusCode::FORBIDDEN);
+                resp.headers_mut()
+                    .append(header::VARY, HeaderValue::from_static("Origin"));
+                return resp;
+            }
+        };
+
+        let mut resp = status(StatusCode::NO_CONTENT);
+        let headers = resp.headers_mut();
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, allow);
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            self.allowed_headers.clone(),
+        );
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, self.max_age.clone());
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        resp
+    }
+}
+
+fn status(code: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply(), code).into_response()
+}
+
+/// Whether `path` matches a route pattern, where `{name}` segments match any value.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.split('/');
+    let mut path = path.split('/');
+    loop {
+        match (pattern.next(), path.next()) {
+            (None, None) => return true,
+            (Some(expected), Some(actual))
+                if expected == actual
+                    || (expected.starts_with('{')
+                        && expected.ends_with('}')
+                        && !actual.is_empty()) =>
+            {
+                continue
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Answers OPTIONS requests for every route in the policy's table, and adds CORS
+/// headers to every response `api` produces.
+pub fn wrap<F, R>(
+    policy: Arc<CorsPolicy>,
+    api: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let preflight_policy = policy.clone();
+    // warp::options() would reject other methods with a 405, which outranks the
+    // 404 for an unknown path when rejections are combined
+    let is_options = warp::method()
+        .and_then(|method: Method| async move {
+            if method == Method::OPTIONS {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one();
+    let preflight = is_options
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>(
+            "access-control-request-method",
+        ))
+        .map(
+            move |path: FullPath, origin: Option<String>, method: Option<String>| {
+                preflight_policy.preflight(path.as_str(), origin.as_deref(), method.as_deref())
+            },
+        );
+
+    let actual = warp::header::optional::<String>("origin").and(api).map(
+        move |origin: Option<String>, reply: R| {
+            let mut resp = reply.into_response();
+            policy.decorate(&mut resp, origin.as_deref());
+            resp
+        },
+    );
+
+    preflight.or(actual).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use warp::{http::StatusCode, test::request, Filter};
+
+    use crate::config::CorsConfig;
+
+    use super::{wrap, CorsPolicy};
+
+    const ROUTES: [(&str, &str); 2] = [("/spins/get", "GET"), ("/spins/update", "POST")];
+
+    fn api(
+        origins: &[&str],
+        allow_credentials: bool,
+    ) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+        let config = CorsConfig {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allow_credentials,
+            ..CorsConfig::default()
+        };
+        let routes = warp::path!("spins" / "get")
+            .and(warp::get())
+            .map(|| "spins")
+            .or(warp::path!("spins" / "update")
+                .and(warp::post())
+                .map(|| "OK"));
+        wrap(Arc::new(CorsPolicy::new(&config, ROUTES)), routes)
+    }
+
+    #[tokio::test]
+    async fn test_any_origin() {
+        let resp = request()
+            .path("/spins/get")
+            .header("Origin", "https://example.com")
+            .reply(&api(&["*"], false))
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["access-control-allow-origin"], "*");
+        assert_eq!(resp.headers()["vary"], "Origin");
+    }
+
+    #[tokio::test]
+    async fn test_exact_and_wildcard_origins() {
+        let api = api(&["https://kscu.org", "https://*.kscu.org"], true);
+
+        for origin in ["https://kscu.org", "https://www.kscu.org"] {
+            let resp = request()
+                .path("/spins/get")
+                .header("Origin", origin)
+                .reply(&api)
+                .await;
+            assert_eq!(resp.headers()["access-control-allow-origin"], origin);
+            assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+        }
+
+        for origin in ["https://evilkscu.org", "http://www.kscu.org"] {
+            let resp = request()
+                .path("/spins/get")
+                .header("Origin", origin)
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(!resp.headers().contains_key("access-control-allow-origin"));
+            assert_eq!(resp.headers()["vary"], "Origin");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let api = api(&["https://kscu.org"], false);
+
+        let resp = request()
+            .method("OPTIONS")
+            .path("/spins/update")
+            .header("Origin", "https://kscu.org")
+            .header("Access-Control-Request-Method", "POST")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            resp.headers()["access-control-allow-methods"],
+            "POST, OPTIONS"
+        );
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "https://kscu.org"
+        );
+        assert_eq!(resp.headers()["access-control-max-age"], "600");
+
+        let resp = request()
+            .method("OPTIONS")
+            .path("/spins/get")
+            .header("Origin", "https://kscu.org")
+            .header("Access-Control-Request-Method", "DELETE")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = request()
+            .method("OPTIONS")
+            .path("/spins/get")
+            .header("Origin", "https://example.com")
+            .header("Access-Control-Request-Method", "GET")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = request()
+            .method("OPTIONS")
+            .path("/nowhere")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_pattern() {
+        let config = CorsConfig {
+            allowed_origins: vec!["*.kscu.org".to_string()],
+            ..CorsConfig::default()
+        };
+        CorsPolicy::new(&config, ROUTES);
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::config::{Limit, LinkProvider, LinksConfig};
+
+use super::{Cache, Lookup, Pacer};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const SPOTIFY_LIMIT: Limit = Limit {
+    per_second: 1.0,
+    burst: 5,
+};
+
+// The iTunes Search API allows about 20 requests a minute
+const APPLE_MUSIC_LIMIT: Limit = Limit {
+    per_second: 0.3,
+    burst: 3,
+};
+
+// Results checked for one by the spin's artist
+const RESULTS: &str = "5";
+
+/// Link by provider name.
+pub type Found = BTreeMap<String, String>;
+
+#[derive(Debug)]
+pub struct Query {
+    key: String,
+    artist: String,
+    song: String,
+}
+
+impl Query {
+    fn from_spin(spin: &Value) -> Option<Query> {
+        let text = |field: &str| spin[field].as_str().unwrap_or_default().trim().to_string();
+        let (artist, song) = (text("artist"), text("song"));
+        if artist.is_empty() || song.is_empty() {
+            return None;
+        }
+        Some(Query {
+            key: format!("{}\n{}", artist, song).to_lowercase(),
+            artist,
+            song,
+        })
+    }
+}
+
+pub struct Links {
+    config: LinksConfig,
+    cache: Cache<Found>,
+    queue: UnboundedSender<Query>,
+}
+
+impl Links {
+    /// Returns the queue of spins to resolve along with it, for `run`. Fails
+    /// if the providers are misconfigured.
+    pub fn new(config: LinksConfig) -> Result<(Arc<Links>, UnboundedReceiver<Query>), String> {
+        let mut names = HashSet::new();
+        for provider in &config.providers {
+            if !names.insert(provider.name()) {
+                return Err(format!(
+                    "more than one provider is named \"{}\"",
+                    provider.name()
+                ));
+            }
+            if let LinkProvider::Spotify {
+                client_id,
+                client_secret,
+                ..
+            } = provider
+            {
+                if client_id.is_some() != client_secret.is_some() {
+                    return Err("Spotify needs both a client_id and a client_secret".to_string());
+                }
+            }
+        }
+        let cache = Cache::load(config.cache_path.clone(), config.cache_size);
+        let (queue, queries) = mpsc::unbounded_channel();
+        Ok((
+            Arc::new(Links {
+                config,
+                cache,
+                queue,
+            }),
+            queries,
+        ))
+    }
+
+    /// Adds `links` to `spin`: resolved ones if they're known, and searches
+    /// until then.
+    pub fn apply(&self, spin: &mut Value) {
+        let Some(query) = Query::from_spin(spin) else {
+            return;
+        };
+        let links = match self.cache.get(&query.key) {
+            Lookup::Known(found) => found.unwrap_or_default(),
+            Lookup::Missing => {
+                let links = self.searches(&query);
+                let _ = self.queue.send(query);
+                links
+            }
+            Lookup::Pending => self.searches(&query),
+        };
+        spin["links"] = json!(links);
+    }
+
+    fn searches(&self, query: &Query) -> Found {
+        self.config
+            .providers
+            .iter()
+            .map(|provider| (provider.name().to_string(), search(provider, query)))
+            .collect()
+    }
+
+    /// Resolves queued spins one at a time, calling `revise` after each.
+    /// Lookups that fail are dropped, to be queued again the next time the
+    /// spin is ingested.
+    pub async fn run(self: Arc<Self>, mut queries: UnboundedReceiver<Query>, revise: impl Fn()) {
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap();
+        let mut resolvers: Vec<Resolver> = self
+            .config
+            .providers
+            .iter()
+            .map(|provider| Resolver::new(provider.clone()))
+            .collect();
+        while let Some(query) = queries.recv().await {
+            let mut found = Found::new();
+            let mut result = Ok(());
+            for resolver in &mut resolvers {
+                match resolver.resolve(&client, &query).await {
+                    Ok(link) => {
+                        let link = link.unwrap_or_else(|| search(&resolver.provider, &query));
+                        found.insert(resolver.provider.name().to_string(), link);
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            match result {
+                Ok(()) => {
+                    debug!("Resolved links for {:?}: {:?}", query.key, found);
+                    self.cache.insert(&query.key, Some(found));
+                    self.cache.save().await;
+                    revise();
+                }
+                Err(e) => {
+                    warn!("Couldn't resolve links for {:?}: {}", query.key, e);
+                    self.cache.forget(&query.key);
+                }
+            }
+        }
+    }
+}
+
+// A search for the spin on the provider's site
+fn search(provider: &LinkProvider, query: &Query) -> String {
+    let template = match provider {
+        LinkProvider::Spotify { .. } => "https://open.spotify.com/search/{query}".to_string(),
+        LinkProvider::AppleMusic { country, .. } => {
+            format!("https://music.apple.com/{}/search?term={{query}}", country)
+        }
+        LinkProvider::Search { url, .. } => url.clone(),
+    };
+    let encode = |text: &str| utf8_percent_encode(text, NON_ALPHANUMERIC).to_string();
+    template
+        .replace(
+            "{query}",
+            &encode(&format!("{} {}", query.artist, query.song)),
+        )
+        .replace("{artist}", &encode(&query.artist))
+        .replace("{song}", &encode(&query.song))
+}
+
+// Finds the track itself on a provider with an API
+struct Resolver {
+    provider: LinkProvider,
+    pacer: Pacer,
+    // Spotify's access token and when to renew it
+    token: Option<(String, Instant)>,
+}
+
+impl Resolver {
+    fn new(provider: LinkProvider) -> Resolver {
+        let limit = match provider {
+            LinkProvider::AppleMusic { .. } => APPLE_MUSIC_LIMIT,
+            _ => SPOTIFY_LIMIT,
+        };
+        Resolver {
+            provider,
+            pacer: Pacer::new(limit),
+            token: None,
+        }
+    }
+
+    // None if there's no match by the spin's artist, or no API to ask
+    async fn resolve(
+        &mut self,
+        client: &reqwest::Client,
+        query: &Query,
+    ) -> Result<Option<String>, reqwest::Error> {
+        match self.provider.clone() {
+            LinkProvider::Spotify {
+                client_id: Some(client_id),
+                client_secret: Some(client_secret),
+                api_url,
+                accounts_url,
+            } => {
+                let token = self
+                    .spotify_token(client, &accounts_url, &client_id, &client_secret)
+                    .await?;
+                self.pacer.wait().await;
+                let search = format!(
+                    "track:\"{}\" artist:\"{}\"",
+                    query.song.replace('"', ""),
+                    query.artist.replace('"', "")
+                );
+                let results: Value = client
+                    .get(format!("{}/v1/search", api_url.trim_end_matches('/')))
+                    .query(&[
+                        ("q", search.as_str()),
+                        ("type", "track"),
+                        ("limit", RESULTS),
+                    ])
+                    .bearer_auth(token)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                let track = results["tracks"]["items"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .find(|track| {
+                        track["artists"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .any(|artist| same(artist["name"].as_str(), &query.artist))
+                    });
+                Ok(track.and_then(|track| {
+                    track["external_urls"]["spotify"]
+                        .as_str()
+                        .map(str::to_string)
+                }))
+            }
+            LinkProvider::AppleMusic { country, api_url } => {
+                self.pacer.wait().await;
+                let term = format!("{} {}", query.artist, query.song);
+                let results: Value = client
+                    .get(format!("{}/search", api_url.trim_end_matches('/')))
+                    .query(&[
+                        ("term", term.as_str()),
+                        ("media", "music"),
+                        ("entity", "song"),
+                        ("limit", RESULTS),
+                        ("country", country.as_str()),
+                    ])
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                let track = results["results"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .find(|track| same(track["artistName"].as_str(), &query.artist));
+                Ok(track.and_then(|track| track["trackViewUrl"].as_str().map(str::to_string)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Client credentials are exchanged for a token lasting an hour
+    async fn spotify_token(
+        &mut self,
+        client: &reqwest::Client,
+        accounts_url: &str,
+        client_id: &str,
+        client_secret: &str,
+    ) -> Result<String, reqwest::Error> {
+        if let Some((token, renew_at)) = &self.token {
+            if Instant::now() < *renew_at {
+                return Ok(token.clone());
+            }
+        }
+        let response: Value = client
+            .post(format!("{}/api/token", accounts_url.trim_end_matches('/')))
+            .basic_auth(client_id, Some(client_secret))
+            .form(&[("grant_type", "client_credentials")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let token = response["access_token"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let expires_in = response["expires_in"].as_u64().unwrap_or(3600);
+        // Renewed a minute early, so it never runs out mid-request
+        let renew_at = Instant::now() + Duration::from_secs(expires_in.saturating_sub(60));
+        self.token = Some((token.clone(), renew_at));
+        Ok(token)
+    }
+}
+
+fn same(name: Option<&str>, artist: &str) -> bool {
+    name.is_some_and(|name| name.to_lowercase() == artist.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+    use warp::http::StatusCode;
+
+    use crate::config::{LinkProvider, LinksConfig};
+    use crate::sinks::stand_in;
+
+    use super::Links;
+
+    const SPOTIFY_TRACKS: &str = r#"{"tracks": {"items": [
+        {"artists": [{"name": "Nina Simone Tribute Band"}],
+         "external_urls": {"spotify": "https://open.spotify.com/track/wrong"}},
+        {"artists": [{"name": "Nina Simone"}],
+         "external_urls": {"spotify": "https://open.spotify.com/track/abc"}}
+    ]}}"#;
+
+    const ITUNES_RESULTS: &str = r#"{"results": [
+        {"artistName": "Someone Else", "trackViewUrl": "https://music.apple.com/us/album/x"}
+    ]}"#;
+
+    fn config(url: &str) -> LinksConfig {
+        LinksConfig {
+            providers: vec![
+                LinkProvider::Spotify {
+                    client_id: Some("id".to_string()),
+                    client_secret: Some("secret".to_string()),
+                    api_url: url.to_string(),
+                    accounts_url: url.to_string(),
+                },
+                LinkProvider::AppleMusic {
+                    country: "gb".to_string(),
+                    api_url: url.to_string(),
+                },
+                LinkProvider::Search {
+                    name: "bandcamp".to_string(),
+                    url: "https://bandcamp.com/search?q={artist}%20{song}".to_string(),
+                },
+            ],
+            cache_path: None,
+            cache_size: 100,
+        }
+    }
+
+    fn spin() -> Value {
+        json!({"id": 2, "artist": "Nina Simone", "song": "Sinnerman"})
+    }
+
+    #[tokio::test]
+    async fn test_links() {
+        let (url, mut requests) = stand_in::serve_with(|request, _| match request.path.as_str() {
+            "/api/token" => (
+                StatusCode::OK,
+                r#"{"access_token": "token", "expires_in": 3600}"#.to_string(),
+            ),
+            "/v1/search" => (StatusCode::OK, SPOTIFY_TRACKS.to_string()),
+            "/search" => (StatusCode::OK, ITUNES_RESULTS.to_string()),
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        });
+        let (links, queries) = Links::new(config(&url)).unwrap();
+        let (revised, mut revisions) = mpsc::unbounded_channel();
+        tokio::spawn(links.clone().run(queries, move || {
+            let _ = revised.send(());
+        }));
+
+        // Searches straight away
+        let mut spin = spin();
+        links.apply(&mut spin);
+        assert_eq!(
+            spin["links"],
+            json!({
+                "spotify": "https://open.spotify.com/search/Nina%20Simone%20Sinnerman",
+                "apple_music": "https://music.apple.com/gb/search?term=Nina%20Simone%20Sinnerman",
+                "bandcamp": "https://bandcamp.com/search?q=Nina%20Simone%20Sinnerman",
+            })
+        );
+
+        revisions.recv().await.unwrap();
+        let mut spin = self::spin();
+        links.apply(&mut spin);
+        // Apple Music had nothing by the artist, so it's still a search
+        assert_eq!(
+            spin["links"],
+            json!({
+                "spotify": "https://open.spotify.com/track/abc",
+                "apple_music": "https://music.apple.com/gb/search?term=Nina%20Simone%20Sinnerman",
+                "bandcamp": "https://bandcamp.com/search?q=Nina%20Simone%20Sinnerman",
+            })
+        );
+
+        let token = requests.recv().await.unwrap();
+        assert_eq!(token.path, "/api/token");
+        // id:secret
+        assert_eq!(token.authorization.unwrap(), "Basic aWQ6c2VjcmV0");
+        let search = requests.recv().await.unwrap();
+        assert_eq!(
+            search.query["q"],
+            r#"track:"Sinnerman" artist:"Nina Simone""#
+        );
+        assert_eq!(search.authorization.unwrap(), "Bearer token");
+        let itunes = requests.recv().await.unwrap();
+        assert_eq!(itunes.query["term"], "Nina Simone Sinnerman");
+        assert_eq!(itunes.query["country"], "gb");
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let mut config = config("http://localhost");
+        config.providers.push(LinkProvider::Search {
+            name: "spotify".to_string(),
+            url: "https://example.com/{query}".to_string(),
+        });
+        assert!(Links::new(config).is_err());
+
+        let config = LinksConfig {
+            providers: vec![LinkProvider::Spotify {
+                client_id: Some("id".to_string()),
+                client_secret: None,
+                api_url: String::new(),
+                accounts_url: String::new(),
+            }],
+            cache_path: None,
+            cache_size: 100,
+        };
+        assert!(Links::new(config).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+//! Details looked up for each spin, like MusicBrainz IDs, cover art and
+//! links to the track on streaming services.
+//! Lookups run in the background: spins are given what's already known as
+//! they're ingested, and the cached /v2 spins are revised as more comes in.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    time::Instant,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::config::{EnrichmentConfig, Limit};
+use crate::models::Db;
+use crate::rate_limit::Bucket;
+
+mod links;
+mod musicbrainz;
+
+static ENRICHERS: OnceLock<Enrichers> = OnceLock::new();
+
+struct Enrichers {
+    musicbrainz: Option<Arc<musicbrainz::MusicBrainz>>,
+    links: Option<Arc<links::Links>>,
+}
+
+/// Starts looking up details for the spins in `db`, as configured. Call
+/// before the first fetch from Spinitron, so its spins are looked up too.
+pub fn start(config: &EnrichmentConfig, db: Db) {
+    let revise = move || db.revise_v2(apply);
+    let musicbrainz = config.musicbrainz.as_ref().map(|config| {
+        info!("Looking up spins on MusicBrainz at {}", config.url);
+        let (musicbrainz, queries) = musicbrainz::MusicBrainz::new(config.clone());
+        tokio::spawn(musicbrainz.clone().run(queries, revise.clone()));
+        musicbrainz
+    });
+    let links = config.links.as_ref().map(|config| {
+        let (links, queries) = links::Links::new(config.clone())
+            .unwrap_or_else(|e| panic!("Invalid links config: {}", e));
+        info!(
+            "Adding links to {} providers to spins",
+            config.providers.len()
+        );
+        tokio::spawn(links.clone().run(queries, revise.clone()));
+        links
+    });
+    if ENRICHERS.set(Enrichers { musicbrainz, links }).is_err() {
+        panic!("Enrichment started twice");
+    }
+}
+
+/// Adds what's known about each spin in a /v2 spins document, queueing
+/// lookups for the rest. Returns it unchanged if nothing's configured.
+pub fn apply(v2: &Value) -> Value {
+    let mut v2 = v2.clone();
+    if let Some(enrichers) = ENRICHERS.get() {
+        if let Some(spins) = v2["spins"].as_array_mut() {
+            for spin in spins {
+                if let Some(musicbrainz) = &enrichers.musicbrainz {
+                    musicbrainz.apply(spin);
+                }
+                if let Some(links) = &enrichers.links {
+                    links.apply(spin);
+                }
+            }
+        }
+    }
+    v2
+}
+
+/// What's known for a key in a `Cache`.
+#[derive(Debug, PartialEq)]
+pub enum Lookup<T> {
+    // Looked up; None if nothing was found
+    Known(Option<T>),
+    // Not looked up yet. The caller should queue it; later calls get
+    // `Pending` until it's been inserted or forgotten.
+    Missing,
+    Pending,
+}
+
+/// Lookup results by key, oldest forgotten first once it's full. Saved to
+/// `path`, if set, so they last across restarts.
+pub struct Cache<T> {
+    inner: Mutex<Entries<T>>,
+    path: Option<PathBuf>,
+    capacity: usize,
+}
+
+struct Entries<T> {
+    known: HashMap<String, Option<T>>,
+    // Keys of `known`, oldest first
+    order: VecDeque<String>,
+    pending: HashSet<String>,
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Cache<T> {
+    /// A cache holding what was saved at `path`. A missing or unreadable
+    /// file starts it empty.
+    pub fn load(path: Option<PathBuf>, capacity: usize) -> Cache<T> {
+        let saved: Vec<(String, Option<T>)> = path
+            .as_ref()
+            .and_then(|path| match std::fs::read(path) {
+                Ok(saved) => Some(saved),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    warn!("Couldn't read cached lookups from {:?}: {}", path, e);
+                    None
+                }
+            })
+            .and_then(|saved| match serde_json::from_slice(&saved) {
+                Ok(saved) => Some(saved),
+                Err(e) => {
+                    warn!("Ignoring cached lookups in {:?}: {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        let cache = Cache {
+            inner: Mutex::new(Entries {
+                known: HashMap::new(),
+                order: VecDeque::new(),
+                pending: HashSet::new(),
+            }),
+            path,
+            capacity,
+        };
+        for (key, value) in saved {
+            cache.insert(&key, value);
+        }
+        cache
+    }
+
+    pub fn get(&self, key: &str) -> Lookup<T> {
+        let mut entries = self.inner.lock().unwrap();
+        if let Some(value) = entries.known.get(key) {
+            return Lookup::Known(value.clone());
+        }
+        match entries.pending.insert(key.to_string()) {
+            true => Lookup::Missing,
+            false => Lookup::Pending,
+        }
+    }
+
+    pub fn insert(&self, key: &str, value: Option<T>) {
+        let mut entries = self.inner.lock().unwrap();
+        entries.pending.remove(key);
+        if entries.known.insert(key.to_string(), value).is_none() {
+            entries.order.push_back(key.to_string());
+        }
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.known.remove(&oldest);
+            }
+        }
+    }
+
+    /// Gives up on a pending lookup, e.g. after a network error, so it's
+    /// tried again the next time the key comes up.
+    pub fn forget(&self, key: &str) {
+        self.inner.lock().unwrap().pending.remove(key);
+    }
+
+    /// Writes the cache to its path, if it has one.
+    pub async fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let saved = {
+            let entries = self.inner.lock().unwrap();
+            let saved: Vec<(&String, &Option<T>)> = entries
+                .order
+                .iter()
+                .map(|key| (key, &entries.known[key]))
+                .collect();
+            serde_json::to_vec(&saved).unwrap()
+        };
+        // Replaced whole, so a crash never leaves it half written
+        let partial = path.with_extension("partial");
+        let result = match tokio::fs::write(&partial, saved).await {
+            Ok(()) => tokio::fs::rename(&partial, path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Couldn't save cached lookups to {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Keeps requests to a service within its limit.
+pub struct Pacer {
+    bucket: Bucket,
+    limit: Limit,
+}
+
+impl Pacer {
+    pub fn new(limit: Limit) -> Pacer {
+        Pacer {
+            bucket: Bucket::new(limit, Instant::now()),
+            limit,
+        }
+    }
+
+    /// Waits until the next request is allowed.
+    pub async fn wait(&mut self) {
+        while let Err(wait) = self.bucket.take(self.limit, Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::{Cache, Lookup};
+
+    #[test]
+    fn test_cache() {
+        let cache = Cache::load(None, 2);
+
+        assert_eq!(cache.get("a"), Lookup::Missing);
+        assert_eq!(cache.get("a"), Lookup::Pending);
+        cache.insert("a", Some(1));
+        assert_eq!(cache.get("a"), Lookup::Known(Some(1)));
+
+        cache.get("b");
+        cache.forget("b");
+        assert_eq!(cache.get("b"), Lookup::Missing);
+        cache.insert("b", None);
+        assert_eq!(cache.get("b"), Lookup::Known(None));
+
+        // The oldest goes first
+        cache.insert("c", Some(3));
+        assert_eq!(cache.get("a"), Lookup::Missing);
+        assert_eq!(cache.get("c"), Lookup::Known(Some(3)));
+    }
+
+    #[tokio::test]
+    async fn test_saved_cache() {
+        let path = env::temp_dir().join(format!("relay-lookups-{}.json", process::id()));
+        let cache = Cache::load(Some(path.clone()), 10);
+        cache.insert("a", Some("found".to_string()));
+        cache.insert("b", None);
+        cache.save().await;
+
+        let loaded: Cache<String> = Cache::load(Some(path.clone()), 10);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.get("a"), Lookup::Known(Some("found".to_string())));
+        assert_eq!(loaded.get("b"), Lookup::Known(None));
+        assert_eq!(loaded.get("c"), Lookup::Missing);
+    }
+}
@@ -0,0 +1,452 @@
+use std::{sync::Arc, time::Duration};
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::config::{Limit, MusicBrainzConfig};
+
+use super::{Cache, Lookup, Pacer};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// MusicBrainz allows a request a second. The Cover Art Archive doesn't
+// limit requests, but is paced the same to be polite.
+const LIMIT: Limit = Limit {
+    per_second: 1.0,
+    burst: 1,
+};
+
+// Search results scoring lower (out of 100) are taken as no match
+const MIN_SCORE: u64 = 90;
+
+/// What was found for a spin.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Match {
+    pub recording_id: String,
+    pub release_id: Option<String>,
+    // As credited on MusicBrainz, which may be spelled differently
+    pub artist: Option<String>,
+    pub cover_art: Option<String>,
+}
+
+/// A spin to look up, by ISRC if it has one and by artist, song and
+/// release otherwise.
+#[derive(Debug)]
+pub struct Query {
+    key: String,
+    artist: String,
+    song: String,
+    release: String,
+    isrc: String,
+}
+
+impl Query {
+    fn from_spin(spin: &Value) -> Option<Query> {
+        let text = |field: &str| spin[field].as_str().unwrap_or_default().trim().to_string();
+        let (artist, song, release) = (text("artist"), text("song"), text("release"));
+        let isrc = text("isrc").to_uppercase();
+        let searchable = !artist.is_empty() && !song.is_empty();
+        let key = match isrc.as_str() {
+            "" if searchable => format!("{}\n{}\n{}", artist, song, release).to_lowercase(),
+            "" => return None,
+            isrc => format!("isrc:{}", isrc),
+        };
+        Some(Query {
+            key,
+            artist,
+            song,
+            release,
+            isrc,
+        })
+    }
+
+    fn searchable(&self) -> bool {
+        !self.artist.is_empty() && !self.song.is_empty()
+    }
+}
+
+pub struct MusicBrainz {
+    config: MusicBrainzConfig,
+    cache: Cache<Match>,
+    client: reqwest::Client,
+    queue: UnboundedSender<Query>,
+}
+
+impl MusicBrainz {
+    /// Returns the queue of spins to look up along with it, for `run`.
+    pub fn new(config: MusicBrainzConfig) -> (Arc<MusicBrainz>, UnboundedReceiver<Query>) {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .user_agent(format!(
+                "api-relay/{} ( {} )",
+                env!("CARGO_PKG_VERSION"),
+                config.contact
+            ))
+            .build()
+            .unwrap();
+        let cache = Cache::load(config.cache_path.clone(), config.cache_size);
+        let (queue, queries) = mpsc::unbounded_channel();
+        let musicbrainz = MusicBrainz {
+            config,
+            cache,
+            client,
+            queue,
+        };
+        (Arc::new(musicbrainz), queries)
+    }
+
+    /// Adds what's been found for `spin` to it, or queues it to be looked up.
+    pub fn apply(&self, spin: &mut Value) {
+        let Some(query) = Query::from_spin(spin) else {
+            return;
+        };
+        match self.cache.get(&query.key) {
+            Lookup::Known(Some(found)) => attach(spin, &found),
+            Lookup::Known(None) | Lookup::Pending => {}
+            Lookup::Missing => {
+                let _ = self.queue.send(query);
+            }
+        }
+    }
+
+    /// Looks up queued spins one at a time, calling `revise` whenever
+    /// something's found. Lookups that fail are dropped, to be queued
+    /// again the next time the spin is ingested.
+    pub async fn run(self: Arc<Self>, queries: UnboundedReceiver<Query>, revise: impl Fn()) {
+        self.run_with_limit(queries, LIMIT, revise).await
+    }
+
+    async fn run_with_limit(
+        self: Arc<Self>,
+        mut queries: UnboundedReceiver<Query>,
+        limit: Limit,
+        revise: impl Fn(),
+    ) {
+        let mut pacer = Pacer::new(limit);
+        while let Some(query) = queries.recv().await {
+            match self.lookup(&query, &mut pacer).await {
+                Ok(found) => {
+                    debug!("Looked up {:?} on MusicBrainz: {:?}", query.key, found);
+                    let found_something = found.is_some();
+                    self.cache.insert(&query.key, found);
+                    self.cache.save().await;
+                    if found_something {
+                        revise();
+                    }
+                }
+                Err(e) => {
+                    warn!("Couldn't look up {:?} on MusicBrainz: {}", query.key, e);
+                    self.cache.forget(&query.key);
+                }
+            }
+        }
+    }
+
+    async fn lookup(
+        &self,
+        query: &Query,
+        pacer: &mut Pacer,
+    ) -> Result<Option<Match>, reqwest::Error> {
+        let mut recording = None;
+        if !query.isrc.is_empty() {
+            recording = self.by_isrc(&query.isrc, pacer).await?;
+        }
+        if recording.is_none() && query.searchable() {
+            recording = self.search(query, pacer).await?;
+        }
+        let Some(recording) = recording else {
+            return Ok(None);
+        };
+
+        let release_id = release_id(&recording, &query.release);
+        let cover_art = match &release_id {
+            Some(release_id) => self.cover_art(release_id, pacer).await?,
+            None => None,
+        };
+        Ok(Some(Match {
+            recording_id: recording["id"].as_str().unwrap_or_default().to_string(),
+            release_id,
+            artist: artist_credit(&recording),
+            cover_art,
+        }))
+    }
+
+    async fn by_isrc(
+        &self,
+        isrc: &str,
+        pacer: &mut Pacer,
+    ) -> Result<Option<Value>, reqwest::Error> {
+        let url = format!(
+            "{}/ws/2/isrc/{}",
+            self.config.url.trim_end_matches('/'),
+            isrc
+        );
+        let query = [("inc", "artist-credits+releases"), ("fmt", "json")];
+        let found = self.get(url, &query, pacer).await?;
+        Ok(found.and_then(|found| found["recordings"].get(0).cloned()))
+    }
+
+    // Searches with the release first, if there is one, as the song may be
+    // on several
+    async fn search(
+        &self,
+        query: &Query,
+        pacer: &mut Pacer,
+    ) -> Result<Option<Value>, reqwest::Error> {
+        let url = format!("{}/ws/2/recording", self.config.url.trim_end_matches('/'));
+        let base = format!(
+            "recording:\"{}\" AND artist:\"{}\"",
+            escape(&query.song),
+            escape(&query.artist)
+        );
+        let mut searches = vec![base.clone()];
+        if !query.release.is_empty() {
+            searches.insert(
+                0,
+                format!("{} AND release:\"{}\"", base, escape(&query.release)),
+            );
+        }
+        for search in searches {
+            let params = [("query", search.as_str()), ("limit", "5"), ("fmt", "json")];
+            let results = self.get(url.clone(), &params, pacer).await?;
+            let best = results.and_then(|results| {
+                results["recordings"]
+                    .as_array()?
+                    .iter()
+                    .find(|recording| recording["score"].as_u64() >= Some(MIN_SCORE))
+                    .cloned()
+            });
+            if best.is_some() {
+                return Ok(best);
+            }
+        }
+        Ok(None)
+    }
+
+    // The front cover's 500px thumbnail, or the full image if there's none
+    async fn cover_art(
+        &self,
+        release_id: &str,
+        pacer: &mut Pacer,
+    ) -> Result<Option<String>, reqwest::Error> {
+        let url = format!(
+            "{}/release/{}",
+            self.config.cover_art_url.trim_end_matches('/'),
+            release_id
+        );
+        let Some(art) = self.get(url, &[], pacer).await? else {
+            return Ok(None);
+        };
+        let front = art["images"]
+            .as_array()
+            .and_then(|images| images.iter().find(|image| image["front"] == true));
+        Ok(front.and_then(|front| {
+            front["thumbnails"]["500"]
+                .as_str()
+                .or(front["image"].as_str())
+                .map(str::to_string)
+        }))
+    }
+
+    // None for a 404, which both services answer when nothing's found
+    async fn get(
+        &self,
+        url: String,
+        query: &[(&str, &str)],
+        pacer: &mut Pacer,
+    ) -> Result<Option<Value>, reqwest::Error> {
+        pacer.wait().await;
+        let response = self.client.get(url).query(query).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+}
+
+// Prefers the release the spin names, as the recording may be on several
+fn release_id(recording: &Value, wanted: &str) -> Option<String> {
+    let releases = recording["releases"].as_array()?;
+    releases
+        .iter()
+        .find(|release| {
+            release["title"]
+                .as_str()
+                .is_some_and(|title| title.eq_ignore_ascii_case(wanted))
+        })
+        .or(releases.first())
+        .and_then(|release| release["id"].as_str())
+        .map(str::to_string)
+}
+
+// "Simon & Garfunkel", or "Jay-Z feat. Alicia Keys" for several artists
+fn artist_credit(recording: &Value) -> Option<String> {
+    let credit: String = recording["artist-credit"]
+        .as_array()?
+        .iter()
+        .map(|artist| {
+            format!(
+                "{}{}",
+                artist["name"].as_str().unwrap_or_default(),
+                artist["joinphrase"].as_str().unwrap_or_default()
+            )
+        })
+        .collect();
+    (!credit.is_empty()).then_some(credit)
+}
+
+// For a phrase in a Lucene query
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// The cover art stands in for the spin's image if Spinitron has none. An
+// image field redacted away isn't brought back.
+fn attach(spin: &mut Value, found: &Match) {
+    spin["musicbrainz"] = json!({
+        "recording_id": found.recording_id,
+        "release_id": found.release_id,
+        "artist": found.artist,
+    });
+    if let Some(cover_art) = &found.cover_art {
+        spin["cover_art"] = json!(cover_art);
+        let missing_image = spin
+            .get("image")
+            .is_some_and(|image| image.as_str().is_none_or(str::is_empty));
+        if missing_image {
+            spin["image"] = json!(cover_art);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+    use warp::http::StatusCode;
+
+    use crate::config::{Limit, MusicBrainzConfig};
+    use crate::sinks::stand_in;
+
+    use super::{MusicBrainz, Query};
+
+    const RECORDINGS: &str = r#"{"recordings": [{
+        "id": "rec-1",
+        "score": 100,
+        "artist-credit": [{"name": "Nina Simone", "joinphrase": ""}],
+        "releases": [
+            {"id": "rel-0", "title": "The Best of Nina Simone"},
+            {"id": "rel-1", "title": "Pastel Blues"}
+        ]
+    }]}"#;
+
+    const COVER_ART: &str = r#"{"images": [
+        {"front": false, "image": "https://caa.test/back.jpg", "thumbnails": {}},
+        {"front": true, "image": "https://caa.test/front.jpg",
+         "thumbnails": {"500": "https://caa.test/front-500.jpg"}}
+    ]}"#;
+
+    fn config(url: String) -> MusicBrainzConfig {
+        MusicBrainzConfig {
+            contact: "ops@kscu.org".to_string(),
+            url: url.clone(),
+            cover_art_url: url,
+            cache_path: None,
+            cache_size: 100,
+        }
+    }
+
+    // Looks `spin` up, returning it with what was found once the lookup's done
+    async fn enrich(url: String, mut spin: Value) -> Value {
+        let (musicbrainz, queries) = MusicBrainz::new(config(url));
+        let (revised, mut revisions) = mpsc::unbounded_channel();
+        let fast = Limit {
+            per_second: 100.0,
+            burst: 10,
+        };
+        tokio::spawn(musicbrainz.clone().run_with_limit(queries, fast, move || {
+            let _ = revised.send(());
+        }));
+
+        let unchanged = spin.clone();
+        musicbrainz.apply(&mut spin);
+        assert_eq!(spin, unchanged);
+        revisions.recv().await.unwrap();
+        musicbrainz.apply(&mut spin);
+        spin
+    }
+
+    #[test]
+    fn test_query() {
+        let key = |spin| Query::from_spin(&spin).map(|query| query.key);
+
+        assert_eq!(
+            key(json!({"artist": "Nina Simone", "song": "Sinnerman", "release": null})),
+            Some("nina simone\nsinnerman\n".to_string())
+        );
+        assert_eq!(
+            key(json!({"artist": "Nina Simone", "isrc": "usrc16500123"})),
+            Some("isrc:USRC16500123".to_string())
+        );
+        assert_eq!(key(json!({"artist": "Nina Simone", "song": ""})), None);
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let (url, mut requests) = stand_in::serve_with(|request, _| match request.path.as_str() {
+            "/ws/2/recording" => (StatusCode::OK, RECORDINGS.to_string()),
+            "/release/rel-1" => (StatusCode::OK, COVER_ART.to_string()),
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        });
+        let spin = json!({
+            "id": 2,
+            "artist": "Nina Simone",
+            "song": "Sinnerman",
+            "release": "pastel blues",
+            "image": null
+        });
+        let spin = enrich(url, spin).await;
+
+        assert_eq!(
+            spin["musicbrainz"],
+            json!({"recording_id": "rec-1", "release_id": "rel-1", "artist": "Nina Simone"})
+        );
+        assert_eq!(spin["cover_art"], "https://caa.test/front-500.jpg");
+        assert_eq!(spin["image"], "https://caa.test/front-500.jpg");
+        let search = requests.recv().await.unwrap();
+        assert_eq!(
+            search.query["query"],
+            r#"recording:"Sinnerman" AND artist:"Nina Simone" AND release:"pastel blues""#
+        );
+        assert_eq!(search.query["fmt"], "json");
+    }
+
+    #[tokio::test]
+    async fn test_isrc_without_cover_art() {
+        let (url, mut requests) = stand_in::serve_with(|request, _| match request.path.as_str() {
+            "/ws/2/isrc/USRC16500123" => (StatusCode::OK, RECORDINGS.to_string()),
+            _ => (StatusCode::NOT_FOUND, String::new()),
+        });
+        let spin = json!({
+            "id": 2,
+            "artist": "Nina Simone",
+            "song": "Sinnerman",
+            "isrc": "USRC16500123",
+            "image": "https://spinitron.test/art.jpg"
+        });
+        let spin = enrich(url, spin).await;
+
+        assert_eq!(spin["musicbrainz"]["recording_id"], "rec-1");
+        // Nothing matches the release, so it's the first one
+        assert_eq!(spin["musicbrainz"]["release_id"], "rel-0");
+        assert!(spin.get("cover_art").is_none());
+        assert_eq!(spin["image"], "https://spinitron.test/art.jpg");
+        assert_eq!(
+            requests.recv().await.unwrap().query["inc"],
+            "artist-credits+releases"
+        );
+        assert_eq!(requests.recv().await.unwrap().path, "/release/rel-0");
+    }
+}
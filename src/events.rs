@@ -0,0 +1,129 @@
+use std::sync::OnceLock;
+
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+// Events a sink may fall behind by before it starts missing them
+pub const CAPACITY: usize = 64;
+
+static BUS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+
+/// Something that changed in the data fetched from Spinitron. Records are in
+/// the /v2 format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    // A spin that wasn't the latest before now is
+    SpinChanged(Value),
+    // The first show in /v2/shows, the one on air or next up, is a different one
+    ShowChanged(Value),
+}
+
+fn bus() -> &'static broadcast::Sender<Event> {
+    BUS.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// Events published from now on.
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    bus().subscribe()
+}
+
+pub fn publish(event: Event) {
+    debug!("Publishing {:?}", event);
+    // Fails only when nothing's subscribed, i.e. no sinks are configured
+    let _ = bus().send(event);
+}
+
+/// The event for replacing the /v2 spins document `previous` with `current`.
+pub fn spin_change(previous: &Value, current: &Value) -> Option<Event> {
+    changed(previous, current, "spins").map(Event::SpinChanged)
+}
+
+/// The event for replacing the /v2 shows document `previous` with `current`.
+pub fn show_change(previous: &Value, current: &Value) -> Option<Event> {
+    changed(previous, current, "shows").map(Event::ShowChanged)
+}
+
+/// Events that bring a sink that's seen nothing up to date with the /v2
+/// spins and shows documents: the current show, then the latest spin.
+pub fn current(spins: &Value, shows: &Value) -> Vec<Event> {
+    let show = first(shows, "shows").map(Event::ShowChanged);
+    let spin = first(spins, "spins").map(Event::SpinChanged);
+    show.into_iter().chain(spin).collect()
+}
+
+fn first(doc: &Value, list: &str) -> Option<Value> {
+    doc[list]
+        .as_array()
+        .and_then(|items| items.first())
+        .cloned()
+}
+
+fn changed(previous: &Value, current: &Value, list: &str) -> Option<Value> {
+    // The first fetch after starting up isn't a change, or every restart
+    // would post the same spin again
+    if previous.is_null() {
+        return None;
+    }
+    let current = first(current, list)?;
+    match first(previous, list) {
+        Some(previous) if previous["id"] == current["id"] => None,
+        _ => Some(current),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{current, show_change, spin_change, Event};
+
+    #[test]
+    fn test_spin_change() {
+        let before = json!({"spins": [{"id": 1}]});
+        let after = json!({"spins": [{"id": 2, "song": "Sinnerman"}, {"id": 1}]});
+
+        assert_eq!(
+            spin_change(&before, &after),
+            Some(Event::SpinChanged(json!({"id": 2, "song": "Sinnerman"})))
+        );
+        assert_eq!(spin_change(&after, &after), None);
+        // The first fetch after starting up
+        assert_eq!(spin_change(&Value::Null, &after), None);
+        assert_eq!(
+            spin_change(&json!({"spins": []}), &after),
+            Some(Event::SpinChanged(json!({"id": 2, "song": "Sinnerman"})))
+        );
+        assert_eq!(spin_change(&after, &json!({"spins": []})), None);
+    }
+
+    #[test]
+    fn test_show_change() {
+        let before = json!({"shows": [{"id": 10}, {"id": 11}]});
+        let after = json!({"shows": [{"id": 11}, {"id": 12}]});
+
+        assert_eq!(
+            show_change(&before, &after),
+            Some(Event::ShowChanged(json!({"id": 11})))
+        );
+        assert_eq!(show_change(&before, &before), None);
+        assert_eq!(show_change(&Value::Null, &after), None);
+    }
+
+    #[test]
+    fn test_current() {
+        let spins = json!({"spins": [{"id": 2}, {"id": 1}]});
+        let shows = json!({"shows": [{"id": 11}, {"id": 12}]});
+
+        assert_eq!(
+            current(&spins, &shows),
+            vec![
+                Event::ShowChanged(json!({"id": 11})),
+                Event::SpinChanged(json!({"id": 2}))
+            ]
+        );
+        assert_eq!(
+            current(&spins, &Value::Null),
+            vec![Event::SpinChanged(json!({"id": 2}))]
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use chrono::{DateTime, FixedOffset};
+use serde_json::Value;
+
+use crate::config::FeedsConfig;
+
+// Feed readers identify items by GUID, so these must never change for a spin
+const GUID_PREFIX: &str = "urn:spinitron:spin:";
+
+// Used as the Atom feed's ID when no `link` is configured
+const DEFAULT_FEED_ID: &str = "urn:api-relay:spins";
+
+// RSS requires a channel link; Spinitron's site stands in for the station's
+const DEFAULT_LINK: &str = "https://spinitron.com";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Rss,
+    Atom,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Rss => "application/rss+xml; charset=utf-8",
+            Format::Atom => "application/atom+xml; charset=utf-8",
+        }
+    }
+
+    /// The feed of the spins in the /v2 spins document. `updated` is when
+    /// the spins were last fetched.
+    pub fn render(
+        self,
+        spins: &Value,
+        config: &FeedsConfig,
+        updated: DateTime<FixedOffset>,
+    ) -> String {
+        match self {
+            Format::Rss => rss(spins, config),
+            Format::Atom => atom(spins, config, updated),
+        }
+    }
+}
+
+/// An RSS 2.0 feed of the spins in the /v2 spins document, newest first.
+fn rss(spins: &Value, config: &FeedsConfig) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push_str(r#"<rss version="2.0"><channel>"#);
+    element(&mut xml, "title", &config.title);
+    element(
+        &mut xml,
+        "link",
+        config.link.as_deref().unwrap_or(DEFAULT_LINK),
+    );
+    element(&mut xml, "description", &config.description);
+    for spin in items(spins) {
+        xml.push_str("<item>");
+        element(&mut xml, "title", &title(spin));
+        if let Some(description) = description(spin) {
+            element(&mut xml, "description", &description);
+        }
+        xml.push_str(&format!(
+            r#"<guid isPermaLink="false">{}</guid>"#,
+            escape(&guid(spin))
+        ));
+        if let Some(start) = start(spin) {
+            element(&mut xml, "pubDate", &start.to_rfc2822());
+        }
+        if let Some(image) = image(spin) {
+            // The size isn't known without fetching the image; 0 is the usual stand-in
+            xml.push_str(&format!(
+                r#"<enclosure url="{}" length="0" type="{}"/>"#,
+                escape(image),
+                image_type(image)
+            ));
+        }
+        xml.push_str("</item>");
+    }
+    xml.push_str("</channel></rss>");
+    xml
+}
+
+/// An Atom feed of the spins in the /v2 spins document, newest first.
+fn atom(spins: &Value, config: &FeedsConfig, updated: DateTime<FixedOffset>) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+    element(&mut xml, "title", &config.title);
+    element(&mut xml, "subtitle", &config.description);
+    element(
+        &mut xml,
+        "id",
+        config.link.as_deref().unwrap_or(DEFAULT_FEED_ID),
+    );
+    if let Some(link) = &config.link {
+        xml.push_str(&format!(r#"<link href="{}"/>"#, escape(link)));
+    }
+    element(&mut xml, "updated", &updated.to_rfc3339());
+    // Atom requires an author for every entry; the station's as good as any
+    xml.push_str("<author>");
+    element(&mut xml, "name", &config.title);
+    xml.push_str("</author>");
+    for spin in items(spins) {
+        xml.push_str("<entry>");
+        element(&mut xml, "title", &title(spin));
+        element(&mut xml, "id", &guid(spin));
+        let start = start(spin).unwrap_or(updated);
+        element(&mut xml, "published", &start.to_rfc3339());
+        element(&mut xml, "updated", &start.to_rfc3339());
+        if let Some(description) = description(spin) {
+            element(&mut xml, "summary", &description);
+        }
+        if let Some(image) = image(spin) {
+            xml.push_str(&format!(
+                r#"<link rel="enclosure" href="{}" type="{}"/>"#,
+                escape(image),
+                image_type(image)
+            ));
+        }
+        xml.push_str("</entry>");
+    }
+    xml.push_str("</feed>");
+    xml
+}
+
+// Only the spins in the cache, as the relay keeps no history beyond them
+fn items(spins: &Value) -> &[Value] {
+    spins["spins"].as_array().map(Vec::as_slice).unwrap_or(&[])
+}
+
+fn element(xml: &mut String, name: &str, text: &str) {
+    xml.push_str(&format!("<{}>{}</{}>", name, escape(text), name));
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn text<'a>(spin: &'a Value, field: &str) -> Option<&'a str> {
+    spin[field].as_str().filter(|text| !text.is_empty())
+}
+
+// "Artist – Song", or whichever of the two is relayed
+fn title(spin: &Value) -> String {
+    match (text(spin, "artist"), text(spin, "song")) {
+        (Some(artist), Some(song)) => format!("{} – {}", artist, song),
+        (Some(title), None) | (None, Some(title)) => title.to_string(),
+        (None, None) => "Untitled".to_string(),
+    }
+}
+
+// "Release (Label)"
+fn description(spin: &Value) -> Option<String> {
+    match (text(spin, "release"), text(spin, "label")) {
+        (Some(release), Some(label)) => Some(format!("{} ({})", release, label)),
+        (Some(release), None) => Some(release.to_string()),
+        (None, Some(label)) => Some(label.to_string()),
+        (None, None) => None,
+    }
+}
+
+fn guid(spin: &Value) -> String {
+    format!("{}{}", GUID_PREFIX, spin["id"])
+}
+
+fn start(spin: &Value) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(text(spin, "start")?).ok()
+}
+
+fn image(spin: &Value) -> Option<&str> {
+    text(spin, "image")
+}
+
+// Spinitron's album art is almost always JPEG
+fn image_type(url: &str) -> &'static str {
+    let path = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(url)
+        .to_ascii_lowercase();
+    if path.ends_with(".png") {
+        "image/png"
+    } else if path.ends_with(".gif") {
+        "image/gif"
+    } else if path.ends_with(".webp") {
+        "image/webp"
+    } else {
+        "image/jpeg"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use serde_json::json;
+
+    use crate::config::FeedsConfig;
+
+    use super::{atom, rss};
+
+    fn spins() -> serde_json::Value {
+        json!({
+            "spins": [
+                {
+                    "id": 2,
+                    "artist": "Simon & Garfunkel",
+                    "song": "The Boxer",
+                    "release": "Bridge over Troubled Water",
+                    "label": "Columbia",
+                    "start": "2024-03-01T12:03:00-08:00",
+                    "image": "https://i.scdn.co/image/abc.png?size=300"
+                },
+                {"id": 1, "song": "Space Is the Place", "start": "not a time"}
+            ]
+        })
+    }
+
+    #[test]
+    fn test_rss() {
+        let xml = rss(&spins(), &FeedsConfig::default());
+
+        assert!(xml.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?><rss version="2.0">"#));
+        assert!(xml.contains(
+            "<item><title>Simon &amp; Garfunkel – The Boxer</title>\
+             <description>Bridge over Troubled Water (Columbia)</description>\
+             <guid isPermaLink=\"false\">urn:spinitron:spin:2</guid>\
+             <pubDate>Fri, 1 Mar 2024 12:03:00 -0800</pubDate>\
+             <enclosure url=\"https://i.scdn.co/image/abc.png?size=300\" length=\"0\" \
+             type=\"image/png\"/></item>"
+        ));
+        assert!(xml.contains(
+            "<item><title>Space Is the Place</title>\
+             <guid isPermaLink=\"false\">urn:spinitron:spin:1</guid></item>"
+        ));
+        // RSS requires a link, so there's one even when none is configured
+        assert!(xml.contains("<link>https://spinitron.com</link>"));
+    }
+
+    #[test]
+    fn test_atom() {
+        let config = FeedsConfig {
+            link: Some("https://kscu.org".to_string()),
+            ..FeedsConfig::default()
+        };
+        let updated = DateTime::parse_from_rfc3339("2024-03-01T20:04:00+00:00").unwrap();
+        let xml = atom(&spins(), &config, updated);
+
+        assert!(xml.contains("<id>https://kscu.org</id><link href=\"https://kscu.org\"/>"));
+        assert!(xml.contains(
+            "<entry><title>Simon &amp; Garfunkel – The Boxer</title>\
+             <id>urn:spinitron:spin:2</id>\
+             <published>2024-03-01T12:03:00-08:00</published>"
+        ));
+        // Entries without a usable start time fall back to the feed's
+        assert!(xml.contains(
+            "<id>urn:spinitron:spin:1</id><published>2024-03-01T20:04:00+00:00</published>"
+        ));
+        assert!(xml.contains(
+            "<link rel=\"enclosure\" href=\"https://i.scdn.co/image/abc.png?size=300\" \
+             type=\"image/png\"/>"
+        ));
+        assert!(xml.ends_with("</feed>"));
+    }
+}
@@ -0,0 +1,434 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::OnceLock,
+};
+
+use serde_json::Value;
+use warp::{reject::Reject, Filter, Rejection};
+
+use crate::config::{self, FieldsConfig, RecordFields};
+
+// The query parameter clients list the fields they want in, comma separated
+const FIELDS_QUERY: &str = "fields";
+
+// Kept whatever the allowlists say: /now and the feeds need the times, and
+// `_links` is how DJs are found (it's stripped before anything is served)
+const ALWAYS_KEPT: &[&str] = &["id", "start", "end", "duration", "_links"];
+
+// Fields the relay adds itself, like the DJs nested in each /v2 show and
+// what enrichment finds for each spin
+const ADDED_FIELDS: &[&str] = &["djs", "musicbrainz", "cover_art", "links"];
+
+// Read by name by the feeds, enrichment, sinks and /images, so they can be
+// denied but not renamed
+const SPIN_READ: &[&str] = &["artist", "song", "release", "label", "isrc", "image", "url"];
+const SHOW_READ: &[&str] = &["title", "category", "image", "url"];
+const DJ_READ: &[&str] = &["name", "image"];
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Spin,
+    Show,
+    Dj,
+}
+
+/// Rejection for a `fields=` list naming fields that are never relayed.
+/// Turned into a 400 by `filters::handle_rejection`.
+#[derive(Debug)]
+pub struct UnknownFields(pub Vec<String>);
+
+impl Reject for UnknownFields {}
+
+/// The configured allowlists, denylists and renames, applied to Spinitron's
+/// records as they're fetched so redacted fields never reach the cache.
+pub struct Policy {
+    spin: RecordPolicy,
+    show: RecordPolicy,
+    dj: RecordPolicy,
+}
+
+struct RecordPolicy {
+    allow: Option<HashSet<String>>,
+    deny: HashSet<String>,
+    rename: HashMap<String, String>,
+}
+
+impl RecordPolicy {
+    fn new(config: &RecordFields, read: &[&str]) -> Result<RecordPolicy, String> {
+        let kept = |name: &String| ALWAYS_KEPT.contains(&name.as_str());
+        if let Some(name) = config.deny.iter().find(|name| kept(name)) {
+            return Err(format!("\"{}\" can't be denied", name));
+        }
+        let fixed = |name: &String| kept(name) || read.contains(&name.as_str());
+        if let Some((from, to)) = config
+            .rename
+            .iter()
+            .find(|(from, to)| fixed(from) || fixed(to))
+        {
+            return Err(format!("\"{}\" can't be renamed to \"{}\"", from, to));
+        }
+        let mut targets = HashSet::new();
+        if let Some(to) = config.rename.values().find(|to| !targets.insert(*to)) {
+            return Err(format!("more than one field is renamed to \"{}\"", to));
+        }
+        Ok(RecordPolicy {
+            allow: config
+                .allow
+                .as_ref()
+                .map(|fields| fields.iter().cloned().collect()),
+            deny: config.deny.iter().cloned().collect(),
+            rename: config.rename.clone(),
+        })
+    }
+
+    // Whether a Spinitron field is relayed, under whatever name
+    fn relays(&self, name: &str) -> bool {
+        ALWAYS_KEPT.contains(&name)
+            || (!self.deny.contains(name)
+                && self.allow.as_ref().is_none_or(|allow| allow.contains(name)))
+    }
+
+    // Whether a relayed field is renamed to this name
+    fn renamed_to(&self, name: &str) -> bool {
+        self.rename
+            .iter()
+            .any(|(from, to)| to == name && self.relays(from))
+    }
+
+    // Whether a relayed record may have a field with this name
+    fn exposes(&self, name: &str) -> bool {
+        self.renamed_to(name) || (!self.rename.contains_key(name) && self.relays(name))
+    }
+}
+
+impl Policy {
+    /// Fails if the config denies or renames a field the relay depends on, or
+    /// renames two fields to the same name.
+    pub fn new(config: &FieldsConfig) -> Result<Policy, String> {
+        let record = |kind: &str, fields: &RecordFields, read: &[&str]| {
+            RecordPolicy::new(fields, read).map_err(|e| format!("[fields.{}]: {}", kind, e))
+        };
+        Ok(Policy {
+            spin: record("spin", &config.spin, SPIN_READ)?,
+            show: record("show", &config.show, SHOW_READ)?,
+            dj: record("dj", &config.dj, DJ_READ)?,
+        })
+    }
+
+    fn record(&self, kind: Kind) -> &RecordPolicy {
+        match kind {
+            Kind::Spin => &self.spin,
+            Kind::Show => &self.show,
+            Kind::Dj => &self.dj,
+        }
+    }
+
+    /// Drops the fields of one record that aren't relayed and renames the rest.
+    pub fn apply(&self, kind: Kind, record: &mut Value) {
+        let policy = self.record(kind);
+        if let Some(fields) = record.as_object_mut() {
+            let relayed = std::mem::take(fields)
+                .into_iter()
+                // A renamed field replaces one that already had its new name
+                .filter(|(name, _)| {
+                    policy.relays(name)
+                        && (policy.rename.contains_key(name) || !policy.renamed_to(name))
+                })
+                .map(|(name, value)| match policy.rename.get(&name) {
+                    Some(to) => (to.clone(), value),
+                    None => (name, value),
+                });
+            fields.extend(relayed);
+        }
+    }
+
+    /// Applies `apply` to each record in a Spinitron collection response.
+    pub fn apply_items(&self, kind: Kind, raw: &mut Value) {
+        if let Some(items) = raw["items"].as_array_mut() {
+            for item in items {
+                self.apply(kind, item);
+            }
+        }
+    }
+
+    // Whether any record type might have this field
+    fn exposable(&self, name: &str) -> bool {
+        ADDED_FIELDS.contains(&name)
+            || [&self.spin, &self.show, &self.dj]
+                .iter()
+                .any(|record| record.exposes(name))
+    }
+
+    pub fn projection(&self, list: &str) -> Result<Projection, UnknownFields> {
+        let fields: HashSet<String> = list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        let mut unknown: Vec<String> = fields
+            .iter()
+            .filter(|name| !self.exposable(name))
+            .cloned()
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort();
+            return Err(UnknownFields(unknown));
+        }
+        Ok(Projection(fields))
+    }
+}
+
+/// The policy built from the config file.
+pub fn policy() -> &'static Policy {
+    POLICY.get_or_init(|| {
+        Policy::new(&config::get().fields)
+            .unwrap_or_else(|e| panic!("Invalid fields config: {}", e))
+    })
+}
+
+/// A client's `fields=` selection.
+#[derive(Debug)]
+pub struct Projection(HashSet<String>);
+
+impl Projection {
+    /// Keeps only the selected fields of every record in `doc`. Records are
+    /// the objects with an `id`; the objects and arrays holding them (and any
+    /// other top-level values, like /now's `on_air`) are left as they are.
+    /// Nested records, like a show's `djs`, are projected with the same list.
+    pub fn apply(&self, doc: &Value) -> Value {
+        match doc {
+            Value::Object(fields) if fields.contains_key("id") => Value::Object(
+                fields
+                    .iter()
+                    .filter(|(name, _)| self.0.contains(*name))
+                    .map(|(name, value)| (name.clone(), self.apply(value)))
+                    .collect(),
+            ),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.clone(), self.apply(value)))
+                    .collect(),
+            ),
+            Value::Array(items) => {
+                Value::Array(items.iter().map(|item| self.apply(item)).collect())
+            }
+            other => other.clone(),
+        }
+    }
+}
+
+/// Extracts the client's `fields=` selection, if it made one, rejecting with
+/// `UnknownFields` if it asks for fields that are never relayed.
+pub fn projection() -> impl Filter<Extract = (Option<Projection>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().and_then(parse_query)
+}
+
+async fn parse_query(query: HashMap<String, String>) -> Result<Option<Projection>, Rejection> {
+    match query.get(FIELDS_QUERY) {
+        Some(list) => policy()
+            .projection(list)
+            .map(Some)
+            .map_err(warp::reject::custom),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::config::{FieldsConfig, RecordFields};
+
+    use super::{Kind, Policy};
+
+    fn policy() -> Policy {
+        Policy::new(&FieldsConfig {
+            spin: RecordFields {
+                allow: Some(vec!["artist".to_string(), "song".to_string()]),
+                ..RecordFields::default()
+            },
+            ..FieldsConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn redacting() -> Policy {
+        Policy::new(&FieldsConfig {
+            dj: RecordFields {
+                deny: vec!["email".to_string(), "note".to_string()],
+                rename: [("website".to_string(), "homepage".to_string())].into(),
+                ..RecordFields::default()
+            },
+            ..FieldsConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let mut spin = json!({
+            "id": 1,
+            "artist": "Nina Simone",
+            "song": "Sinnerman",
+            "note": "Requested by the station manager",
+            "start": "2024-03-01T12:03:00+0000",
+            "_links": {}
+        });
+        policy().apply(Kind::Spin, &mut spin);
+
+        assert_eq!(
+            spin,
+            json!({
+                "id": 1,
+                "artist": "Nina Simone",
+                "song": "Sinnerman",
+                "start": "2024-03-01T12:03:00+0000",
+                "_links": {}
+            })
+        );
+
+        // No allowlist for DJs, so nothing is dropped
+        let mut dj = json!({"id": 5, "name": "DJ Cool", "email": "cool@example.org"});
+        policy().apply(Kind::Dj, &mut dj);
+        assert_eq!(dj["email"], "cool@example.org");
+    }
+
+    #[test]
+    fn test_projection() {
+        let doc = json!({
+            "on_air": true,
+            "spin": {
+                "id": 2,
+                "artist": "Nina Simone",
+                "song": "Sinnerman",
+                "label": "Philips"
+            },
+            "show": {
+                "id": 10,
+                "title": "Morning Jazz",
+                "djs": [{"id": 5, "name": "DJ Cool"}]
+            }
+        });
+        let projection = Policy::new(&FieldsConfig::default())
+            .unwrap()
+            .projection("artist,song, title,djs,name")
+            .unwrap();
+
+        assert_eq!(
+            projection.apply(&doc),
+            json!({
+                "on_air": true,
+                "spin": {"artist": "Nina Simone", "song": "Sinnerman"},
+                "show": {"title": "Morning Jazz", "djs": [{"name": "DJ Cool"}]}
+            })
+        );
+    }
+
+    #[test]
+    fn test_projection_limited_to_allowlist() {
+        // Spins are limited, but shows and DJs may still have any field
+        assert!(policy().projection("artist,title").is_ok());
+
+        let policy = Policy::new(&FieldsConfig {
+            spin: RecordFields {
+                allow: Some(vec!["artist".to_string()]),
+                ..RecordFields::default()
+            },
+            show: RecordFields {
+                allow: Some(vec!["title".to_string()]),
+                ..RecordFields::default()
+            },
+            dj: RecordFields {
+                allow: Some(vec!["name".to_string()]),
+                ..RecordFields::default()
+            },
+        })
+        .unwrap();
+        let err = policy.projection("artist,email,note,id").unwrap_err();
+        assert_eq!(err.0, vec!["email".to_string(), "note".to_string()]);
+    }
+
+    #[test]
+    fn test_deny_and_rename() {
+        let mut dj = json!({
+            "id": 5,
+            "website": "https://example.org/cool",
+            "homepage": "Stale",
+            "email": "cool@example.org",
+            "note": "Call before noon",
+            "_links": {}
+        });
+        redacting().apply(Kind::Dj, &mut dj);
+
+        assert_eq!(
+            dj,
+            json!({"id": 5, "homepage": "https://example.org/cool", "_links": {}})
+        );
+
+        // Shows have no policy of their own
+        let mut show = json!({"id": 10, "name": "Morning Jazz", "note": "Guest host"});
+        redacting().apply(Kind::Show, &mut show);
+        assert_eq!(show["note"], "Guest host");
+    }
+
+    #[test]
+    fn test_projection_after_rename() {
+        let policy = Policy::new(&FieldsConfig {
+            dj: RecordFields {
+                allow: Some(vec!["website".to_string(), "email".to_string()]),
+                deny: vec!["email".to_string()],
+                rename: [("website".to_string(), "homepage".to_string())].into(),
+            },
+            spin: RecordFields {
+                allow: Some(vec!["artist".to_string()]),
+                ..RecordFields::default()
+            },
+            show: RecordFields {
+                allow: Some(vec!["title".to_string()]),
+                ..RecordFields::default()
+            },
+        })
+        .unwrap();
+
+        assert!(policy.projection("homepage").is_ok());
+        let err = policy.projection("website,email").unwrap_err();
+        assert_eq!(err.0, vec!["email".to_string(), "website".to_string()]);
+    }
+
+    #[test]
+    fn test_invalid_policy() {
+        let invalid = |dj: RecordFields| {
+            Policy::new(&FieldsConfig {
+                dj,
+                ..FieldsConfig::default()
+            })
+            .err()
+        };
+
+        assert_eq!(
+            invalid(RecordFields {
+                deny: vec!["id".to_string()],
+                ..RecordFields::default()
+            }),
+            Some("[fields.dj]: \"id\" can't be denied".to_string())
+        );
+        assert!(invalid(RecordFields {
+            rename: [("start".to_string(), "begins".to_string())].into(),
+            ..RecordFields::default()
+        })
+        .is_some());
+        assert!(invalid(RecordFields {
+            rename: [
+                ("bio".to_string(), "about".to_string()),
+                ("email".to_string(), "about".to_string())
+            ]
+            .into(),
+            ..RecordFields::default()
+        })
+        .is_some());
+    }
+}
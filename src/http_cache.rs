@@ -0,0 +1,193 @@
+use std::hash::{DefaultHasher, Hasher};
+
+use chrono::{DateTime, SubsecRound, Utc};
+use warp::{
+    http::{header, HeaderValue, StatusCode},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+/// ETag and Last-Modified for one version of a cached response body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    /// Keeps `previous` when the body hasn't changed, so re-fetching identical
+    /// data from Spinitron doesn't move Last-Modified.
+    pub fn new(body: &[u8], previous: Option<&Validators>, now: DateTime<Utc>) -> Validators {
+        // DefaultHasher::new() always uses the same keys, so every instance of
+        // the relay hands out the same ETag for the same body
+        let mut hasher = DefaultHasher::new();
+        hasher.write(body);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+
+        match previous {
+            Some(previous) if previous.etag == etag => previous.clone(),
+            // HTTP dates only have whole seconds
+            _ => Validators {
+                etag,
+                last_modified: now.trunc_subsecs(0),
+            },
+        }
+    }
+
+    /// Validators for another representation of the same data, e.g. a
+    /// projection of it. It changes whenever the data does.
+    pub fn for_variant(&self, body: &[u8]) -> Validators {
+        Validators::new(body, None, self.last_modified)
+    }
+
+    fn http_date(&self) -> String {
+        self.last_modified
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+}
+
+/// The conditional request headers a client sent.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+impl Conditions {
+    /// Whether the client's copy is still current. If-Modified-Since is only
+    /// looked at when there's no If-None-Match, as RFC 9110 requires.
+    pub fn not_modified(&self, validators: &Validators) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            // Weak comparison: a W/ prefix doesn't stop a match
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == validators.etag);
+        }
+        match self
+            .if_modified_since
+            .as_deref()
+            .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+        {
+            Some(since) => validators.last_modified <= since,
+            // Missing or unparseable dates are ignored
+            None => false,
+        }
+    }
+}
+
+pub fn conditions() -> impl Filter<Extract = (Conditions,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("if-none-match")
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .map(|if_none_match, if_modified_since| Conditions {
+            if_none_match,
+            if_modified_since,
+        })
+}
+
+/// How long, and by whom, a response may be cached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheControl {
+    pub max_age: u64,
+    // Set for routes behind client API keys, so a shared cache or CDN can't
+    // hand a keyed response to a client without a key
+    pub private: bool,
+}
+
+impl CacheControl {
+    pub fn header_value(self) -> HeaderValue {
+        let scope = if self.private { "private" } else { "public" };
+        HeaderValue::from_str(&format!("{}, max-age={}", scope, self.max_age)).unwrap()
+    }
+}
+
+/// Adds ETag, Last-Modified and Cache-Control to a response.
+pub fn add_headers(resp: &mut Response, validators: &Validators, cache_control: CacheControl) {
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&validators.etag).unwrap(),
+    );
+    headers.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&validators.http_date()).unwrap(),
+    );
+    headers.insert(header::CACHE_CONTROL, cache_control.header_value());
+}
+
+/// Answers with `body`, or a bodyless 304 if the client already has it, in
+/// which case `body` is never built.
+pub fn reply<R: Reply>(
+    body: impl FnOnce() -> R,
+    conditions: &Conditions,
+    validators: &Validators,
+    cache_control: CacheControl,
+) -> Response {
+    let mut resp = if conditions.not_modified(validators) {
+        warp::reply::with_status(warp::reply(), StatusCode::NOT_MODIFIED).into_response()
+    } else {
+        body().into_response()
+    };
+    add_headers(&mut resp, validators, cache_control);
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{Conditions, Validators};
+
+    fn validators() -> Validators {
+        Validators::new(
+            b"{\"spin-0\":{}}",
+            None,
+            Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_unchanged_body_keeps_last_modified() {
+        let first = validators();
+        let later = Utc.with_ymd_and_hms(2024, 3, 1, 12, 15, 0).unwrap();
+
+        assert_eq!(
+            Validators::new(b"{\"spin-0\":{}}", Some(&first), later),
+            first
+        );
+
+        let changed = Validators::new(b"{\"spin-0\":{\"id\":1}}", Some(&first), later);
+        assert_ne!(changed.etag, first.etag);
+        assert_eq!(changed.last_modified, later);
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let validators = validators();
+        let conditions = |tags: &str| Conditions {
+            if_none_match: Some(tags.to_string()),
+            // Ignored whenever If-None-Match is present
+            if_modified_since: Some("Fri, 01 Mar 2024 13:00:00 GMT".to_string()),
+        };
+
+        assert!(conditions(&validators.etag).not_modified(&validators));
+        assert!(conditions(&format!("\"other\", W/{}", validators.etag)).not_modified(&validators));
+        assert!(conditions("*").not_modified(&validators));
+        assert!(!conditions("\"other\"").not_modified(&validators));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let validators = validators();
+        let conditions = |date: &str| Conditions {
+            if_none_match: None,
+            if_modified_since: Some(date.to_string()),
+        };
+
+        assert!(conditions("Fri, 01 Mar 2024 12:00:00 GMT").not_modified(&validators));
+        assert!(!conditions("Fri, 01 Mar 2024 11:59:59 GMT").not_modified(&validators));
+        assert!(!conditions("yesterday").not_modified(&validators));
+        assert!(!Conditions::default().not_modified(&validators));
+    }
+}
@@ -0,0 +1,652 @@
+//! Spin art and DJ photos, scaled down and re-encoded for the pages showing
+//! them. Spinitron links to the originals, at whatever size they were
+//! uploaded.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::Cursor,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, SubsecRound, Utc};
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType as PngFilter, PngEncoder},
+    },
+    imageops::FilterType,
+    DynamicImage, ImageReader,
+};
+use md5::{Digest, Md5};
+use serde_json::Value;
+use warp::{reject::Reject, Filter, Rejection};
+
+use crate::config::ImagesConfig;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Originals bigger than this aren't resized
+const MAX_ORIGINAL: usize = 20 * 1024 * 1024;
+
+/// Whose image to serve.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Spin(u64),
+    Dj(u64),
+}
+
+impl Source {
+    /// The original's URL, from the /v2 spins or shows. None if the relay
+    /// doesn't hold the record, or it has no image.
+    pub fn url(self, spins: &Value, shows: &Value) -> Option<String> {
+        let record = match self {
+            Source::Spin(id) => find(spins["spins"].as_array()?.iter(), id),
+            Source::Dj(id) => find(
+                shows["shows"]
+                    .as_array()?
+                    .iter()
+                    .flat_map(|show| show["djs"].as_array().into_iter().flatten()),
+                id,
+            ),
+        }?;
+        record["image"]
+            .as_str()
+            .filter(|url| url.starts_with("https://") || url.starts_with("http://"))
+            .map(str::to_string)
+    }
+}
+
+fn find<'a>(mut records: impl Iterator<Item = &'a Value>, id: u64) -> Option<&'a Value> {
+    records.find(|record| record["id"].as_u64() == Some(id))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Jpeg,
+    Png,
+}
+
+impl Encoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Jpeg => "image/jpeg",
+            Encoding::Png => "image/png",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Jpeg => "jpg",
+            Encoding::Png => "png",
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Encoding> {
+        match extension {
+            "jpg" => Some(Encoding::Jpeg),
+            "png" => Some(Encoding::Png),
+            _ => None,
+        }
+    }
+}
+
+/// The size and format a client asked for, from the query string.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ImageQuery {
+    pub width: Option<u32>,
+    // Chosen from the image when unset: PNG if it has transparency, as
+    // JPEG can't keep it, and JPEG otherwise
+    pub format: Option<Encoding>,
+}
+
+/// Rejection for an image query parameter that isn't valid. Turned into a
+/// 400 by `filters::handle_rejection`.
+#[derive(Debug)]
+pub struct InvalidImageQuery(pub String);
+
+impl Reject for InvalidImageQuery {}
+
+impl ImageQuery {
+    /// Parses `w` and `format`, ignoring any others (like `api_key`).
+    pub fn from_query(query: &HashMap<String, String>) -> Result<ImageQuery, InvalidImageQuery> {
+        let mut image_query = ImageQuery::default();
+        for (name, value) in query {
+            match name.as_str() {
+                "w" => {
+                    image_query.width = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|width| *width > 0)
+                            .ok_or_else(|| invalid(name, value))?,
+                    )
+                }
+                "format" => {
+                    image_query.format = Some(match value.as_str() {
+                        "jpeg" | "jpg" => Encoding::Jpeg,
+                        "png" => Encoding::Png,
+                        _ => return Err(invalid(name, value)),
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(image_query)
+    }
+}
+
+fn invalid(name: &str, value: &str) -> InvalidImageQuery {
+    InvalidImageQuery(format!("Invalid value for {}: \"{}\"", name, value))
+}
+
+pub fn query() -> impl Filter<Extract = (ImageQuery,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>().and_then(|query| async move {
+        ImageQuery::from_query(&query).map_err(warp::reject::custom)
+    })
+}
+
+/// A resized image, ready to serve.
+#[derive(Debug)]
+pub struct Resized {
+    pub body: Bytes,
+    pub encoding: Encoding,
+    pub etag: String,
+    pub modified: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Fetch(reqwest::Error),
+    TooLarge,
+    Decode(image::ImageError),
+    // The decoder or encoder panicked
+    Panicked(tokio::task::JoinError),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Fetch(e) => write!(f, "couldn't fetch it: {}", e),
+            ImageError::TooLarge => write!(f, "it's over {} bytes", MAX_ORIGINAL),
+            ImageError::Decode(e) => write!(f, "couldn't decode it: {}", e),
+            ImageError::Panicked(e) => write!(f, "resizing it failed: {}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ImageError {
+    fn from(e: reqwest::Error) -> Self {
+        ImageError::Fetch(e)
+    }
+}
+
+impl From<image::ImageError> for ImageError {
+    fn from(e: image::ImageError) -> Self {
+        ImageError::Decode(e)
+    }
+}
+
+/// Fetches, resizes and caches images.
+pub struct Images {
+    config: ImagesConfig,
+    client: reqwest::Client,
+    cache: DiskCache,
+    // One lock per image being resized, so requests for the same one wait
+    // for the first instead of all fetching it
+    resizing: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl Images {
+    /// Reads what's already cached in the config's `cache_dir`.
+    pub fn new(config: ImagesConfig) -> Images {
+        let cache = DiskCache::open(config.cache_dir.clone(), config.cache_size_mb * 1024 * 1024);
+        Images {
+            config,
+            client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
+            cache,
+            resizing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The image at `url`, resized as `query` asks, from the cache if it's
+    /// been resized that way before.
+    pub async fn get(&self, url: &str, query: &ImageQuery) -> Result<Resized, ImageError> {
+        let width = query
+            .width
+            .unwrap_or(self.config.max_width)
+            .min(self.config.max_width);
+        let stem = stem(url, width, query.format, self.config.quality);
+        if let Some(cached) = self.cache.get(&stem).await {
+            return Ok(cached);
+        }
+
+        let lock = self
+            .resizing
+            .lock()
+            .unwrap()
+            .entry(stem.clone())
+            .or_default()
+            .clone();
+        let _resizing = lock.lock().await;
+        // It may have been cached while this request waited
+        if let Some(cached) = self.cache.get(&stem).await {
+            return Ok(cached);
+        }
+        let resized = match self.fetch(url).await {
+            Ok(original) => {
+                let (format, quality) = (query.format, self.config.quality);
+                tokio::task::spawn_blocking(move || resize(&original, width, format, quality))
+                    .await
+                    .unwrap_or_else(|e| Err(ImageError::Panicked(e)))
+            }
+            Err(e) => Err(e),
+        };
+        if let Ok((body, encoding)) = &resized {
+            self.cache.insert(&stem, *encoding, body).await;
+        }
+        self.resizing.lock().unwrap().remove(&stem);
+
+        let (body, encoding) = resized?;
+        Ok(Resized {
+            body,
+            encoding,
+            etag: format!("\"{}\"", stem),
+            modified: Utc::now().trunc_subsecs(0),
+        })
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, ImageError> {
+        let mut response = self.client.get(url).send().await?.error_for_status()?;
+        let mut original = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            original.extend_from_slice(&chunk);
+            if original.len() > MAX_ORIGINAL {
+                return Err(ImageError::TooLarge);
+            }
+        }
+        Ok(original)
+    }
+}
+
+// Names the cached file for an image resized one way. Includes the quality,
+// so changing it doesn't serve images encoded the old way.
+fn stem(url: &str, width: u32, format: Option<Encoding>, quality: u8) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(format!("{}\n{}\n{:?}\n{}", url, width, format, quality).as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Decodes `original`, scales it down to `width` if it's wider, and
+/// re-encodes it. Images are never scaled up.
+pub fn resize(
+    original: &[u8],
+    width: u32,
+    format: Option<Encoding>,
+    quality: u8,
+) -> Result<(Bytes, Encoding), ImageError> {
+    let image = ImageReader::new(Cursor::new(original))
+        .with_guessed_format()
+        .map_err(image::ImageError::IoError)?
+        .decode()?;
+    let image = if width < image.width() {
+        image.resize(width, u32::MAX, FilterType::CatmullRom)
+    } else {
+        image
+    };
+    let transparent = image.color().has_alpha();
+    let encoding = format.unwrap_or(if transparent {
+        Encoding::Png
+    } else {
+        Encoding::Jpeg
+    });
+
+    let mut encoded = Vec::new();
+    match encoding {
+        Encoding::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(
+            JpegEncoder::new_with_quality(&mut encoded, quality.clamp(1, 100)),
+        )?,
+        // Meant for logos and the like; photos are better off as JPEG
+        Encoding::Png => image.write_with_encoder(PngEncoder::new_with_quality(
+            &mut encoded,
+            CompressionType::Best,
+            PngFilter::Adaptive,
+        ))?,
+    }
+    Ok((Bytes::from(encoded), encoding))
+}
+
+/// Resized images on disk, named by stem and encoding ("<md5>.png"). Once
+/// they take up more than `capacity` bytes, the least recently served are
+/// removed. After a restart, the oldest count as least recently served.
+struct DiskCache {
+    dir: PathBuf,
+    capacity: u64,
+    index: Mutex<Index>,
+    // Numbers the files being written, so two never share a name
+    partials: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Index {
+    files: HashMap<String, CachedFile>,
+    // Stems by when they were last served, least recent first
+    by_use: BTreeMap<u64, String>,
+    uses: u64,
+    size: u64,
+}
+
+struct CachedFile {
+    encoding: Encoding,
+    size: u64,
+    last_use: u64,
+    modified: SystemTime,
+}
+
+impl Index {
+    fn add(&mut self, stem: &str, encoding: Encoding, size: u64, modified: SystemTime) {
+        self.remove(stem);
+        self.uses += 1;
+        self.by_use.insert(self.uses, stem.to_string());
+        self.files.insert(
+            stem.to_string(),
+            CachedFile {
+                encoding,
+                size,
+                last_use: self.uses,
+                modified,
+            },
+        );
+        self.size += size;
+    }
+
+    fn touch(&mut self, stem: &str) -> Option<(Encoding, SystemTime)> {
+        let file = self.files.get_mut(stem)?;
+        self.by_use.remove(&file.last_use);
+        self.uses += 1;
+        file.last_use = self.uses;
+        self.by_use.insert(self.uses, stem.to_string());
+        Some((file.encoding, file.modified))
+    }
+
+    fn remove(&mut self, stem: &str) -> Option<CachedFile> {
+        let file = self.files.remove(stem)?;
+        self.by_use.remove(&file.last_use);
+        self.size -= file.size;
+        Some(file)
+    }
+
+    // Forgets the least recently served until the rest fit in `capacity`,
+    // returning the names of their files
+    fn evict(&mut self, capacity: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.size > capacity {
+            let Some((_, stem)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some(file) = self.files.remove(&stem) {
+                self.size -= file.size;
+                evicted.push(file_name(&stem, file.encoding));
+            }
+        }
+        evicted
+    }
+}
+
+fn file_name(stem: &str, encoding: Encoding) -> String {
+    format!("{}.{}", stem, encoding.extension())
+}
+
+impl DiskCache {
+    fn open(dir: PathBuf, capacity: u64) -> DiskCache {
+        let mut found = Vec::new();
+        match std::fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                        continue;
+                    };
+                    // Left behind by a crash mid-write
+                    if name.contains(".partial-") {
+                        let _ = std::fs::remove_file(&path);
+                        continue;
+                    }
+                    let Some((stem, encoding)) =
+                        name.split_once('.').and_then(|(stem, extension)| {
+                            Some((stem, Encoding::from_extension(extension)?))
+                        })
+                    else {
+                        continue;
+                    };
+                    if let Ok(metadata) = entry.metadata() {
+                        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                        found.push((modified, stem.to_string(), encoding, metadata.len()));
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Couldn't read cached images in {:?}: {}", dir, e),
+        }
+        found.sort_by_key(|(modified, ..)| *modified);
+
+        let mut index = Index::default();
+        for (modified, stem, encoding, size) in found {
+            index.add(&stem, encoding, size, modified);
+        }
+        for name in index.evict(capacity) {
+            let _ = std::fs::remove_file(dir.join(name));
+        }
+        DiskCache {
+            dir,
+            capacity,
+            index: Mutex::new(index),
+            partials: AtomicUsize::new(0),
+        }
+    }
+
+    async fn get(&self, stem: &str) -> Option<Resized> {
+        let (encoding, modified) = self.index.lock().unwrap().touch(stem)?;
+        match tokio::fs::read(self.dir.join(file_name(stem, encoding))).await {
+            Ok(body) => Some(Resized {
+                body: Bytes::from(body),
+                encoding,
+                etag: format!("\"{}\"", stem),
+                modified: DateTime::<Utc>::from(modified).trunc_subsecs(0),
+            }),
+            Err(e) => {
+                warn!("Couldn't read cached image {}: {}", stem, e);
+                self.index.lock().unwrap().remove(stem);
+                None
+            }
+        }
+    }
+
+    async fn insert(&self, stem: &str, encoding: Encoding, body: &[u8]) {
+        let size = body.len() as u64;
+        if size > self.capacity {
+            return;
+        }
+        // Written whole, then renamed, so no one reads half an image
+        let name = file_name(stem, encoding);
+        let partial = self.dir.join(format!(
+            "{}.partial-{}",
+            name,
+            self.partials.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            tokio::fs::write(&partial, body).await?;
+            tokio::fs::rename(&partial, self.dir.join(&name)).await
+        };
+        if let Err(e) = written.await {
+            error!("Couldn't cache image in {:?}: {}", self.dir, e);
+            let _ = tokio::fs::remove_file(&partial).await;
+            return;
+        }
+
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            index.add(stem, encoding, size, SystemTime::now());
+            index.evict(self.capacity)
+        };
+        for name in evicted {
+            if let Err(e) = tokio::fs::remove_file(self.dir.join(&name)).await {
+                warn!("Couldn't remove cached image {}: {}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, env, io::Cursor, process};
+
+    use image::{DynamicImage, ImageFormat, RgbImage, RgbaImage};
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    use crate::config::ImagesConfig;
+    use crate::sinks::stand_in;
+
+    use super::{resize, DiskCache, Encoding, ImageQuery, Images, Source};
+
+    fn png(image: DynamicImage) -> Vec<u8> {
+        let mut encoded = Cursor::new(Vec::new());
+        image.write_to(&mut encoded, ImageFormat::Png).unwrap();
+        encoded.into_inner()
+    }
+
+    fn decode(encoded: &[u8]) -> DynamicImage {
+        image::load_from_memory(encoded).unwrap()
+    }
+
+    fn cache_dir(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("relay-images-{}-{}", name, process::id()))
+    }
+
+    #[test]
+    fn test_query() {
+        let query: HashMap<String, String> = [("w", "200"), ("format", "png"), ("api_key", "abc")]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(
+            ImageQuery::from_query(&query).unwrap(),
+            ImageQuery {
+                width: Some(200),
+                format: Some(Encoding::Png),
+            }
+        );
+
+        for (name, value) in [("w", "0"), ("w", "wide"), ("format", "gif")] {
+            let query = HashMap::from([(name.to_string(), value.to_string())]);
+            let err = ImageQuery::from_query(&query).unwrap_err();
+            assert!(err.0.contains(name), "{}", err.0);
+        }
+    }
+
+    #[test]
+    fn test_source() {
+        let spins = json!({"spins": [
+            {"id": 2, "image": "https://i.scdn.co/image/abc"},
+            {"id": 1, "image": null},
+        ]});
+        let shows = json!({"shows": [
+            {"id": 10, "djs": [{"id": 7, "image": "javascript:alert(1)"}]},
+            {"id": 11, "djs": [{"id": 8, "image": "https://spinitron.com/images/8.jpg"}]},
+        ]});
+
+        assert_eq!(
+            Source::Spin(2).url(&spins, &shows).as_deref(),
+            Some("https://i.scdn.co/image/abc")
+        );
+        assert_eq!(
+            Source::Dj(8).url(&spins, &shows).as_deref(),
+            Some("https://spinitron.com/images/8.jpg")
+        );
+        assert_eq!(Source::Spin(1).url(&spins, &shows), None);
+        assert_eq!(Source::Spin(3).url(&spins, &shows), None);
+        assert_eq!(Source::Dj(7).url(&spins, &shows), None);
+    }
+
+    #[test]
+    fn test_resize() {
+        let photo = png(DynamicImage::ImageRgb8(RgbImage::new(400, 200)));
+        let logo = png(DynamicImage::ImageRgba8(RgbaImage::new(40, 40)));
+
+        let (body, encoding) = resize(&photo, 100, None, 85).unwrap();
+        assert_eq!(encoding, Encoding::Jpeg);
+        let resized = decode(&body);
+        assert_eq!((resized.width(), resized.height()), (100, 50));
+
+        // Transparency needs PNG, and small images aren't scaled up
+        let (body, encoding) = resize(&logo, 100, None, 85).unwrap();
+        assert_eq!(encoding, Encoding::Png);
+        let resized = decode(&body);
+        assert_eq!((resized.width(), resized.height()), (40, 40));
+        assert!(resized.color().has_alpha());
+
+        let (_, encoding) = resize(&photo, 100, Some(Encoding::Png), 85).unwrap();
+        assert_eq!(encoding, Encoding::Png);
+        assert!(resize(b"not an image", 100, None, 85).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let photo = png(DynamicImage::ImageRgb8(RgbImage::new(400, 200)));
+        let (url, mut requests) = stand_in::serve_with(move |_, _| (StatusCode::OK, photo.clone()));
+        let url = format!("{}/art.png", url);
+        let dir = cache_dir("get");
+        let config = ImagesConfig {
+            cache_dir: dir.clone(),
+            max_width: 300,
+            ..ImagesConfig::default()
+        };
+        let query = ImageQuery {
+            width: Some(1000),
+            format: None,
+        };
+
+        let images = Images::new(config.clone());
+        let first = images.get(&url, &query).await.unwrap();
+        let second = images.get(&url, &query).await.unwrap();
+        // Still cached after a restart
+        let third = Images::new(config).get(&url, &query).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(decode(&first.body).width(), 300);
+        assert_eq!(first.encoding, Encoding::Jpeg);
+        assert_eq!(second.body, first.body);
+        assert_eq!(third.etag, first.etag);
+        requests.recv().await.unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        let dir = cache_dir("eviction");
+        let cache = DiskCache::open(dir.clone(), 25);
+        cache.insert("a", Encoding::Jpeg, &[0; 10]).await;
+        cache.insert("b", Encoding::Jpeg, &[0; 10]).await;
+        // Served, so "b" is now the least recently served
+        assert!(cache.get("a").await.is_some());
+        cache.insert("c", Encoding::Png, &[0; 10]).await;
+        // Bigger than the whole cache
+        cache.insert("d", Encoding::Jpeg, &[0; 30]).await;
+
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_some());
+        assert!(cache.get("d").await.is_none());
+        assert!(!dir.join("b.jpg").exists());
+        assert!(dir.join("c.png").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
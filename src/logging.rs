@@ -0,0 +1,217 @@
+use log::LevelFilter;
+use log4rs::{
+    append::{
+        console::ConsoleAppender,
+        file::FileAppender,
+        rolling_file::{
+            policy::compound::{
+                roll::fixed_window::FixedWindowRoller,
+                trigger::{
+                    size::SizeTrigger,
+                    time::{TimeTrigger, TimeTriggerConfig},
+                    Trigger,
+                },
+                CompoundPolicy,
+            },
+            RollingFileAppender,
+        },
+        Append,
+    },
+    config::{Appender, Config, Logger, Root},
+    encode::{json::JsonEncoder, pattern::PatternEncoder, Encode},
+};
+use warp::log::{Info, Log};
+
+use crate::{
+    client_ip,
+    config::{self, LogFormat, LogSink, LoggingConfig},
+};
+
+// Target used for per-request lines so they can be filtered separately
+pub const ACCESS_TARGET: &str = "access";
+
+// Fields attached to each access log record. With the JSON format these show up
+// under "mdc"; the text format prints them inline.
+const ACCESS_FIELDS: [&str; 7] = [
+    "method",
+    "route",
+    "status",
+    "latency_ms",
+    "client_ip",
+    "user_agent",
+    "referer",
+];
+
+const TEXT_PATTERN: &str = "{d(%Y-%m-%dT%H:%M:%S%.3f%:z)} {l} {t} - {m}{n}";
+
+pub fn init(config: &LoggingConfig) {
+    let log_config = build(config).unwrap_or_else(|e| panic!("Invalid logging config: {}", e));
+    log4rs::init_config(log_config).unwrap();
+}
+
+fn build(config: &LoggingConfig) -> Result<Config, String> {
+    let level: LevelFilter = config
+        .level
+        .parse()
+        .map_err(|_| format!("unknown level \"{}\"", config.level))?;
+
+    let mut builder = Config::builder();
+    let mut root = Root::builder();
+    for (i, sink) in config.sinks.iter().enumerate() {
+        let name = format!("sink-{}", i);
+        builder =
+            builder.appender(Appender::builder().build(&name, appender(sink, config.format)?));
+        root = root.appender(name);
+    }
+
+    // Access lines are written at info regardless of the root level
+    let access_level = if config.access_log {
+        LevelFilter::Info
+    } else {
+        LevelFilter::Off
+    };
+    builder = builder.logger(Logger::builder().build(ACCESS_TARGET, access_level));
+
+    builder.build(root.build(level)).map_err(|e| e.to_string())
+}
+
+fn encoder(format: LogFormat) -> Box<dyn Encode> {
+    match format {
+        LogFormat::Json => Box::new(JsonEncoder::new()),
+        LogFormat::Text => Box::new(PatternEncoder::new(TEXT_PATTERN)),
+    }
+}
+
+fn appender(sink: &LogSink, format: LogFormat) -> Result<Box<dyn Append>, String> {
+    match sink {
+        LogSink::Stdout => Ok(Box::new(
+            ConsoleAppender::builder().encoder(encoder(format)).build(),
+        )),
+        LogSink::File {
+            path,
+            max_size_mb,
+            max_age,
+            keep,
+        } => {
+            let trigger: Box<dyn Trigger> = match (max_size_mb, max_age) {
+                (Some(_), Some(_)) => {
+                    return Err(format!(
+                        "{}: set either max_size_mb or max_age, not both",
+                        path.display()
+                    ))
+                }
+                (Some(mb), None) => Box::new(SizeTrigger::new(mb * 1024 * 1024)),
+                (None, Some(age)) => {
+                    let config: TimeTriggerConfig =
+                        serde_json::from_value(serde_json::json!({ "interval": age }))
+                            .map_err(|e| format!("{}: invalid max_age: {}", path.display(), e))?;
+                    Box::new(TimeTrigger::new(config))
+                }
+                (None, None) => {
+                    let file = FileAppender::builder()
+                        .encoder(encoder(format))
+                        .build(path)
+                        .map_err(|e| format!("{}: {}", path.display(), e))?;
+                    return Ok(Box::new(file));
+                }
+            };
+
+            // Rolled files are named output.log.1, output.log.2, ...
+            let pattern = format!("{}.{{}}", path.display());
+            let roller = FixedWindowRoller::builder()
+                .build(&pattern, *keep)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let file = RollingFileAppender::builder()
+                .encoder(encoder(format))
+                .build(
+                    path,
+                    Box::new(CompoundPolicy::new(trigger, Box::new(roller))),
+                )
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            Ok(Box::new(file))
+        }
+    }
+}
+
+/// Wraps the API to log one record per request with its route, status,
+/// latency and client IP.
+pub fn access_log() -> Log<impl Fn(Info<'_>) + Copy> {
+    warp::log::custom(|info: Info<'_>| {
+        let forwarded_for = info
+            .request_headers()
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok());
+        let client_ip = client_ip::resolve(
+            info.remote_addr().map(|addr| addr.ip()),
+            forwarded_for,
+            &config::get().trusted_proxies,
+        )
+        .map(|ip| ip.to_string())
+        .unwrap_or_default();
+        let latency_ms = info.elapsed().as_secs_f64() * 1000.0;
+
+        log_mdc::insert("method", info.method().as_str());
+        log_mdc::insert("route", info.path());
+        log_mdc::insert("status", info.status().as_str());
+        log_mdc::insert("latency_ms", format!("{:.3}", latency_ms));
+        log_mdc::insert("client_ip", &client_ip);
+        log_mdc::insert("user_agent", info.user_agent().unwrap_or_default());
+        log_mdc::insert("referer", info.referer().unwrap_or_default());
+
+        info!(
+            target: ACCESS_TARGET,
+            "{} {} {} {:.3}ms {}",
+            info.method(),
+            info.path(),
+            info.status().as_u16(),
+            latency_ms,
+            client_ip
+        );
+
+        for field in ACCESS_FIELDS {
+            log_mdc::remove(field);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{LogFormat, LogSink, LoggingConfig};
+
+    use super::build;
+
+    fn file_sink(max_size_mb: Option<u64>, max_age: Option<&str>) -> LoggingConfig {
+        let path = std::env::temp_dir().join("api_relay_test.log");
+        LoggingConfig {
+            level: "info".to_string(),
+            format: LogFormat::Json,
+            access_log: true,
+            sinks: vec![
+                LogSink::Stdout,
+                LogSink::File {
+                    path,
+                    max_size_mb,
+                    max_age: max_age.map(String::from),
+                    keep: 3,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_rolling_policies() {
+        assert!(build(&file_sink(Some(10), None)).is_ok());
+        assert!(build(&file_sink(None, Some("1 day"))).is_ok());
+        assert!(build(&file_sink(None, None)).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        assert!(build(&file_sink(Some(10), Some("1 day"))).is_err());
+        assert!(build(&file_sink(None, Some("fortnightly"))).is_err());
+
+        let mut config = file_sink(None, None);
+        config.level = "loud".to_string();
+        assert!(build(&config).is_err());
+    }
+}
@@ -12,10 +12,26 @@ use handlers::Message;
 use tokio::sync::mpsc::UnboundedSender;
 use warp::Filter;
 
-use log::LevelFilter;
-use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Config, Root};
-use log4rs::encode::pattern::PatternEncoder;
+mod api_keys;
+mod client_ip;
+mod compression;
+mod config;
+mod cors;
+mod enrichment;
+mod events;
+mod feeds;
+mod fields;
+mod http_cache;
+mod images;
+mod logging;
+mod metrics;
+mod now;
+mod openapi;
+mod rate_limit;
+mod sinks;
+mod tls;
+mod v2;
+mod widget;
 
 // Define a global constant to store the Spinitron API Key
 static SPIN_API_KEY: OnceLock<String> = OnceLock::new();
@@ -30,17 +46,10 @@ fn get_api_key() -> &'static str {
 
 #[tokio::main]
 async fn main() {
-    let logfile = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{l} - {m}\n")))
-        .build("log/output.log")
-        .unwrap();
-
-    let config = Config::builder()
-        .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(Root::builder().appender("logfile").build(LevelFilter::Info))
-        .unwrap();
-
-    log4rs::init_config(config).unwrap();
+    logging::init(&config::get().logging);
+    // Fail at startup, not on the first request, if these are misconfigured
+    fields::policy();
+    widget::template();
 
     log::info!("Starting API-Relay...");
 
@@ -53,30 +62,33 @@ async fn main() {
     let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
         Arc::new(Mutex::new(HashMap::new()));
 
+    enrichment::start(&config::get().enrichment, spin_db.clone());
     _ = handlers::update_spins_no_reply(spin_db.clone()).await;
     _ = handlers::update_shows(show_db.clone()).await;
+    sinks::start(&config::get().sinks, &spin_db, &show_db);
 
-    let for_closure = connected_users.clone();
-    let connected_users_filter = warp::any().map(move || for_closure.clone());
-
-    let spin_recv = warp::path!("spins" / "stream")
-        .and(warp::get())
-        .and(connected_users_filter)
-        .map(|connected_users_filter| {
-            let stream = handlers::user_connected(connected_users_filter);
-            warp::sse::reply(warp::sse::keep_alive().stream(stream))
-        })
-        .with(warp::reply::with::headers(headers::cors()));
-
-    let api = spin_recv.or(filters::routes(spin_db, show_db, connected_users.clone()));
+    let api =
+        filters::routes(spin_db, show_db, connected_users.clone()).with(logging::access_log());
 
     // If env var LOCAL is set, run on localhost
-    if env::var("LOCAL").is_ok() {
+    let host = if env::var("LOCAL").is_ok() {
+        [127, 0, 0, 1]
+    } else {
+        [0, 0, 0, 0]
+    };
+
+    if let Some(tls_config) = &config::get().tls {
+        if let Some(port) = tls_config.redirect_port {
+            info!("Redirecting HTTP on port {} to HTTPS", port);
+            tokio::spawn(warp::serve(tls::redirect(tls_config.port)).run((host, port)));
+        }
+        tls::serve(api, tls_config, (host, tls_config.port).into()).await;
+    } else if env::var("LOCAL").is_ok() {
         info!("Running on localhost port 8080");
-        warp::serve(api).run(([127, 0, 0, 1], 8080)).await;
+        warp::serve(api).run((host, 8080)).await;
     } else {
         info!("Running exposed");
-        warp::serve(api).run(([0, 0, 0, 0], 80)).await;
+        warp::serve(api).run((host, 80)).await;
     }
 }
 
@@ -122,41 +134,327 @@ mod filters {
     use std::convert::Infallible;
     use std::sync::Arc;
 
-    use crate::headers;
+    use crate::api_keys::{self, KeyRegistry, KeyRejection};
+    use crate::compression;
+    use crate::config;
+    use crate::cors::{self, CorsPolicy};
+    use crate::feeds::Format;
+    use crate::fields::{self, UnknownFields};
+    use crate::http_cache::{self, CacheControl};
+    use crate::images::{self, Images, InvalidImageQuery, Source};
+    use crate::metrics;
+    use crate::openapi::{self, Auth, Body, Route};
+    use crate::rate_limit::{self, RateLimited, RateLimiter};
+    use crate::widget::{self, InvalidTheme};
 
     use super::handlers;
-    use super::models::Db;
-    use serde_json::Value;
-    use tokio::sync::Mutex;
+    use super::models::{Db, Version};
     use warp::Filter;
 
+    // Every route the relay serves. CORS preflights and the OpenAPI document
+    // are built from this, so new routes must be added here too.
+    pub const ROUTES: &[Route] = &[
+        Route {
+            method: "GET",
+            path: "/spins/get",
+            summary: "The ten most recent spins, keyed spin-0 to spin-9.",
+            body: Body::Json("SpinsV1"),
+            auth: Auth::ApiKey,
+            cached: true,
+        },
+        Route {
+            method: "GET",
+            path: "/spins/stream",
+            summary: "Server-sent events telling clients when new spins should be fetched.",
+            body: Body::EventStream,
+            auth: Auth::Public,
+            cached: false,
+        },
+        Route {
+            method: "POST",
+            path: "/spins/update",
+            summary: "Fetches new spins from Spinitron. Called by Spinitron's metadata push.",
+            body: Body::Text,
+            auth: Auth::Public,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/shows/get",
+            summary: "The current and next show, with their DJs.",
+            body: Body::Json("ShowsV1"),
+            auth: Auth::ApiKey,
+            cached: true,
+        },
+        Route {
+            method: "POST",
+            path: "/shows/update",
+            summary: "Fetches new show and DJ data from Spinitron.",
+            body: Body::Text,
+            auth: Auth::Public,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/v1/spins/get",
+            summary: "The ten most recent spins, keyed spin-0 to spin-9.",
+            body: Body::Json("SpinsV1"),
+            auth: Auth::ApiKey,
+            cached: true,
+        },
+        Route {
+            method: "GET",
+            path: "/v1/spins/stream",
+            summary: "Server-sent events telling clients when new spins should be fetched.",
+            body: Body::EventStream,
+            auth: Auth::Public,
+            cached: false,
+        },
+        Route {
+            method: "POST",
+            path: "/v1/spins/update",
+            summary: "Fetches new spins from Spinitron. Called by Spinitron's metadata push.",
+            body: Body::Text,
+            auth: Auth::Public,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/v1/shows/get",
+            summary: "The current and next show, with their DJs.",
+            body: Body::Json("ShowsV1"),
+            auth: Auth::ApiKey,
+            cached: true,
+        },
+        Route {
+            method: "POST",
+            path: "/v1/shows/update",
+            summary: "Fetches new show and DJ data from Spinitron.",
+            body: Body::Text,
+            auth: Auth::Public,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/v2/spins",
+            summary: "The ten most recent spins, newest first.",
+            body: Body::Json("SpinsV2"),
+            auth: Auth::ApiKey,
+            cached: true,
+        },
+        Route {
+            method: "GET",
+            path: "/v2/shows",
+            summary: "The current and next show, each with all of its DJs.",
+            body: Body::Json("ShowsV2"),
+            auth: Auth::ApiKey,
+            cached: true,
+        },
+        Route {
+            method: "GET",
+            path: "/now",
+            summary: "The spin playing now, the show on air with its DJs, and the next show.",
+            body: Body::Json("NowPlaying"),
+            auth: Auth::ApiKey,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/spins/feed.rss",
+            summary: "RSS feed of the ten most recent spins.",
+            body: Body::Xml("application/rss+xml"),
+            auth: Auth::ApiKey,
+            cached: true,
+        },
+        Route {
+            method: "GET",
+            path: "/spins/feed.atom",
+            summary: "Atom feed of the ten most recent spins.",
+            body: Body::Xml("application/atom+xml"),
+            auth: Auth::ApiKey,
+            cached: true,
+        },
+        Route {
+            method: "GET",
+            path: "/images/{spin_id}",
+            summary: "A spin's art, scaled down to width w, as JPEG or PNG (format).",
+            body: Body::Image,
+            auth: Auth::Public,
+            cached: true,
+        },
+        Route {
+            method: "GET",
+            path: "/images/djs/{dj_id}",
+            summary: "A DJ's photo, scaled down to width w, as JPEG or PNG (format).",
+            body: Body::Image,
+            auth: Auth::Public,
+            cached: true,
+        },
+        Route {
+            method: "GET",
+            path: "/widget/now-playing",
+            summary: "Embeddable now-playing page, themed with bg, fg, accent, compact and recent.",
+            body: Body::Html,
+            auth: Auth::Public,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/healthCheck",
+            summary: "Responds OK while the relay is running.",
+            body: Body::Text,
+            auth: Auth::Public,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/admin/usage",
+            summary: "Per-client request counts and quota usage.",
+            body: Body::Json("UsageReport"),
+            auth: Auth::Admin,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/admin/metrics",
+            summary: "Sink counters, like HTTP push errors, in the Prometheus text format. \
+                Takes the metrics token or the admin token.",
+            body: Body::Text,
+            auth: Auth::Admin,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/openapi.json",
+            summary: "This OpenAPI document.",
+            body: Body::Json("OpenApi"),
+            auth: Auth::Public,
+            cached: false,
+        },
+        Route {
+            method: "GET",
+            path: "/docs",
+            summary: "Human-readable API documentation.",
+            body: Body::Html,
+            auth: Auth::Public,
+            cached: false,
+        },
+    ];
+
     pub fn routes(
         spin_db: Db,
         show_db: Db,
         users: handlers::Users,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        spin_update(spin_db.clone(), users.clone())
-            .or(get_spin(spin_db.clone()))
-            .or(show_update(show_db.clone()))
-            .or(get_show(show_db.clone()))
+    ) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+        let limiter = RateLimiter::from_config(config::get());
+        let keys = KeyRegistry::from_config(config::get());
+        let cors = Arc::new(CorsPolicy::new(
+            &config::get().cors,
+            ROUTES.iter().map(|route| (route.path, route.method)),
+        ));
+
+        // The original routes, frozen. Also served under /v1.
+        let v1 = spin_stream(users.clone(), limiter.clone())
+            .or(spin_update(spin_db.clone(), users.clone(), limiter.clone()))
+            .or(get_spin(spin_db.clone(), keys.clone(), limiter.clone()))
+            .or(show_update(show_db.clone(), limiter.clone()))
+            .or(get_show(show_db.clone(), keys.clone(), limiter.clone()));
+        let v2 = get_spins_v2(spin_db.clone(), keys.clone(), limiter.clone())
+            .or(get_shows_v2(show_db.clone(), keys.clone(), limiter.clone()))
+            .or(get_now(spin_db.clone(), show_db.clone(), keys.clone(), limiter.clone()));
+        let feeds = get_feed(spin_db.clone(), keys.clone(), limiter.clone(), Format::Rss)
+            .or(get_feed(spin_db.clone(), keys.clone(), limiter.clone(), Format::Atom));
+        let images = Arc::new(Images::new(config::get().images.clone()));
+
+        let api = v1
+            .clone()
+            .or(warp::path("v1").and(v1))
+            .or(v2)
+            .or(feeds)
+            .or(get_image(spin_db, show_db, images, limiter.clone()))
+            .or(now_playing_widget(limiter.clone()))
             .or(health_check())
+            .or(admin_usage(keys.clone()))
+            .or(admin_metrics(keys, config::get().metrics.as_ref().map(|m| m.token.clone())))
+            .or(openapi_spec(limiter.clone()))
+            .or(docs(limiter))
             .or(not_found())
+            .recover(handle_rejection);
+        cors::wrap(cors, api)
     }
 
+    use warp::http::StatusCode;
     use warp::Reply;
 
+    // Turns our own rejections into responses; anything else is passed on to warp
+    pub async fn handle_rejection(
+        err: warp::Rejection,
+    ) -> Result<warp::reply::Response, warp::Rejection> {
+        let (status, retry_after) = if let Some(RateLimited { retry_after }) = err.find() {
+            (StatusCode::TOO_MANY_REQUESTS, Some(*retry_after))
+        } else if let Some(InvalidTheme(message)) = err.find() {
+            return Ok(
+                warp::reply::with_status(message.clone(), StatusCode::BAD_REQUEST).into_response(),
+            );
+        } else if let Some(InvalidImageQuery(message)) = err.find() {
+            return Ok(
+                warp::reply::with_status(message.clone(), StatusCode::BAD_REQUEST).into_response(),
+            );
+        } else if let Some(UnknownFields(names)) = err.find() {
+            let message = format!("Unknown fields: {}", names.join(", "));
+            return Ok(warp::reply::with_status(message, StatusCode::BAD_REQUEST).into_response());
+        } else if let Some(rejection) = err.find::<KeyRejection>() {
+            match rejection {
+                KeyRejection::Missing | KeyRejection::Invalid => (StatusCode::UNAUTHORIZED, None),
+                KeyRejection::RouteNotAllowed => (StatusCode::FORBIDDEN, None),
+                KeyRejection::QuotaExceeded { retry_after } => {
+                    (StatusCode::TOO_MANY_REQUESTS, Some(*retry_after))
+                }
+            }
+        } else {
+            return Err(err);
+        };
+
+        let mut resp =
+            warp::reply::with_status(status.canonical_reason().unwrap_or_default(), status)
+                .into_response();
+        if let Some(retry_after) = retry_after {
+            resp.headers_mut().insert(
+                "Retry-After",
+                retry_after.as_secs_f64().ceil().to_string().parse().unwrap(),
+            );
+        }
+        Ok(resp)
+    }
+
+    // Pushes a message to the client whenever spins are updated
+    pub fn spin_stream(
+        users: handlers::Users,
+        limiter: RateLimiter,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("spins" / "stream")
+            .and(warp::get())
+            .and(rate_limit::limit(limiter, "spins/stream"))
+            .map(move || {
+                let stream = handlers::user_connected(users.clone());
+                warp::sse::reply(warp::sse::keep_alive().stream(stream))
+            })
+    }
+
     // Update methods
     pub fn spin_update(
         spin_db: Db,
         users: handlers::Users,
+        limiter: RateLimiter,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("spins" / "update")
             .and(warp::post())
+            .and(rate_limit::limit(limiter, "spins/update"))
             .and(is_form_content())
             // .and(with_db(spin_db))
             .and(with_db_and_users(spin_db, users))
             .and_then(
-                |(db, users): (Arc<Mutex<Value>>, handlers::Users)| async move {
+                |(db, users): (Db, handlers::Users)| async move {
                     let resp = handlers::update_spins_no_reply(db.clone()).await;
                     match resp {
                         Ok(_) => {
@@ -184,9 +482,11 @@ mod filters {
 
     pub fn show_update(
         show_db: Db,
+        limiter: RateLimiter,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("shows" / "update")
             .and(warp::post())
+            .and(rate_limit::limit(limiter, "shows/update"))
             .and(is_form_content())
             .and(with_db(show_db))
             .and_then(handlers::update_shows)
@@ -195,22 +495,197 @@ mod filters {
     // Get methods
     pub fn get_spin(
         spin_db: Db,
+        keys: KeyRegistry,
+        limiter: RateLimiter,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let cache_control = CacheControl {
+            max_age: config::get().cache.max_age("spins/get"),
+            private: keys.enabled(),
+        };
         warp::path!("spins" / "get")
             .and(warp::get())
+            .and(api_keys::authorize(keys, "spins/get"))
+            .and(rate_limit::limit(limiter, "spins/get"))
             .and(with_db(spin_db))
-            .and_then(handlers::get)
-            .with(warp::reply::with::headers(headers::cors()))
+            .and(http_cache::conditions())
+            .and(compression::accepted())
+            .and(fields::projection())
+            .and_then(move |db, conditions, accepted, projection| {
+                handlers::get(db, Version::V1, conditions, accepted, projection, cache_control)
+            })
     }
 
     pub fn get_show(
         show_db: Db,
+        keys: KeyRegistry,
+        limiter: RateLimiter,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let cache_control = CacheControl {
+            max_age: config::get().cache.max_age("shows/get"),
+            private: keys.enabled(),
+        };
         warp::path!("shows" / "get")
             .and(warp::get())
+            .and(api_keys::authorize(keys, "shows/get"))
+            .and(rate_limit::limit(limiter, "shows/get"))
+            .and(with_db(show_db))
+            .and(http_cache::conditions())
+            .and(compression::accepted())
+            .and(fields::projection())
+            .and_then(move |db, conditions, accepted, projection| {
+                handlers::get(db, Version::V1, conditions, accepted, projection, cache_control)
+            })
+    }
+
+    pub fn get_spins_v2(
+        spin_db: Db,
+        keys: KeyRegistry,
+        limiter: RateLimiter,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let cache_control = CacheControl {
+            max_age: config::get().cache.max_age("v2/spins"),
+            private: keys.enabled(),
+        };
+        warp::path!("v2" / "spins")
+            .and(warp::get())
+            .and(api_keys::authorize(keys, "v2/spins"))
+            .and(rate_limit::limit(limiter, "v2/spins"))
+            .and(with_db(spin_db))
+            .and(http_cache::conditions())
+            .and(compression::accepted())
+            .and(fields::projection())
+            .and_then(move |db, conditions, accepted, projection| {
+                handlers::get(db, Version::V2, conditions, accepted, projection, cache_control)
+            })
+    }
+
+    pub fn get_shows_v2(
+        show_db: Db,
+        keys: KeyRegistry,
+        limiter: RateLimiter,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let cache_control = CacheControl {
+            max_age: config::get().cache.max_age("v2/shows"),
+            private: keys.enabled(),
+        };
+        warp::path!("v2" / "shows")
+            .and(warp::get())
+            .and(api_keys::authorize(keys, "v2/shows"))
+            .and(rate_limit::limit(limiter, "v2/shows"))
+            .and(with_db(show_db))
+            .and(http_cache::conditions())
+            .and(compression::accepted())
+            .and(fields::projection())
+            .and_then(move |db, conditions, accepted, projection| {
+                handlers::get(db, Version::V2, conditions, accepted, projection, cache_control)
+            })
+    }
+
+    pub fn get_now(
+        spin_db: Db,
+        show_db: Db,
+        keys: KeyRegistry,
+        limiter: RateLimiter,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let cache_control = CacheControl {
+            max_age: config::get().cache.max_age("now"),
+            private: keys.enabled(),
+        };
+        warp::path!("now")
+            .and(warp::get())
+            .and(api_keys::authorize(keys, "now"))
+            .and(rate_limit::limit(limiter, "now"))
+            .and(with_db(spin_db))
+            .and(with_db(show_db))
+            .and(fields::projection())
+            .and_then(move |spin_db, show_db, projection| {
+                handlers::now(spin_db, show_db, projection, cache_control)
+            })
+    }
+
+    pub fn get_feed(
+        spin_db: Db,
+        keys: KeyRegistry,
+        limiter: RateLimiter,
+        format: Format,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let route = match format {
+            Format::Rss => "spins/feed.rss",
+            Format::Atom => "spins/feed.atom",
+        };
+        let cache_control = CacheControl {
+            max_age: config::get().cache.max_age(route),
+            private: keys.enabled(),
+        };
+        let path = match format {
+            Format::Rss => warp::path!("spins" / "feed.rss").boxed(),
+            Format::Atom => warp::path!("spins" / "feed.atom").boxed(),
+        };
+        path.and(warp::get())
+            .and(api_keys::authorize(keys, route))
+            .and(rate_limit::limit(limiter, route))
+            .and(with_db(spin_db))
+            .and(http_cache::conditions())
+            .and(compression::accepted())
+            .and_then(move |db, conditions, accepted| {
+                handlers::feed(db, format, conditions, accepted, cache_control)
+            })
+    }
+
+    // Public, so pages can use it in <img> tags, but rate limited
+    pub fn get_image(
+        spin_db: Db,
+        show_db: Db,
+        images: Arc<Images>,
+        limiter: RateLimiter,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let cache_control = CacheControl {
+            max_age: config::get().images.max_age_secs,
+            private: false,
+        };
+        let source = warp::path!("images" / u64)
+            .map(Source::Spin)
+            .or(warp::path!("images" / "djs" / u64).map(Source::Dj))
+            .unify();
+        source
+            .and(warp::get())
+            .and(rate_limit::limit(limiter, "images"))
+            .and(images::query())
+            .and(with_db(spin_db))
             .and(with_db(show_db))
-            .and_then(handlers::get)
-            .with(warp::reply::with::headers(headers::cors()))
+            .and(http_cache::conditions())
+            .and_then(move |source, query, spin_db, show_db, conditions| {
+                handlers::image(
+                    images.clone(),
+                    source,
+                    query,
+                    (spin_db, show_db),
+                    conditions,
+                    cache_control,
+                )
+            })
+    }
+
+    // A page partner sites can put in an iframe; it fetches /now itself
+    pub fn now_playing_widget(
+        limiter: RateLimiter,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let cache_control = format!(
+            "public, max-age={}",
+            config::get().cache.max_age("widget/now-playing")
+        );
+        warp::path!("widget" / "now-playing")
+            .and(warp::get())
+            .and(rate_limit::limit(limiter, "widget/now-playing"))
+            .and(widget::theme())
+            .map(move |theme| {
+                let page = widget::render(widget::template(), &theme);
+                warp::reply::with_header(
+                    warp::reply::html(page),
+                    "Cache-Control",
+                    cache_control.clone(),
+                )
+            })
     }
 
     pub fn health_check() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
@@ -220,6 +695,78 @@ mod filters {
             .map(|| warp::reply::with_status("OK", warp::http::StatusCode::OK))
     }
 
+    // Per-client usage counters, behind the admin token
+    pub fn admin_usage(
+        keys: KeyRegistry,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "usage")
+            .and(warp::get())
+            .and(admin(keys.clone(), None))
+            .map(move || warp::reply::json(&keys.usage_report()))
+    }
+
+    // Sink counters for Prometheus, behind the metrics token or the admin
+    // token, so stations without client keys can still scrape them
+    pub fn admin_metrics(
+        keys: KeyRegistry,
+        token: Option<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "metrics")
+            .and(warp::get())
+            .and(admin(keys, token))
+            .map(|| {
+                warp::reply::with_header(
+                    metrics::render(),
+                    "Content-Type",
+                    "text/plain; version=0.0.4",
+                )
+            })
+    }
+
+    // Not found unless an admin token or the route's own `token` is
+    // configured, and unauthorized without one of them
+    fn admin(
+        keys: KeyRegistry,
+        token: Option<String>,
+    ) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        warp::header::optional::<String>("authorization")
+            .and_then(move |authorization: Option<String>| {
+                let (keys, token) = (keys.clone(), token.clone());
+                async move {
+                    if !keys.admin_enabled() && token.is_none() {
+                        return Err(warp::reject::not_found());
+                    }
+                    let authorization = authorization.as_deref();
+                    let own_token = token
+                        .is_some_and(|token| api_keys::bearer_matches(authorization, &token));
+                    if !own_token && !keys.is_admin(authorization) {
+                        return Err(warp::reject::custom(KeyRejection::Invalid));
+                    }
+                    Ok(())
+                }
+            })
+            .untuple_one()
+    }
+
+    pub fn openapi_spec(
+        limiter: RateLimiter,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let spec = Arc::new(openapi::document(ROUTES));
+        warp::path!("openapi.json")
+            .and(warp::get())
+            .and(rate_limit::limit(limiter, "openapi.json"))
+            .map(move || warp::reply::json(&*spec))
+    }
+
+    pub fn docs(
+        limiter: RateLimiter,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("docs")
+            .and(warp::get())
+            .and(rate_limit::limit(limiter, "docs"))
+            .map(|| warp::reply::html(openapi::DOCS_PAGE))
+    }
+
     pub fn not_found() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::any()
             .and(warp::path::end())
@@ -252,7 +799,7 @@ mod handlers {
     };
 
     use futures_util::Stream;
-    use log::{debug, info};
+    use log::{debug, info, warn};
     use serde_json::{Map, Value};
     use tokio::sync::mpsc;
     use tokio_stream::wrappers::UnboundedReceiverStream;
@@ -260,9 +807,21 @@ mod handlers {
 
     use futures_util::stream::StreamExt;
 
+    use warp::Reply;
+
+    use crate::compression::{self, Accepted};
+    use crate::config;
+    use crate::enrichment;
+    use crate::events;
+    use crate::feeds::Format;
+    use crate::fields::{self, Kind, Policy, Projection};
     use crate::get_api_key;
+    use crate::http_cache::{self, CacheControl, Conditions, Validators};
+    use crate::images::{ImageQuery, Images, Source};
+    use crate::now;
+    use crate::v2;
 
-    use super::models::Db;
+    use super::models::{Db, Version};
 
     async fn remove_links_spins(v: Value) -> Value {
         let mut new_v = Value::Object(serde_json::Map::new());
@@ -298,6 +857,46 @@ mod handlers {
         new_v
     }
 
+    // A persona fetched from Spinitron, without the fields that aren't relayed
+    fn parse_dj(policy: &Policy, data: &str) -> Value {
+        let mut dj = serde_json::from_str(data).unwrap();
+        policy.apply(Kind::Dj, &mut dj);
+        dj
+    }
+
+    /// Builds the v1 and v2 spins documents from Spinitron's spins response,
+    /// redacted by `policy`. The v2 spins get whatever enrichment has found.
+    pub async fn ingest_spins(policy: &Policy, mut v: Value) -> (Value, Value) {
+        policy.apply_items(Kind::Spin, &mut v);
+        let v2 = enrichment::apply(&v2::spins(&v));
+        (remove_links_spins(v).await, v2)
+    }
+
+    /// Builds the v1 and v2 shows documents from Spinitron's shows response
+    /// and the personas fetched for each show, redacted by `policy`. `v` must
+    /// already have been redacted with `apply_items`, as its links are needed
+    /// to fetch the personas.
+    pub async fn ingest_shows(
+        policy: &Policy,
+        v: &Value,
+        dj_data: &[Vec<String>],
+    ) -> (Value, Value) {
+        let mut new_v = remove_links_shows(v.clone()).await;
+        // Every DJ of each show, for /v2
+        let mut djs = vec![Vec::new(); dj_data.len()];
+        for (show, data) in dj_data.iter().enumerate() {
+            for (i, data) in data.iter().enumerate() {
+                let dj_data: Value = remove_links_djs(parse_dj(policy, data)).await;
+                if i == 0 {
+                    new_v[format!("dj-{}", show)] = dj_data.clone();
+                }
+                djs[show].push(dj_data.clone());
+                new_v["v2"][format!("dj-{}", show)][i.to_string()] = dj_data;
+            }
+        }
+        (new_v, v2::shows(v, &djs))
+    }
+
     async fn remove_links_djs(v: Value) -> Value {
         let mut new_v = Value::Object(serde_json::Map::new());
         let arr = v.as_object().unwrap();
@@ -345,8 +944,12 @@ mod handlers {
         let v: Value = serde_json::from_str(&str).unwrap();
 
         // Store in db
-        let mut db = db.lock().await;
-        *db = remove_links_spins(v).await;
+        let (new_v, v2) = ingest_spins(fields::policy(), v).await;
+        let change = events::spin_change(&db.load().v2.value, &v2);
+        db.update(new_v, v2);
+        if let Some(event) = change {
+            events::publish(event);
+        }
         Ok(warp::reply::with_status(
             "Finished updating spins.",
             warp::http::StatusCode::OK,
@@ -387,28 +990,18 @@ mod handlers {
             ));
         }
 
-        let v: Value = serde_json::from_str(&str).unwrap();
-        let mut new_v = remove_links_shows(v.clone()).await;
-
-        // Get the amount of DJs in the current/first up show
-        let dj1_count = v["items"][0]["_links"]["personas"]
-            .as_array()
-            .unwrap()
-            .len();
-        let dj2_count = v["items"][1]["_links"]["personas"]
-            .as_array()
-            .unwrap()
-            .len();
-
-        // Loop over DJs by dj1_count length
-        for i in 0..dj1_count {
-            let dj_link = v["items"][0]["_links"]["personas"][i]["href"]
-                .as_str()
-                .unwrap();
-            if i == 0 {
-                let dj_data = reqwest::get(dj_link).await.unwrap().text().await;
-                let dj_data = match dj_data {
-                    Ok(v) => v,
+        let mut v: Value = serde_json::from_str(&str).unwrap();
+        fields::policy().apply_items(Kind::Show, &mut v);
+        // Personas of the current and next show, as fetched
+        let mut dj_data = vec![Vec::new(), Vec::new()];
+
+        for (show, dj_data) in dj_data.iter_mut().enumerate() {
+            let personas = v["items"][show]["_links"]["personas"].as_array().unwrap();
+            for persona in personas {
+                let dj_link = persona["href"].as_str().unwrap();
+                let data = reqwest::get(dj_link).await.unwrap().text().await;
+                match data {
+                    Ok(data) => dj_data.push(data),
                     Err(e) => {
                         error!("Couldn't get dj_data: {}", e);
                         return Ok(warp::reply::with_status(
@@ -416,70 +1009,17 @@ mod handlers {
                             warp::http::StatusCode::INTERNAL_SERVER_ERROR,
                         ));
                     }
-                };
-                let dj_data: Value =
-                    remove_links_djs(serde_json::from_str(&dj_data).unwrap()).await;
-
-                new_v["dj-0"] = dj_data;
-            }
-            let dj_data = reqwest::get(dj_link).await.unwrap().text().await;
-            let dj_data = match dj_data {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Couldn't get dj_data: {}", e);
-                    return Ok(warp::reply::with_status(
-                        "Couldn't fetch DJ data.",
-                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                    ));
                 }
-            };
-            let dj_data: Value = remove_links_djs(serde_json::from_str(&dj_data).unwrap()).await;
-
-            new_v["v2"]["dj-0"][i.to_string()] = dj_data;
-        }
-
-        // Loop over DJs by dj2_count length
-        for i in 0..dj2_count {
-            let dj_link = v["items"][1]["_links"]["personas"][i]["href"]
-                .as_str()
-                .unwrap();
-            if i == 0 {
-                let dj_data = reqwest::get(dj_link).await.unwrap().text().await;
-                let dj_data = match dj_data {
-                    Ok(v) => v,
-                    Err(e) => {
-                        error!("Couldn't get dj_data: {}", e);
-                        return Ok(warp::reply::with_status(
-                            "Couldn't fetch DJ data.",
-                            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        ));
-                    }
-                };
-                let dj_data: Value =
-                    remove_links_djs(serde_json::from_str(&dj_data).unwrap()).await;
-
-                new_v["dj-1"] = dj_data;
             }
-            let dj_data = reqwest::get(dj_link).await.unwrap().text().await;
-            let dj_data = match dj_data {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Couldn't get dj_data: {}", e);
-                    return Ok(warp::reply::with_status(
-                        "Couldn't fetch DJ data.",
-                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                    ));
-                }
-            };
-            let dj_data: Value = remove_links_djs(serde_json::from_str(&dj_data).unwrap()).await;
-
-            new_v["v2"]["dj-1"][i.to_string()] = dj_data;
         }
 
         // Store in db
-        let mut db = db.lock().await;
-
-        *db = new_v;
+        let (new_v, v2) = ingest_shows(fields::policy(), &v, &dj_data).await;
+        let change = events::show_change(&db.load().v2.value, &v2);
+        db.update(new_v, v2);
+        if let Some(event) = change {
+            events::publish(event);
+        }
 
         Ok(warp::reply::with_status(
             "Finished updating shows and DJs.",
@@ -487,24 +1027,163 @@ mod handlers {
         ))
     }
 
-    pub async fn get(db: Db) -> Result<impl warp::Reply, warp::Rejection> {
-        let db = db.lock().await;
-        let resp = db.clone();
-        if resp == Value::Null {
+    pub async fn get(
+        db: Db,
+        version: Version,
+        conditions: Conditions,
+        accepted: Accepted,
+        projection: Option<Projection>,
+        cache_control: CacheControl,
+    ) -> Result<warp::reply::Response, warp::Rejection> {
+        let snapshot = db.load();
+        let document = snapshot.document(version);
+        if document.value == Value::Null {
+            if version == Version::V2 {
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({
+                        "error": "Data hasn't been fetched from Spinitron yet."
+                    })),
+                    warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                )
+                .into_response());
+            }
             // Create json object with 500 error and return
             let mut resp = Map::new();
             resp.insert("error".to_string(), Value::String("500".to_string()));
             return Ok(warp::reply::with_status(
                 warp::reply::json(&resp),
                 warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response());
+        }
+        if let Some(projection) = projection {
+            // Built per request, so it isn't worth compressing
+            let body = serde_json::to_vec(&projection.apply(&document.value)).unwrap();
+            let validators = document.validators.for_variant(&body);
+            return Ok(http_cache::reply(
+                || {
+                    warp::reply::with_header(body, "Content-Type", "application/json")
+                        .into_response()
+                },
+                &conditions,
+                &validators,
+                cache_control,
             ));
         }
-        Ok(warp::reply::with_status(
-            warp::reply::json(&resp),
-            warp::http::StatusCode::OK,
+        let mut resp = http_cache::reply(
+            || document.body.response(&accepted, "application/json"),
+            &conditions,
+            &document.validators,
+            cache_control,
+        );
+        compression::vary(&mut resp, &document.body, &accepted);
+        Ok(resp)
+    }
+
+    pub async fn feed(
+        db: Db,
+        format: Format,
+        conditions: Conditions,
+        accepted: Accepted,
+        cache_control: CacheControl,
+    ) -> Result<warp::reply::Response, warp::Rejection> {
+        let snapshot = db.load();
+        let Some(feed) = snapshot.feed(format) else {
+            return Ok(warp::reply::with_status(
+                "Data hasn't been fetched from Spinitron yet.",
+                warp::http::StatusCode::SERVICE_UNAVAILABLE,
+            )
+            .into_response());
+        };
+        let mut resp = http_cache::reply(
+            || feed.body.response(&accepted, format.content_type()),
+            &conditions,
+            &feed.validators,
+            cache_control,
+        );
+        compression::vary(&mut resp, &feed.body, &accepted);
+        Ok(resp)
+    }
+
+    pub async fn image(
+        images: Arc<Images>,
+        source: Source,
+        query: ImageQuery,
+        (spin_db, show_db): (Db, Db),
+        conditions: Conditions,
+        cache_control: CacheControl,
+    ) -> Result<warp::reply::Response, warp::Rejection> {
+        let url = source.url(&spin_db.load().v2.value, &show_db.load().v2.value);
+        let Some(url) = url else {
+            return Ok(warp::reply::with_status(
+                "No image for that spin or DJ.",
+                warp::http::StatusCode::NOT_FOUND,
+            )
+            .into_response());
+        };
+        let resized = match images.get(&url, &query).await {
+            Ok(resized) => resized,
+            Err(e) => {
+                warn!("Couldn't resize {}: {}", url, e);
+                return Ok(warp::reply::with_status(
+                    "Couldn't fetch the original image.",
+                    warp::http::StatusCode::BAD_GATEWAY,
+                )
+                .into_response());
+            }
+        };
+        let validators = Validators {
+            etag: resized.etag,
+            last_modified: resized.modified,
+        };
+        Ok(http_cache::reply(
+            || {
+                warp::reply::with_header(
+                    warp::reply::Response::new(resized.body.into()),
+                    "Content-Type",
+                    resized.encoding.content_type(),
+                )
+            },
+            &conditions,
+            &validators,
+            cache_control,
         ))
     }
 
+    pub async fn now(
+        spin_db: Db,
+        show_db: Db,
+        projection: Option<Projection>,
+        cache_control: CacheControl,
+    ) -> Result<warp::reply::Response, warp::Rejection> {
+        let (spins, shows) = (spin_db.load(), show_db.load());
+        if spins.v2.value.is_null() && shows.v2.value.is_null() {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "error": "Data hasn't been fetched from Spinitron yet."
+                })),
+                warp::http::StatusCode::SERVICE_UNAVAILABLE,
+            )
+            .into_response());
+        }
+
+        let mut doc = now::now_playing(
+            &config::get().now,
+            &spins.v2.value,
+            &shows.v2.value,
+            chrono::Utc::now(),
+        );
+        if let Some(projection) = projection {
+            doc = projection.apply(&doc);
+        }
+        Ok(warp::reply::with_header(
+            warp::reply::json(&doc),
+            "Cache-Control",
+            cache_control.header_value(),
+        )
+        .into_response())
+    }
+
     pub(crate) type Users = Arc<Mutex<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
     static NEXT_USER_ID: std::sync::atomic::AtomicUsize = AtomicUsize::new(1);
 
@@ -548,34 +1227,148 @@ mod handlers {
     }
 }
 
-mod headers {
-    use warp::http::header::{HeaderMap, HeaderValue};
-
-    // create headers to be used in responses
-    pub fn cors() -> HeaderMap {
-        let mut headers = HeaderMap::new();
-        headers.insert("Access-Control-Allow-Origin", HeaderValue::from_static("*"));
-        headers.insert(
-            "Access-Control-Allow-Methods",
-            HeaderValue::from_static("GET, POST, PUT, DELETE, OPTIONS"),
-        );
-        headers.insert(
-            "Access-Control-Allow-Headers",
-            HeaderValue::from_static("Content-Type, Authorization"),
-        );
-        headers
-    }
-}
-
 mod models {
+    use arc_swap::ArcSwap;
+    use bytes::Bytes;
     use serde_json::{json, Value};
     use std::sync::Arc;
-    use tokio::sync::Mutex;
 
-    pub type Db = Arc<Mutex<Value>>;
+    use crate::compression::Encoded;
+    use crate::config;
+    use crate::feeds::Format;
+    use crate::http_cache::Validators;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Version {
+        // The original "spin-0" style documents, frozen for existing clients
+        V1,
+        // Arrays and RFC 3339 timestamps, see v2.rs
+        V2,
+    }
+
+    // One API version's view of the data
+    pub struct Document {
+        pub value: Value,
+        // `value` serialized and compressed once, up front, instead of on every request
+        pub body: Encoded,
+        pub validators: Validators,
+    }
+
+    impl Document {
+        fn new(value: Value, previous: Option<&Document>) -> Document {
+            let body = Bytes::from(serde_json::to_vec(&value).unwrap());
+            let validators = Validators::new(
+                &body,
+                previous.map(|previous| &previous.validators),
+                chrono::Utc::now(),
+            );
+            Document {
+                value,
+                body: Encoded::new(body),
+                validators,
+            }
+        }
+    }
+
+    // A feed of the spins, rendered and compressed along with the /v2 document
+    pub struct Feed {
+        pub body: Encoded,
+        pub validators: Validators,
+    }
+
+    impl Feed {
+        fn new(format: Format, v2: &Document) -> Feed {
+            let updated = v2.validators.last_modified.fixed_offset();
+            let body = Bytes::from(format.render(&v2.value, &config::get().feeds, updated));
+            Feed {
+                validators: v2.validators.for_variant(&body),
+                body: Encoded::new(body),
+            }
+        }
+    }
+
+    // One version of the data fetched from Spinitron. Never modified once built,
+    // so readers can hold on to it while a newer one is swapped in.
+    pub struct Snapshot {
+        pub v1: Document,
+        pub v2: Document,
+        // Only the spins cache has feeds
+        rss: Option<Feed>,
+        atom: Option<Feed>,
+    }
+
+    impl Snapshot {
+        fn new(v1: Document, v2: Document) -> Snapshot {
+            let has_spins = v2.value.get("spins").is_some();
+            let feed = |format| has_spins.then(|| Feed::new(format, &v2));
+            Snapshot {
+                rss: feed(Format::Rss),
+                atom: feed(Format::Atom),
+                v1,
+                v2,
+            }
+        }
+
+        pub fn document(&self, version: Version) -> &Document {
+            match version {
+                Version::V1 => &self.v1,
+                Version::V2 => &self.v2,
+            }
+        }
+
+        pub fn feed(&self, format: Format) -> Option<&Feed> {
+            match format {
+                Format::Rss => self.rss.as_ref(),
+                Format::Atom => self.atom.as_ref(),
+            }
+        }
+    }
+
+    // Readers load the current snapshot without locking; updates swap in a new one
+    pub struct Cache {
+        current: ArcSwap<Snapshot>,
+    }
+
+    impl Cache {
+        pub fn load(&self) -> Arc<Snapshot> {
+            self.current.load_full()
+        }
+
+        pub fn update(&self, v1: Value, v2: Value) {
+            // Two updates racing could at worst both think the body changed,
+            // which only costs clients one extra full response
+            let previous = self.load();
+            self.current.store(Arc::new(Snapshot::new(
+                Document::new(v1, Some(&previous.v1)),
+                Document::new(v2, Some(&previous.v2)),
+            )));
+        }
+
+        // Replaces the v2 document with `revise`'s version of it. Left alone
+        // if an update gets in first, as that one's newer.
+        pub fn revise_v2(&self, revise: impl FnOnce(&Value) -> Value) {
+            let previous = self.load();
+            let v2 = revise(&previous.v2.value);
+            if v2 == previous.v2.value {
+                return;
+            }
+            let revised = Arc::new(Snapshot::new(
+                Document::new(previous.v1.value.clone(), Some(&previous.v1)),
+                Document::new(v2, Some(&previous.v2)),
+            ));
+            self.current.compare_and_swap(&previous, revised);
+        }
+    }
+
+    pub type Db = Arc<Cache>;
 
     pub fn blank_db() -> Db {
-        Arc::new(Mutex::new(json!(null)))
+        Arc::new(Cache {
+            current: ArcSwap::from_pointee(Snapshot::new(
+                Document::new(json!(null), None),
+                Document::new(json!(null), None),
+            )),
+        })
     }
 }
 
@@ -689,4 +1482,468 @@ mod tests {
 
         assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let resp = request()
+            .method("OPTIONS")
+            .path("/spins/get")
+            .header("Origin", "https://example.com")
+            .header("Access-Control-Request-Method", "GET")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()["access-control-allow-origin"], "*");
+        assert_eq!(
+            resp.headers()["access-control-allow-methods"],
+            "GET, OPTIONS"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conditional_get() {
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        spin_db.update(serde_json::json!({"spin-0": {"id": 1}}), serde_json::json!(null));
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let resp = request().method("GET").path("/spins/get").reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["cache-control"], "public, max-age=5");
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        assert_eq!(resp.body(), r#"{"spin-0":{"id":1}}"#);
+        let etag = resp.headers()["etag"].clone();
+        let last_modified = resp.headers()["last-modified"].clone();
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/get")
+            .header("If-None-Match", etag.clone())
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert!(resp.body().is_empty());
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/get")
+            .header("If-Modified-Since", last_modified)
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        spin_db.update(serde_json::json!({"spin-0": {"id": 2}}), serde_json::json!(null));
+        let resp = request()
+            .method("GET")
+            .path("/spins/get")
+            .header("If-None-Match", etag)
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_get() {
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let spins: serde_json::Map<String, serde_json::Value> = (0..10)
+            .map(|i| (format!("spin-{}", i), serde_json::json!({"artist": "Nina Simone"})))
+            .collect();
+        spin_db.update(spins.into(), serde_json::json!(null));
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/get")
+            .header("Accept-Encoding", "gzip, deflate, br")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-encoding"], "br");
+        assert!(resp.headers()["etag"].to_str().unwrap().starts_with("W/"));
+        let etag = resp.headers()["etag"].clone();
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/get")
+            .header("Accept-Encoding", "gzip")
+            .header("If-None-Match", etag)
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_versioned_routes() {
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let resp = request().method("GET").path("/v2/spins").reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        spin_db.update(
+            serde_json::json!({"spin-0": {"id": 1}}),
+            serde_json::json!({"spins": [{"id": 1}]}),
+        );
+
+        let resp = request().method("GET").path("/v2/spins").reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), r#"{"spins":[{"id":1}]}"#);
+
+        let resp = request().method("GET").path("/v1/spins/get").reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), r#"{"spin-0":{"id":1}}"#);
+    }
+
+    #[tokio::test]
+    async fn test_openapi() {
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let resp = request().method("GET").path("/openapi.json").reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let spec: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(spec["paths"]["/v2/spins"]["get"].is_object());
+
+        let resp = request().method("GET").path("/docs").reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "text/html; charset=utf-8");
+    }
+
+    #[tokio::test]
+    async fn test_fields_projection() {
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let spin = serde_json::json!({"id": 1, "artist": "Sun Ra", "song": "Rocket Number Nine"});
+        spin_db.update(
+            serde_json::json!({ "spin-0": spin }),
+            serde_json::json!({ "spins": [spin] }),
+        );
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let resp = request()
+            .method("GET")
+            .path("/v2/spins?fields=artist")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), r#"{"spins":[{"artist":"Sun Ra"}]}"#);
+        assert!(resp.headers().contains_key("etag"));
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/get?fields=song")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.body(), r#"{"spin-0":{"song":"Rocket Number Nine"}}"#);
+    }
+
+    #[tokio::test]
+    async fn test_redacted_fields_never_served() {
+        let policy = crate::fields::Policy::new(
+            &crate::config::parse(
+                r#"
+                [fields.spin]
+                deny = ["note"]
+
+                [fields.show]
+                deny = ["note"]
+
+                [fields.dj]
+                deny = ["email"]
+                rename = { website = "homepage" }
+                "#,
+            )
+            .unwrap()
+            .fields,
+        )
+        .unwrap();
+        // Current, so /now serves them too
+        let now = chrono::Utc::now();
+        let time = |minutes| {
+            (now + chrono::Duration::minutes(minutes))
+                .format("%Y-%m-%dT%H:%M:%S%z")
+                .to_string()
+        };
+        let spins = serde_json::json!({
+            "items": [{
+                "id": 2,
+                "artist": "Nina Simone",
+                "note": "SECRET-SPIN-NOTE",
+                "start": time(-1),
+                "duration": 600,
+                "_links": {}
+            }]
+        });
+        let mut shows = serde_json::json!({
+            "items": [
+                {"id": 10, "title": "Morning Jazz", "note": "SECRET-SHOW-NOTE",
+                    "start": time(-30), "end": time(30), "_links": {"personas": []}},
+                {"id": 11, "title": "Overnight", "start": time(30), "end": time(90),
+                    "_links": {"personas": []}}
+            ]
+        });
+        policy.apply_items(crate::fields::Kind::Show, &mut shows);
+        let dj = r#"{"id": 5, "website": "https://example.org/cool",
+            "email": "SECRET@example.org"}"#;
+        let djs = vec![
+            vec![dj.to_string()],
+            vec![],
+        ];
+
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let (v1, v2) = crate::handlers::ingest_spins(&policy, spins).await;
+        spin_db.update(v1, v2);
+        let (v1, v2) = crate::handlers::ingest_shows(&policy, &shows, &djs).await;
+        show_db.update(v1, v2);
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let data_routes = filters::ROUTES.iter().filter(|route| {
+            route.method == "GET" && matches!(route.auth, crate::openapi::Auth::ApiKey)
+        });
+        for route in data_routes {
+            let resp = request().method("GET").path(route.path).reply(&api).await;
+
+            assert_eq!(resp.status(), StatusCode::OK, "{}", route.path);
+            let body = String::from_utf8_lossy(resp.body());
+            assert!(!body.contains("SECRET"), "{}: {}", route.path, body);
+            assert!(!body.contains("\"email\""), "{}: {}", route.path, body);
+            if route.path.contains("show") || route.path == "/now" {
+                let renamed = r#""homepage":"https://example.org/cool""#;
+                assert!(body.contains(renamed), "{}: {}", route.path, body);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_feeds() {
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let resp = request().method("GET").path("/spins/feed.rss").reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        spin_db.update(
+            serde_json::json!({"spin-0": {"id": 7}}),
+            serde_json::json!({"spins": [{"id": 7, "artist": "Sun Ra"}]}),
+        );
+
+        let resp = request().method("GET").path("/spins/feed.rss").reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/rss+xml; charset=utf-8");
+        let body = String::from_utf8_lossy(resp.body());
+        assert!(body.contains("<guid isPermaLink=\"false\">urn:spinitron:spin:7</guid>"));
+        let etag = resp.headers()["etag"].clone();
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/feed.atom")
+            .header("If-None-Match", etag.clone())
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/atom+xml; charset=utf-8");
+        assert_ne!(resp.headers()["etag"], etag);
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/feed.rss")
+            .header("If-None-Match", etag)
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_feeds() {
+        use std::io::Read;
+
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let spins: Vec<serde_json::Value> = (0..10)
+            .map(|i| serde_json::json!({"id": i, "artist": "Nina Simone", "song": "Sinnerman"}))
+            .collect();
+        spin_db.update(serde_json::json!(null), serde_json::json!({"spins": spins}));
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let resp = request().method("GET").path("/spins/feed.rss").reply(&api).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+        let identity = resp.body().clone();
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/feed.rss")
+            .header("Accept-Encoding", "gzip, deflate, br")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.headers()["content-encoding"], "br");
+        assert_eq!(resp.headers()["content-type"], "application/rss+xml; charset=utf-8");
+        assert!(resp.headers()["etag"].to_str().unwrap().starts_with("W/"));
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(&resp.body()[..], 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, identity);
+        let etag = resp.headers()["etag"].clone();
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/feed.atom")
+            .header("Accept-Encoding", "gzip")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.headers()["content-encoding"], "gzip");
+        assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&resp.body()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert!(decoded.contains("<id>urn:spinitron:spin:9</id>"));
+
+        let resp = request()
+            .method("GET")
+            .path("/spins/feed.rss")
+            .header("Accept-Encoding", "gzip")
+            .header("If-None-Match", etag)
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_widget() {
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+
+        let resp = request()
+            .method("GET")
+            .path("/widget/now-playing?accent=%23ff0000&compact&recent=3&api_key=abc")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "text/html; charset=utf-8");
+        let body = String::from_utf8_lossy(resp.body());
+        assert!(body.contains("color: #ff0000;"));
+        assert!(body.contains(r#"<body class="compact">"#));
+        assert!(body.contains("const RECENT = 3;"));
+        // Keys would be public in the iframe's src, so the page never reads one
+        assert!(!body.contains("api_key"));
+
+        let resp = request()
+            .method("GET")
+            .path("/widget/now-playing?bg=%3C/style%3E")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.body(), r#"Invalid value for bg: "</style>""#);
+    }
+
+    #[tokio::test]
+    async fn test_images() {
+        let show_db = models::blank_db();
+        let spin_db = models::blank_db();
+        let connected_users: Arc<Mutex<HashMap<usize, UnboundedSender<Message>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(spin_db.clone(), show_db.clone(), connected_users.clone());
+        spin_db.update(
+            serde_json::json!({}),
+            serde_json::json!({"spins": [{"id": 2, "image": null}]}),
+        );
+
+        for path in ["/images/2", "/images/3?w=200", "/images/djs/7"] {
+            let resp = request().method("GET").path(path).reply(&api).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+
+        let resp = request()
+            .method("GET")
+            .path("/images/2?w=-1")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.body(), r#"Invalid value for w: "-1""#);
+    }
+
+    #[test]
+    fn test_revise_v2() {
+        let db = models::blank_db();
+        db.update(
+            serde_json::json!({"spin-0": {"id": 1}}),
+            serde_json::json!({"spins": [{"id": 1}]}),
+        );
+
+        db.revise_v2(|v2| {
+            let mut v2 = v2.clone();
+            v2["spins"][0]["cover_art"] = serde_json::json!("https://caa.test/front.jpg");
+            v2
+        });
+        let revised = db.load();
+        assert_eq!(revised.v1.value, serde_json::json!({"spin-0": {"id": 1}}));
+        assert_eq!(revised.v2.value["spins"][0]["cover_art"], "https://caa.test/front.jpg");
+
+        // A revision of data that's been updated in the meantime is dropped
+        db.revise_v2(|v2| {
+            db.update(serde_json::json!({}), serde_json::json!({"spins": [{"id": 2}]}));
+            let mut v2 = v2.clone();
+            v2["spins"][0]["cover_art"] = serde_json::json!(null);
+            v2
+        });
+        assert_eq!(db.load().v2.value, serde_json::json!({"spins": [{"id": 2}]}));
+    }
 }
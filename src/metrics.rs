@@ -0,0 +1,118 @@
+//! Counters served at /admin/metrics, in the Prometheus text format.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+static COUNTERS: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+struct Registered {
+    name: &'static str,
+    help: &'static str,
+    labels: Vec<(&'static str, String)>,
+    counter: Counter,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The counter with this name and labels, registered the first time it's
+/// asked for. Names should end in `_total`, as Prometheus expects.
+pub fn counter(name: &'static str, help: &'static str, labels: &[(&'static str, &str)]) -> Counter {
+    let labels: Vec<(&'static str, String)> = labels
+        .iter()
+        .map(|(label, value)| (*label, value.to_string()))
+        .collect();
+    let mut counters = COUNTERS.lock().unwrap();
+    if let Some(registered) = counters
+        .iter()
+        .find(|registered| registered.name == name && registered.labels == labels)
+    {
+        return registered.counter.clone();
+    }
+    let counter = Counter::default();
+    counters.push(Registered {
+        name,
+        help,
+        labels,
+        counter: counter.clone(),
+    });
+    counter
+}
+
+/// Every counter, grouped by name in the order they were registered.
+pub fn render() -> String {
+    let counters = COUNTERS.lock().unwrap();
+    let mut names: Vec<&'static str> = Vec::new();
+    for registered in counters.iter() {
+        if !names.contains(&registered.name) {
+            names.push(registered.name);
+        }
+    }
+
+    let mut rendered = String::new();
+    for name in names {
+        let series: Vec<&Registered> = counters
+            .iter()
+            .filter(|registered| registered.name == name)
+            .collect();
+        rendered.push_str(&format!("# HELP {} {}\n", name, series[0].help));
+        rendered.push_str(&format!("# TYPE {} counter\n", name));
+        for registered in series {
+            let labels: Vec<String> = registered
+                .labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+                .collect();
+            match labels.is_empty() {
+                true => rendered.push_str(name),
+                false => rendered.push_str(&format!("{}{{{}}}", name, labels.join(","))),
+            }
+            rendered.push_str(&format!(" {}\n", registered.counter.get()));
+        }
+    }
+    rendered
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{counter, render};
+
+    #[test]
+    fn test_counters() {
+        let first = counter("test_pushes_total", "Pushes.", &[("target", "a\"b")]);
+        let second = counter("test_pushes_total", "Pushes.", &[("target", "c")]);
+        counter("test_pushes_total", "Pushes.", &[("target", "a\"b")]).inc();
+        first.inc();
+        second.inc();
+        counter("test_starts_total", "Starts.", &[]).inc();
+
+        assert_eq!(first.get(), 2);
+        let rendered = render();
+        assert!(rendered.contains(
+            "# HELP test_pushes_total Pushes.\n\
+             # TYPE test_pushes_total counter\n\
+             test_pushes_total{target=\"a\\\"b\"} 2\n\
+             test_pushes_total{target=\"c\"} 1\n"
+        ));
+        assert!(rendered.contains("# TYPE test_starts_total counter\ntest_starts_total 1\n"));
+    }
+}
@@ -0,0 +1,168 @@
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+
+use crate::config::NowConfig;
+
+/// Builds the /now document from the /v2 spins and shows documents:
+/// the spin that's playing right now, the show on air and the next one.
+/// `on_air` is false when no live show is on, e.g. automation (a show in one
+/// of the config's automation categories) or dead air.
+pub fn now_playing(config: &NowConfig, spins: &Value, shows: &Value, now: DateTime<Utc>) -> Value {
+    let spin = spins["spins"]
+        .as_array()
+        .and_then(|spins| spins.first())
+        .filter(|spin| match window(spin) {
+            Some((start, end)) => start <= now && now < end,
+            None => false,
+        });
+
+    let shows = shows["shows"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+    let show = shows.iter().find(|show| match window(show) {
+        Some((start, end)) => start <= now && now < end,
+        None => false,
+    });
+    let next_show = shows
+        .iter()
+        .filter(|show| matches!(window(show), Some((start, _)) if start > now))
+        .min_by_key(|show| window(show).map(|(start, _)| start));
+
+    let on_air = show.is_some_and(|show| {
+        !show["category"].as_str().is_some_and(|category| {
+            config
+                .automation_categories
+                .iter()
+                .any(|automation| category.eq_ignore_ascii_case(automation))
+        })
+    });
+
+    json!({
+        "on_air": on_air,
+        "spin": spin,
+        "show": show,
+        "next_show": next_show,
+    })
+}
+
+// When a spin or show starts and ends. Spins without an end are assumed to
+// last `duration` seconds.
+fn window(item: &Value) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let time = |field: &str| {
+        DateTime::parse_from_rfc3339(item[field].as_str()?)
+            .ok()
+            .map(|time| time.with_timezone(&Utc))
+    };
+    let start = time("start")?;
+    let end =
+        time("end").or_else(|| Some(start + Duration::seconds(item["duration"].as_i64()?)))?;
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    use crate::config::NowConfig;
+
+    use super::now_playing;
+
+    fn spins() -> serde_json::Value {
+        json!({
+            "spins": [
+                {
+                    "id": 2,
+                    "song": "Sinnerman",
+                    "start": "2024-03-01T12:03:00-08:00",
+                    "duration": 620
+                },
+                {
+                    "id": 1,
+                    "song": "Space Is the Place",
+                    "start": "2024-03-01T11:50:00-08:00",
+                    "duration": 780
+                }
+            ]
+        })
+    }
+
+    fn shows() -> serde_json::Value {
+        json!({
+            "shows": [
+                {
+                    "id": 10,
+                    "title": "Morning Jazz",
+                    "start": "2024-03-01T10:00:00-08:00",
+                    "end": "2024-03-01T13:00:00-08:00",
+                    "djs": [{"id": 5, "name": "DJ Cool"}]
+                },
+                {
+                    "id": 11,
+                    "title": "Overnight",
+                    "category": "Automation",
+                    "start": "2024-03-01T13:00:00-08:00",
+                    "end": "2024-03-01T16:00:00-08:00",
+                    "djs": []
+                }
+            ]
+        })
+    }
+
+    #[test]
+    fn test_live_show() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 20, 5, 0).unwrap();
+        let doc = now_playing(&NowConfig::default(), &spins(), &shows(), now);
+
+        assert_eq!(doc["on_air"], true);
+        assert_eq!(doc["spin"]["id"], 2);
+        assert_eq!(doc["show"]["djs"][0]["name"], "DJ Cool");
+        assert_eq!(doc["next_show"]["id"], 11);
+    }
+
+    #[test]
+    fn test_spin_over() {
+        // Sinnerman ended at 12:13:20
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 20, 20, 0).unwrap();
+        let doc = now_playing(&NowConfig::default(), &spins(), &shows(), now);
+
+        assert_eq!(doc["on_air"], true);
+        assert!(doc["spin"].is_null());
+    }
+
+    #[test]
+    fn test_automation_and_dead_air() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 21, 30, 0).unwrap();
+        let doc = now_playing(&NowConfig::default(), &spins(), &shows(), now);
+
+        assert_eq!(doc["on_air"], false);
+        assert_eq!(doc["show"]["id"], 11);
+        assert!(doc["next_show"].is_null());
+
+        let now = Utc.with_ymd_and_hms(2024, 3, 2, 12, 0, 0).unwrap();
+        let doc = now_playing(&NowConfig::default(), &spins(), &shows(), now);
+
+        assert_eq!(
+            doc,
+            json!({"on_air": false, "spin": null, "show": null, "next_show": null})
+        );
+    }
+
+    #[test]
+    fn test_custom_automation_categories() {
+        let config = NowConfig {
+            automation_categories: vec!["Rotation".to_string()],
+        };
+        let mut rotation = shows();
+        rotation["shows"][1]["category"] = json!("rotation");
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 21, 30, 0).unwrap();
+
+        assert_eq!(
+            now_playing(&config, &spins(), &rotation, now)["on_air"],
+            false
+        );
+        // "Automation" is no longer one of them
+        assert_eq!(
+            now_playing(&config, &spins(), &shows(), now)["on_air"],
+            true
+        );
+    }
+}
@@ -0,0 +1,465 @@
+use serde_json::{json, Map, Value};
+
+/// The page served at /docs. It renders /openapi.json in the browser.
+pub const DOCS_PAGE: &str = include_str!("docs.html");
+
+/// What a route responds with on success.
+pub enum Body {
+    // Named schema under components/schemas
+    Json(&'static str),
+    Text,
+    EventStream,
+    Html,
+    // XML with this media type, e.g. "application/rss+xml"
+    Xml(&'static str),
+    // JPEG or PNG
+    Image,
+}
+
+pub enum Auth {
+    Public,
+    // Client API key, when configured, plus rate limiting
+    ApiKey,
+    // Bearer admin token
+    Admin,
+}
+
+/// One method on one path. `filters::ROUTES` lists every route the relay
+/// serves, and both CORS preflights and the OpenAPI document are built from it.
+pub struct Route {
+    pub method: &'static str,
+    // `{name}` segments are path parameters
+    pub path: &'static str,
+    pub summary: &'static str,
+    pub body: Body,
+    pub auth: Auth,
+    // Supports ETag/Last-Modified revalidation
+    pub cached: bool,
+}
+
+/// Builds the OpenAPI 3 document for `routes`.
+pub fn document(routes: &[Route]) -> Value {
+    let mut paths = Map::new();
+    for route in routes {
+        let path = paths
+            .entry(route.path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap();
+        path.insert(route.method.to_ascii_lowercase(), operation(route));
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "API-Relay",
+            "description": "Relays a radio station's Spinitron data without exposing its API key.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "apiKeyHeader": {"type": "apiKey", "in": "header", "name": "X-API-Key"},
+                "apiKeyQuery": {"type": "apiKey", "in": "query", "name": "api_key"},
+                "adminToken": {"type": "http", "scheme": "bearer"},
+            },
+        },
+    })
+}
+
+fn operation(route: &Route) -> Value {
+    let mut responses = Map::new();
+    responses.insert("200".to_string(), success(&route.body));
+    if route.cached {
+        responses.insert(
+            "304".to_string(),
+            json!({"description": "Unchanged since the ETag or date the client sent."}),
+        );
+    }
+    if let Body::Image = route.body {
+        responses.insert(
+            "400".to_string(),
+            json!({"description": "`w` or `format` isn't valid."}),
+        );
+        responses.insert(
+            "404".to_string(),
+            json!({"description": "The relay doesn't hold that record, or it has no image."}),
+        );
+        responses.insert(
+            "502".to_string(),
+            json!({"description": "The original couldn't be fetched or decoded."}),
+        );
+    }
+    match route.auth {
+        Auth::Public => {}
+        Auth::ApiKey => {
+            if let Body::Json(_) = route.body {
+                responses.insert(
+                    "400".to_string(),
+                    json!({"description": "`fields` names a field that's never relayed."}),
+                );
+            }
+            responses.insert(
+                "401".to_string(),
+                json!({"description": "Missing or invalid API key."}),
+            );
+            responses.insert(
+                "403".to_string(),
+                json!({"description": "The API key isn't allowed to use this route."}),
+            );
+            responses.insert(
+                "429".to_string(),
+                json!({
+                    "description": "Rate limit or daily quota exceeded.",
+                    "headers": {"Retry-After": {"schema": {"type": "integer"}}},
+                }),
+            );
+        }
+        Auth::Admin => {
+            responses.insert(
+                "401".to_string(),
+                json!({"description": "Missing or wrong admin token."}),
+            );
+        }
+    }
+
+    let operation_id: String = format!("{} {}", route.method, route.path)
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let mut operation = json!({
+        "summary": route.summary,
+        "operationId": operation_id,
+        "responses": responses,
+    });
+
+    let mut parameters: Vec<Value> = route
+        .path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": {"type": "string"},
+            })
+        })
+        .collect();
+    // Every JSON data route takes a projection
+    if let (Auth::ApiKey, Body::Json(_)) = (&route.auth, &route.body) {
+        parameters.push(json!({
+            "name": "fields",
+            "in": "query",
+            "description": "Comma-separated fields to keep in each record, e.g. artist,song,title.",
+            "schema": {"type": "string"},
+        }));
+    }
+    if !parameters.is_empty() {
+        operation["parameters"] = Value::Array(parameters);
+    }
+    if route.method == "POST" {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": {"application/x-www-form-urlencoded": {"schema": {"type": "object"}}},
+        });
+    }
+    match route.auth {
+        Auth::Public => {}
+        // An empty requirement makes the key optional, as it is unless `required` is set
+        Auth::ApiKey => {
+            operation["security"] = json!([{}, {"apiKeyHeader": []}, {"apiKeyQuery": []}])
+        }
+        Auth::Admin => operation["security"] = json!([{"adminToken": []}]),
+    }
+    operation
+}
+
+fn success(body: &Body) -> Value {
+    let (content_type, schema) = match body {
+        Body::Json(name) => (
+            "application/json",
+            json!({"$ref": format!("#/components/schemas/{}", name)}),
+        ),
+        Body::Text => ("text/plain", json!({"type": "string"})),
+        Body::EventStream => ("text/event-stream", json!({"type": "string"})),
+        Body::Html => ("text/html", json!({"type": "string"})),
+        Body::Xml(media_type) => (*media_type, json!({"type": "string"})),
+        Body::Image => {
+            let schema = json!({"type": "string", "format": "binary"});
+            return json!({
+                "description": "OK",
+                "content": {"image/jpeg": {"schema": schema}, "image/png": {"schema": schema}},
+            });
+        }
+    };
+    json!({
+        "description": "OK",
+        "content": {content_type: {"schema": schema}},
+    })
+}
+
+// Spinitron passes through more fields than are listed here; only the ones
+// clients commonly rely on are described.
+fn schemas() -> Value {
+    let spin_fields = json!({
+        "id": {"type": "integer"},
+        "playlist_id": {"type": "integer"},
+        "duration": {"type": "integer", "description": "Seconds"},
+        "timezone": {"type": "string"},
+        "image": {"type": "string", "nullable": true},
+        "artist": {"type": "string"},
+        "song": {"type": "string"},
+        "release": {"type": "string"},
+        "label": {"type": "string"},
+        "released": {"type": "integer", "nullable": true},
+        "genre": {"type": "string", "nullable": true},
+        "note": {"type": "string", "nullable": true},
+    });
+    let show_fields = json!({
+        "id": {"type": "integer"},
+        "title": {"type": "string"},
+        "description": {"type": "string"},
+        "category": {"type": "string", "nullable": true},
+        "duration": {"type": "integer", "description": "Seconds"},
+        "timezone": {"type": "string"},
+        "url": {"type": "string", "nullable": true},
+        "image": {"type": "string", "nullable": true},
+    });
+    let with_times = |fields: &Value, format: Option<&str>| {
+        let mut fields = fields.as_object().unwrap().clone();
+        for name in ["start", "end"] {
+            let schema = match format {
+                Some(format) => json!({"type": "string", "format": format}),
+                None => json!({"type": "string", "example": "2024-03-01T12:03:00+0000"}),
+            };
+            fields.insert(name.to_string(), schema);
+        }
+        Value::Object(fields)
+    };
+
+    let mut spin_v2 = with_times(&spin_fields, Some("date-time"));
+    spin_v2["musicbrainz"] = json!({
+        "type": "object",
+        "description": "Only when enrichment is configured and a match was found.",
+        "properties": {
+            "recording_id": {"type": "string"},
+            "release_id": {"type": "string", "nullable": true},
+            "artist": {"type": "string", "nullable": true,
+                       "description": "As credited on MusicBrainz"},
+        },
+    });
+    spin_v2["cover_art"] = json!({
+        "type": "string",
+        "description": "From the Cover Art Archive, when enrichment found some.",
+    });
+    spin_v2["links"] = json!({
+        "type": "object",
+        "description": "Links to the track by provider, e.g. \"spotify\", when configured.",
+        "additionalProperties": {"type": "string"},
+    });
+    let mut show_v2 = with_times(&show_fields, Some("date-time"));
+    show_v2["djs"] = json!({"type": "array", "items": {"$ref": "#/components/schemas/Dj"}});
+
+    json!({
+        "Spin": {"type": "object", "properties": spin_v2},
+        "Show": {"type": "object", "properties": show_v2},
+        "Dj": {
+            "type": "object",
+            "properties": {
+                "id": {"type": "integer"},
+                "name": {"type": "string"},
+                "bio": {"type": "string", "nullable": true},
+                "website": {"type": "string", "nullable": true},
+                "image": {"type": "string", "nullable": true},
+            },
+        },
+        "SpinsV2": {
+            "type": "object",
+            "properties": {
+                "spins": {"type": "array", "items": {"$ref": "#/components/schemas/Spin"}},
+            },
+        },
+        "ShowsV2": {
+            "type": "object",
+            "properties": {
+                "shows": {"type": "array", "items": {"$ref": "#/components/schemas/Show"}},
+            },
+        },
+        "SpinsV1": {
+            "type": "object",
+            "description": "Spins keyed \"spin-0\" (most recent) to \"spin-9\".",
+            "additionalProperties": {
+                "type": "object",
+                "properties": with_times(&spin_fields, None),
+            },
+        },
+        "ShowsV1": {
+            "type": "object",
+            "description": "Shows keyed \"show-0\" and \"show-1\", the first DJ of each as \"dj-0\" and \"dj-1\", and every DJ under \"v2\".",
+            "additionalProperties": {"type": "object"},
+        },
+        "NowPlaying": {
+            "type": "object",
+            "properties": {
+                "on_air": {
+                    "type": "boolean",
+                    "description": "False during automation or when nothing is scheduled.",
+                },
+                "spin": {
+                    "allOf": [{"$ref": "#/components/schemas/Spin"}],
+                    "nullable": true,
+                    "description": "Null once the latest spin has finished playing.",
+                },
+                "show": {"allOf": [{"$ref": "#/components/schemas/Show"}], "nullable": true},
+                "next_show": {"allOf": [{"$ref": "#/components/schemas/Show"}], "nullable": true},
+            },
+        },
+        "UsageReport": {
+            "type": "object",
+            "properties": {
+                "clients": {"type": "object", "additionalProperties": {"type": "object"}},
+            },
+        },
+        "OpenApi": {"type": "object"},
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    use warp::reject::MethodNotAllowed;
+    use warp::test::request;
+
+    use crate::filters::{self, ROUTES};
+    use crate::{handlers, models};
+
+    use super::{document, Auth};
+
+    // The paths of every `warp::path!` in `source`, with parameters as "{}"
+    fn declared_paths(source: &str) -> Vec<String> {
+        // Split up so this file doesn't match itself
+        source
+            .split(concat!("warp::path", "!("))
+            .skip(1)
+            .map(|rest| {
+                let args = &rest[..rest.find(')').unwrap()];
+                let segments: Vec<String> = args
+                    .split('/')
+                    .map(str::trim)
+                    .map(|segment| match segment.strip_prefix('"') {
+                        Some(literal) => literal.trim_end_matches('"').to_string(),
+                        None => "{}".to_string(),
+                    })
+                    .collect();
+                format!("/{}", segments.join("/"))
+            })
+            .collect()
+    }
+
+    // "/images/{spin_id}" -> "/images/1", something the route will match
+    fn concrete(path: &str) -> String {
+        path.split('/')
+            .map(|segment| {
+                if segment.starts_with('{') {
+                    "1"
+                } else {
+                    segment
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    // Every `.rs` file under `dir`
+    fn sources(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(sources(&path));
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    // Fails when filters::routes serves a method and path without an entry in
+    // filters::ROUTES, which would leave it out of the OpenAPI document and
+    // CORS preflights, or when an entry isn't actually served. The paths to
+    // try come from ROUTES and the `warp::path!`s in the source, each also
+    // under /v1; whether they're served is up to the real routes.
+    #[tokio::test]
+    async fn test_every_route_is_documented() {
+        let users: handlers::Users = Arc::new(Mutex::new(HashMap::new()));
+        let api = filters::routes(models::blank_db(), models::blank_db(), users);
+
+        let src = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src"));
+        let mut paths: BTreeSet<String> = ROUTES.iter().map(|route| concrete(route.path)).collect();
+        for file in sources(src) {
+            let source = fs::read_to_string(&file).unwrap();
+            paths.extend(declared_paths(&source).iter().map(|path| concrete(path)));
+        }
+        for path in paths.clone() {
+            paths.insert(format!("/v1{}", path));
+        }
+
+        for path in &paths {
+            for method in ["GET", "POST", "PUT", "PATCH", "DELETE"] {
+                let documented = ROUTES
+                    .iter()
+                    .find(|route| route.method == method && concrete(route.path) == *path);
+                // Without a content type the updates are turned away before
+                // they'd call Spinitron
+                let served = match request().method(method).path(path).filter(&api).await {
+                    Ok(_) => true,
+                    Err(rejection) => {
+                        !rejection.is_not_found() && rejection.find::<MethodNotAllowed>().is_none()
+                    }
+                };
+                match documented {
+                    // Not found until a token is configured
+                    Some(route) if matches!(route.auth, Auth::Admin) => {}
+                    Some(_) => assert!(served, "{} {} is in ROUTES but not served", method, path),
+                    None => assert!(
+                        !served,
+                        "{} {} is served but missing from filters::ROUTES",
+                        method, path
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_document() {
+        let doc = document(ROUTES);
+
+        for route in ROUTES {
+            let operation = &doc["paths"][route.path][route.method.to_ascii_lowercase()];
+            assert!(operation.is_object(), "{} {}", route.method, route.path);
+            if let Some(reference) = operation["responses"]["200"]["content"]["application/json"]
+                ["schema"]["$ref"]
+                .as_str()
+            {
+                let name = reference.rsplit('/').next().unwrap();
+                assert!(doc["components"]["schemas"][name].is_object(), "{}", name);
+            }
+        }
+        assert!(doc["paths"]["/v2/spins"]["get"]["responses"]["304"].is_object());
+        assert_eq!(
+            doc["paths"]["/spins/update"]["post"]["responses"]
+                .as_object()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
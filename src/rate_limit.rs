@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use warp::{reject::Reject, Filter, Rejection};
+
+use crate::{
+    client_ip,
+    config::{self, IpRange, Limit, RateLimitConfig},
+};
+
+// How often idle buckets are dropped so the map doesn't grow without bound
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Rejection for a client that has run out of tokens. Turned into a 429 by
+/// `filters::handle_rejection`.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+impl Reject for RateLimited {}
+
+/// A token bucket holding up to `burst` tokens, refilled at `per_second`.
+/// Sinks use these too, to stay within the limits of the services they post to.
+pub struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // When the bucket will be back to `burst` tokens if left alone
+    full_at: Instant,
+}
+
+impl Bucket {
+    pub fn new(limit: Limit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+            full_at: now,
+        }
+    }
+
+    /// Takes a token, or says how long until one is available.
+    pub fn take(&mut self, limit: Limit, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+
+        let result = if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.per_second,
+            ))
+        };
+        let missing = limit.burst as f64 - self.tokens;
+        self.full_at = now + Duration::from_secs_f64(missing / limit.per_second);
+        result
+    }
+}
+
+struct Buckets {
+    map: HashMap<(&'static str, IpAddr), Bucket>,
+    last_sweep: Instant,
+}
+
+struct Inner {
+    default: Limit,
+    routes: HashMap<String, Limit>,
+    exempt: Vec<IpRange>,
+    trusted_proxies: Arc<[IpRange]>,
+    buckets: Mutex<Buckets>,
+}
+
+/// Per-client token buckets, keyed by route and client IP. Cloning shares the
+/// buckets. A limiter built without a `[rate_limit]` section lets everything through.
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Option<Arc<Inner>>,
+}
+
+impl RateLimiter {
+    pub fn from_config(config: &config::Config) -> RateLimiter {
+        match &config.rate_limit {
+            Some(rate_limit) => RateLimiter::new(rate_limit, &config.trusted_proxies),
+            None => RateLimiter { inner: None },
+        }
+    }
+
+    pub fn new(config: &RateLimitConfig, trusted_proxies: &[IpRange]) -> RateLimiter {
+        for (route, limit) in std::iter::once(("default", &config.default))
+            .chain(config.routes.iter().map(|(k, v)| (k.as_str(), v)))
+        {
+            if limit.per_second <= 0.0 || limit.burst == 0 {
+                panic!(
+                    "Rate limit for {} must have a positive per_second and burst.",
+                    route
+                );
+            }
+        }
+
+        RateLimiter {
+            inner: Some(Arc::new(Inner {
+                default: config.default,
+                routes: config.routes.clone(),
+                exempt: config.exempt.clone(),
+                trusted_proxies: trusted_proxies.into(),
+                buckets: Mutex::new(Buckets {
+                    map: HashMap::new(),
+                    last_sweep: Instant::now(),
+                }),
+            })),
+        }
+    }
+
+    fn trusted_proxies(&self) -> Arc<[IpRange]> {
+        match &self.inner {
+            Some(inner) => inner.trusted_proxies.clone(),
+            None => Arc::new([]),
+        }
+    }
+
+    fn check(&self, route: &'static str, ip: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        let (inner, ip) = match (&self.inner, ip) {
+            (Some(inner), Some(ip)) => (inner, ip),
+            // Disabled, or no peer address to key on
+            _ => return Ok(()),
+        };
+        if inner.exempt.iter().any(|range| range.contains(&ip)) {
+            return Ok(());
+        }
+
+        let limit = *inner.routes.get(route).unwrap_or(&inner.default);
+        let mut buckets = inner.buckets.lock().unwrap();
+        if now.saturating_duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            buckets.map.retain(|_, bucket| bucket.full_at > now);
+            buckets.last_sweep = now;
+        }
+        buckets
+            .map
+            .entry((route, ip))
+            .or_insert_with(|| Bucket::new(limit, now))
+            .take(limit, now)
+    }
+}
+
+/// Takes a token for `route` from the client's bucket, rejecting with
+/// `RateLimited` when it's empty. Should come after the route's path match so
+/// requests for other routes don't spend this route's tokens.
+pub fn limit(
+    limiter: RateLimiter,
+    route: &'static str,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_ip::filter(limiter.trusted_proxies())
+        .and_then(move |ip: Option<IpAddr>| {
+            let result = limiter.check(route, ip, Instant::now());
+            async move {
+                result.map_err(|retry_after| warp::reject::custom(RateLimited { retry_after }))
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use warp::{http::StatusCode, test::request, Filter};
+
+    use crate::{
+        config::{IpRange, Limit, RateLimitConfig},
+        filters,
+    };
+
+    use super::{limit, RateLimiter};
+
+    fn limiter(exempt: &[&str], trusted: &[&str]) -> RateLimiter {
+        let range = |s: &&str| IpRange::try_from(s.to_string()).unwrap();
+        let config = RateLimitConfig {
+            default: Limit {
+                per_second: 10.0,
+                burst: 10,
+            },
+            routes: HashMap::from([(
+                "spins/get".to_string(),
+                Limit {
+                    per_second: 0.5,
+                    burst: 2,
+                },
+            )]),
+            exempt: exempt.iter().map(range).collect(),
+        };
+        let trusted: Vec<IpRange> = trusted.iter().map(range).collect();
+        RateLimiter::new(&config, &trusted)
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let limiter = limiter(&[], &[]);
+        let ip = Some("198.51.100.4".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.check("spins/get", ip, start).is_ok());
+        assert!(limiter.check("spins/get", ip, start).is_ok());
+        assert_eq!(
+            limiter.check("spins/get", ip, start),
+            Err(Duration::from_secs(2))
+        );
+        // Other routes and clients have their own buckets
+        assert!(limiter.check("shows/get", ip, start).is_ok());
+        assert!(limiter
+            .check("spins/get", Some("198.51.100.5".parse().unwrap()), start)
+            .is_ok());
+
+        assert!(limiter
+            .check("spins/get", ip, start + Duration::from_secs(2))
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_returns_429_with_retry_after() {
+        let api = warp::path!("spins" / "get")
+            .and(limit(limiter(&[], &[]), "spins/get"))
+            .map(warp::reply)
+            .recover(filters::handle_rejection);
+
+        for _ in 0..2 {
+            let resp = request()
+                .path("/spins/get")
+                .remote_addr(([198, 51, 100, 4], 5000).into())
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let resp = request()
+            .path("/spins/get")
+            .remote_addr(([198, 51, 100, 4], 5000).into())
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["retry-after"], "2");
+    }
+
+    #[tokio::test]
+    async fn test_exempt_behind_trusted_proxy() {
+        let api = warp::path!("spins" / "get")
+            .and(limit(
+                limiter(&["203.0.113.7"], &["10.0.0.0/8"]),
+                "spins/get",
+            ))
+            .map(warp::reply)
+            .recover(filters::handle_rejection);
+
+        for _ in 0..5 {
+            let resp = request()
+                .path("/spins/get")
+                .remote_addr(([10, 0, 0, 2], 5000).into())
+                .header("X-Forwarded-For", "203.0.113.7")
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tokio::sync::broadcast::Receiver;
+use warp::http::StatusCode;
+
+use crate::config::{ChatConfig, ChatService};
+use crate::events::Event;
+use crate::rate_limit::Bucket;
+
+use super::template;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to back off when told to slow down without being told for how long
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+// Discord rejects longer embed titles
+const DISCORD_TITLE_MAX: usize = 256;
+
+/// Posts to a chat webhook when a show starts and, if configured, on every
+/// spin. Posts are paced by the config's rate limit, and one the service
+/// turns away with 429 is retried once after the wait it asks for.
+pub async fn run(config: ChatConfig, mut events: Receiver<Event>) {
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap();
+    let mut bucket = Bucket::new(config.rate_limit, Instant::now());
+    let mut show = None;
+    while let Some(event) = super::next(&mut events).await {
+        let (text, record) = match event {
+            Event::ShowChanged(changed) => {
+                show = Some(changed.clone());
+                if !config.events.shows() {
+                    continue;
+                }
+                (template::render(&config.show_template, &changed), changed)
+            }
+            Event::SpinChanged(mut spin) => {
+                if !config.events.spins() {
+                    continue;
+                }
+                spin["show"] = show.clone().unwrap_or(Value::Null);
+                (template::render(&config.spin_template, &spin), spin)
+            }
+        };
+        while let Err(wait) = bucket.take(config.rate_limit, Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+        let payload = payload(config.service, &text, &record);
+        if let Err(e) = post(&client, &config.webhook_url, &payload).await {
+            // The webhook URL is a secret, so it's left out
+            error!("Couldn't post to the {:?} webhook: {}", config.service, e);
+        }
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &str, payload: &Value) -> Result<(), reqwest::Error> {
+    let response = client.post(url).json(payload).send().await?;
+    let response = match response.status() {
+        StatusCode::TOO_MANY_REQUESTS => {
+            let wait = retry_after(&response).min(MAX_RETRY_AFTER);
+            warn!("Chat webhook is rate limited, posting again in {:?}", wait);
+            tokio::time::sleep(wait).await;
+            client.post(url).json(payload).send().await?
+        }
+        _ => response,
+    };
+    response.error_for_status()?;
+    Ok(())
+}
+
+// Discord sends fractional seconds, Slack whole ones
+fn retry_after(response: &reqwest::Response) -> Duration {
+    response
+        .headers()
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+/// The webhook body for `text`, with the record's image (album art for a
+/// spin) alongside where the service can show one.
+pub fn payload(service: ChatService, text: &str, record: &Value) -> Value {
+    let image = record["image"].as_str().filter(|image| !image.is_empty());
+    match service {
+        ChatService::Discord => {
+            let mut embed = json!({
+                "title": text.chars().take(DISCORD_TITLE_MAX).collect::<String>(),
+            });
+            if let Some(url) = record["url"].as_str().filter(|url| !url.is_empty()) {
+                embed["url"] = json!(url);
+            }
+            if let Some(image) = image {
+                embed["thumbnail"] = json!({"url": image});
+            }
+            if let Some(start) = record["start"].as_str() {
+                embed["timestamp"] = json!(start);
+            }
+            json!({"embeds": [embed]})
+        }
+        ChatService::Slack => {
+            let text = escape(text);
+            let mut section = json!({
+                "type": "section",
+                "text": {"type": "mrkdwn", "text": text},
+            });
+            if let Some(image) = image {
+                section["accessory"] = json!({
+                    "type": "image",
+                    "image_url": image,
+                    "alt_text": "Cover art",
+                });
+            }
+            // `text` is the fallback for notifications
+            json!({"text": text, "blocks": [section]})
+        }
+        // Mattermost takes Slack's older attachments rather than blocks
+        ChatService::Mattermost => match image {
+            Some(image) => json!({
+                "attachments": [{"fallback": text, "text": text, "thumb_url": image}],
+            }),
+            None => json!({"text": text}),
+        },
+    }
+}
+
+// Slack treats these three as markup
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use serde_json::{json, Value};
+    use tokio::sync::broadcast;
+    use warp::http::StatusCode;
+
+    use crate::config::{ChatConfig, ChatService, EventFilter, Limit};
+    use crate::events::Event;
+    use crate::sinks::stand_in;
+
+    use super::{payload, run};
+
+    fn config(webhook_url: String, events: EventFilter) -> ChatConfig {
+        ChatConfig {
+            service: ChatService::Slack,
+            webhook_url,
+            events,
+            show_template: "Now on air: {title}".to_string(),
+            spin_template: "{artist} - {song} on {show.title}".to_string(),
+            rate_limit: Limit {
+                per_second: 10.0,
+                burst: 1,
+            },
+        }
+    }
+
+    fn show() -> Value {
+        json!({"id": 10, "title": "Morning Jazz"})
+    }
+
+    fn spin() -> Value {
+        json!({"id": 2, "artist": "Nina Simone", "song": "Sinnerman"})
+    }
+
+    fn text(request: &stand_in::Request) -> String {
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        body["text"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_payloads() {
+        let spin = json!({
+            "artist": "Simon & Garfunkel",
+            "song": "America",
+            "start": "2024-03-01T12:03:00-08:00",
+            "image": "https://i.scdn.co/image/abc.png",
+        });
+        let text = "Simon & Garfunkel - America";
+
+        assert_eq!(
+            payload(ChatService::Discord, text, &spin),
+            json!({"embeds": [{
+                "title": text,
+                "thumbnail": {"url": "https://i.scdn.co/image/abc.png"},
+                "timestamp": "2024-03-01T12:03:00-08:00",
+            }]})
+        );
+        assert_eq!(
+            payload(ChatService::Slack, text, &spin),
+            json!({
+                "text": "Simon &amp; Garfunkel - America",
+                "blocks": [{
+                    "type": "section",
+                    "text": {"type": "mrkdwn", "text": "Simon &amp; Garfunkel - America"},
+                    "accessory": {
+                        "type": "image",
+                        "image_url": "https://i.scdn.co/image/abc.png",
+                        "alt_text": "Cover art",
+                    },
+                }],
+            })
+        );
+        assert_eq!(
+            payload(ChatService::Mattermost, text, &spin)["attachments"][0]["thumb_url"],
+            "https://i.scdn.co/image/abc.png"
+        );
+        assert_eq!(
+            payload(ChatService::Mattermost, text, &json!({"image": null})),
+            json!({"text": text})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "ok");
+        let (tx, rx) = broadcast::channel(8);
+        tokio::spawn(run(config(url.clone(), EventFilter::Shows), rx));
+        tokio::spawn(run(config(url, EventFilter::All), tx.subscribe()));
+
+        tx.send(Event::ShowChanged(show())).unwrap();
+        tx.send(Event::SpinChanged(spin())).unwrap();
+        let mut texts = Vec::new();
+        for _ in 0..3 {
+            texts.push(text(&requests.recv().await.unwrap()));
+        }
+        texts.sort();
+
+        assert_eq!(
+            texts,
+            [
+                "Nina Simone - Sinnerman on Morning Jazz",
+                "Now on air: Morning Jazz",
+                "Now on air: Morning Jazz",
+            ]
+        );
+        // Only the sink posting every spin posts it
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "ok");
+        let (tx, rx) = broadcast::channel(8);
+        tokio::spawn(run(config(url, EventFilter::Spins), rx));
+
+        tx.send(Event::SpinChanged(spin())).unwrap();
+        tx.send(Event::SpinChanged(spin())).unwrap();
+        requests.recv().await.unwrap();
+        let first = Instant::now();
+        requests.recv().await.unwrap();
+
+        // 10 a second with no burst to speak of
+        assert!(first.elapsed() >= Duration::from_millis(80));
+    }
+
+    #[tokio::test]
+    async fn test_retry_when_limited() {
+        let statuses = vec![StatusCode::TOO_MANY_REQUESTS, StatusCode::OK];
+        let (url, mut requests) = stand_in::serve_statuses(statuses, "");
+        let (tx, rx) = broadcast::channel(8);
+        tokio::spawn(run(config(url, EventFilter::Shows), rx));
+
+        tx.send(Event::ShowChanged(show())).unwrap();
+        let limited = requests.recv().await.unwrap();
+        let retried = requests.recv().await.unwrap();
+
+        assert_eq!(text(&limited), "Now on air: Morning Jazz");
+        assert_eq!(text(&retried), text(&limited));
+    }
+}
@@ -0,0 +1,311 @@
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+use tokio::{sync::broadcast::Receiver, time::Instant};
+
+use crate::config::HttpPushConfig;
+use crate::events::Event;
+use crate::metrics::{self, Counter};
+
+use super::template;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Counters {
+    sent: Counter,
+    failed: Counter,
+    dropped: Counter,
+}
+
+impl Counters {
+    fn new(target: &str) -> Counters {
+        let labels = [("target", target)];
+        Counters {
+            sent: metrics::counter(
+                "relay_http_push_sent_total",
+                "Spins pushed to the target.",
+                &labels,
+            ),
+            failed: metrics::counter(
+                "relay_http_push_errors_total",
+                "Pushes that failed, including timeouts and error statuses.",
+                &labels,
+            ),
+            dropped: metrics::counter(
+                "relay_http_push_dropped_total",
+                "Spins replaced by a newer one while waiting out min_interval.",
+                &labels,
+            ),
+        }
+    }
+}
+
+/// Pushes each new spin to a directory, at most once per `min_interval`.
+/// A spin that has to wait is replaced by any newer one, so the target
+/// always gets the latest.
+pub async fn run(config: HttpPushConfig, mut events: Receiver<Event>) {
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap();
+    let counters = Counters::new(config.name());
+    let min_interval = Duration::from_secs(config.min_interval());
+    let mut show = None;
+    let mut waiting: Option<Value> = None;
+    let mut last_push: Option<Instant> = None;
+    loop {
+        let due = last_push
+            .map(|last| last + min_interval)
+            .filter(|due| Instant::now() < *due);
+        let event = match (waiting.is_some(), due) {
+            (false, _) => super::next(&mut events).await,
+            (true, Some(due)) => tokio::select! {
+                event = super::next(&mut events) => event,
+                _ = tokio::time::sleep_until(due) => continue,
+            },
+            (true, None) => {
+                let spin = waiting.take().unwrap();
+                last_push = Some(Instant::now());
+                push(&client, &config, &spin, &counters).await;
+                continue;
+            }
+        };
+        match event {
+            None => return,
+            Some(Event::ShowChanged(changed)) => show = Some(changed),
+            Some(Event::SpinChanged(mut spin)) => {
+                spin["show"] = show.clone().unwrap_or(Value::Null);
+                if let Some(dropped) = waiting.replace(spin) {
+                    debug!(
+                        "Not pushing spin {} to {}, there's a newer one",
+                        dropped["id"],
+                        config.name()
+                    );
+                    counters.dropped.inc();
+                }
+            }
+        }
+    }
+}
+
+async fn push(
+    client: &reqwest::Client,
+    config: &HttpPushConfig,
+    spin: &Value,
+    counters: &Counters,
+) {
+    let request = match config {
+        HttpPushConfig::Tunein {
+            partner_id,
+            partner_key,
+            station_id,
+            url,
+            ..
+        } => client
+            .get(url)
+            .query(&tunein_query(partner_id, partner_key, station_id, spin)),
+        HttpPushConfig::Json {
+            url, headers, body, ..
+        } => headers
+            .iter()
+            .fold(client.post(url), |request, (name, value)| {
+                request.header(name, value)
+            })
+            .json(&render(body, spin)),
+    };
+    match request
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+    {
+        Ok(_) => {
+            debug!("Pushed spin {} to {}", spin["id"], config.name());
+            counters.sent.inc();
+        }
+        Err(e) => {
+            // The URL can hold the partner key
+            error!("Couldn't push to {}: {}", config.name(), e.without_url());
+            counters.failed.inc();
+        }
+    }
+}
+
+// https://tunein.com/broadcasters/api/
+fn tunein_query(
+    partner_id: &str,
+    partner_key: &str,
+    station_id: &str,
+    spin: &Value,
+) -> Vec<(&'static str, String)> {
+    let mut query = vec![
+        ("partnerId", partner_id.to_string()),
+        ("partnerKey", partner_key.to_string()),
+        ("id", station_id.to_string()),
+        ("title", template::field(spin, "song")),
+        ("artist", template::field(spin, "artist")),
+    ];
+    let album = template::field(spin, "release");
+    if !album.is_empty() {
+        query.push(("album", album));
+    }
+    query
+}
+
+/// `body` with the placeholders in each of its strings filled in from
+/// `spin`. A string that's a single placeholder, like "{duration}", becomes
+/// the field's JSON value, so numbers stay numbers.
+pub fn render(body: &Value, spin: &Value) -> Value {
+    match body {
+        Value::String(text) => match template::whole_field(text, spin) {
+            Some(value) => value.clone(),
+            None => Value::String(template::render(text, spin)),
+        },
+        Value::Array(items) => items.iter().map(|item| render(item, spin)).collect(),
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(name, value)| (name.clone(), render(value, spin)))
+            .collect::<Map<_, _>>()
+            .into(),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use serde_json::{json, Value};
+    use tokio::sync::broadcast;
+    use warp::http::StatusCode;
+
+    use crate::config::HttpPushConfig;
+    use crate::events::Event;
+    use crate::metrics;
+    use crate::sinks::stand_in;
+
+    use super::{render, run};
+
+    fn json_config(name: &str, url: String, min_interval: u64) -> HttpPushConfig {
+        HttpPushConfig::Json {
+            name: name.to_string(),
+            url,
+            headers: HashMap::from([("Authorization".to_string(), "Bearer token".to_string())]),
+            body: json!({"title": "{artist} - {song}", "seconds": "{duration}"}),
+            min_interval,
+        }
+    }
+
+    fn spin(id: u64, song: &str) -> Event {
+        Event::SpinChanged(
+            json!({"id": id, "artist": "Nina Simone", "song": song, "duration": 600}),
+        )
+    }
+
+    fn counter(name: &'static str, target: &str) -> u64 {
+        metrics::counter(name, "", &[("target", target)]).get()
+    }
+
+    #[test]
+    fn test_render() {
+        let body = json!({
+            "station": "kscu",
+            "track": {"title": "{song}", "length": "{duration}", "show": "{show.title}"},
+            "tags": ["{genre}", 1],
+        });
+        let spin = json!({"song": "Sinnerman", "duration": 600, "show": {"title": "Morning Jazz"}});
+
+        assert_eq!(
+            render(&body, &spin),
+            json!({
+                "station": "kscu",
+                "track": {"title": "Sinnerman", "length": 600, "show": "Morning Jazz"},
+                "tags": [null, 1],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tunein() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "<status>200</status>");
+        let (tx, rx) = broadcast::channel(4);
+        tokio::spawn(run(
+            HttpPushConfig::Tunein {
+                name: "tunein-test".to_string(),
+                partner_id: "id".to_string(),
+                partner_key: "key".to_string(),
+                station_id: "s12345".to_string(),
+                url: format!("{}/Playing.ashx", url),
+                min_interval: 0,
+            },
+            rx,
+        ));
+
+        tx.send(spin(2, "Sinnerman")).unwrap();
+        let request = requests.recv().await.unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/Playing.ashx");
+        assert_eq!(request.query["partnerId"], "id");
+        assert_eq!(request.query["partnerKey"], "key");
+        assert_eq!(request.query["id"], "s12345");
+        assert_eq!(request.query["title"], "Sinnerman");
+        assert_eq!(request.query["artist"], "Nina Simone");
+        assert!(!request.query.contains_key("album"));
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "");
+        let (tx, rx) = broadcast::channel(4);
+        tokio::spawn(run(json_config("json-test", url, 0), rx));
+
+        tx.send(spin(2, "Sinnerman")).unwrap();
+        let request = requests.recv().await.unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.authorization.as_deref(), Some("Bearer token"));
+        assert_eq!(
+            serde_json::from_slice::<Value>(&request.body).unwrap(),
+            json!({"title": "Nina Simone - Sinnerman", "seconds": 600})
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(counter("relay_http_push_sent_total", "json-test"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_min_interval() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "");
+        let (tx, rx) = broadcast::channel(4);
+        tokio::spawn(run(json_config("interval-test", url, 1), rx));
+
+        tx.send(spin(1, "Sinnerman")).unwrap();
+        let first = requests.recv().await.unwrap();
+        let pushed_at = Instant::now();
+        tx.send(spin(2, "Feeling Good")).unwrap();
+        tx.send(spin(3, "Four Women")).unwrap();
+        let second = requests.recv().await.unwrap();
+
+        let title = |request: &stand_in::Request| {
+            serde_json::from_slice::<Value>(&request.body).unwrap()["title"].clone()
+        };
+        assert_eq!(title(&first), "Nina Simone - Sinnerman");
+        // The waiting spin is replaced by the newer one
+        assert_eq!(title(&second), "Nina Simone - Four Women");
+        assert!(pushed_at.elapsed() >= Duration::from_millis(900));
+        assert_eq!(counter("relay_http_push_dropped_total", "interval-test"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_errors_counted() {
+        let (url, mut requests) = stand_in::serve(StatusCode::INTERNAL_SERVER_ERROR, "");
+        let (tx, rx) = broadcast::channel(4);
+        tokio::spawn(run(json_config("errors-test", url, 0), rx));
+
+        tx.send(spin(1, "Sinnerman")).unwrap();
+        tx.send(spin(2, "Feeling Good")).unwrap();
+        requests.recv().await.unwrap();
+        requests.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(counter("relay_http_push_errors_total", "errors-test"), 2);
+        assert_eq!(counter("relay_http_push_sent_total", "errors-test"), 0);
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::{sync::broadcast::Receiver, time::Instant};
+
+use crate::config::{MastodonConfig, Visibility};
+use crate::events::Event;
+
+use super::template;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Mastodon's default; some instances allow more
+const STATUS_MAX: usize = 500;
+
+/// Posts show starts and spins as statuses from a Mastodon account. Spins
+/// within `min_interval` of the last post are skipped, while show starts
+/// wait until it's over.
+pub async fn run(config: MastodonConfig, mut events: Receiver<Event>) {
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap();
+    let min_interval = Duration::from_secs(config.min_interval);
+    let mut last_post: Option<Instant> = None;
+    let mut show = None;
+    while let Some(event) = super::next(&mut events).await {
+        let (kind, template, record) = match event {
+            Event::ShowChanged(changed) => {
+                show = Some(changed.clone());
+                if !config.events.shows() {
+                    continue;
+                }
+                ("show", &config.show_template, changed)
+            }
+            Event::SpinChanged(mut spin) => {
+                if !config.events.spins() {
+                    continue;
+                }
+                spin["show"] = show.clone().unwrap_or(Value::Null);
+                ("spin", &config.spin_template, spin)
+            }
+        };
+        if let Some(due) = last_post.map(|last| last + min_interval) {
+            if Instant::now() < due {
+                if kind == "spin" {
+                    debug!(
+                        "Not posting spin {} to {}, too soon",
+                        record["id"], config.url
+                    );
+                    continue;
+                }
+                tokio::time::sleep_until(due).await;
+            }
+        }
+        last_post = Some(Instant::now());
+
+        let status = status(&config, &template::render(template, &record), &record);
+        if config.dry_run {
+            info!("Dry run, not posting to {}: {}", config.url, status);
+            continue;
+        }
+        // Lets the instance drop a post it already has, e.g. after a timeout
+        let key = format!("api-relay-{}-{}", kind, record["id"]);
+        if let Err(e) = post(&client, &config, &status, &key).await {
+            error!("Couldn't post to {}: {}", config.url, e);
+        }
+    }
+}
+
+async fn post(
+    client: &reqwest::Client,
+    config: &MastodonConfig,
+    status: &str,
+    idempotency_key: &str,
+) -> Result<(), reqwest::Error> {
+    client
+        .post(format!(
+            "{}/api/v1/statuses",
+            config.url.trim_end_matches('/')
+        ))
+        .bearer_auth(&config.access_token)
+        .header("Idempotency-Key", idempotency_key)
+        .json(&json!({
+            "status": status,
+            "visibility": visibility(config.visibility),
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    debug!("Posted to {}", config.url);
+    Ok(())
+}
+
+fn visibility(visibility: Visibility) -> &'static str {
+    match visibility {
+        Visibility::Public => "public",
+        Visibility::Unlisted => "unlisted",
+        Visibility::Private => "private",
+        Visibility::Direct => "direct",
+    }
+}
+
+/// `text` followed by the hashtags for `record`, cut to fit in a status.
+pub fn status(config: &MastodonConfig, text: &str, record: &Value) -> String {
+    let tags = hashtags(config, record);
+    if tags.is_empty() {
+        return truncate(text, STATUS_MAX);
+    }
+    let tags = format!("\n\n{}", tags.join(" "));
+    let room = STATUS_MAX.saturating_sub(tags.chars().count());
+    format!("{}{}", truncate(text, room), tags)
+}
+
+// The configured hashtags, then one per value of each hashtag field. Lists
+// (and comma-separated values) give a hashtag for each item.
+fn hashtags(config: &MastodonConfig, record: &Value) -> Vec<String> {
+    let from_fields = config.hashtag_fields.iter().flat_map(|path| {
+        template::field(record, path)
+            .split(',')
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    });
+    let mut seen = HashSet::new();
+    config
+        .hashtags
+        .iter()
+        .cloned()
+        .chain(from_fields)
+        .filter_map(|tag| hashtag(&tag))
+        .filter(|tag| seen.insert(tag.to_lowercase()))
+        .collect()
+}
+
+// "Hip-hop" becomes "#HipHop", while single words are kept as they are.
+// Mastodon takes letters, digits and underscores, and needs at least one
+// letter.
+fn hashtag(text: &str) -> Option<String> {
+    let words: Vec<&str> = text
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .collect();
+    let tag: String = match words[..] {
+        [word] => word.to_string(),
+        _ => words
+            .iter()
+            .map(|word| {
+                let mut chars = word.chars();
+                let first = chars.next().into_iter().flat_map(char::to_uppercase);
+                first.chain(chars).collect::<String>()
+            })
+            .collect(),
+    };
+    tag.chars()
+        .any(char::is_alphabetic)
+        .then(|| format!("#{}", tag))
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use serde_json::{json, Value};
+    use tokio::sync::broadcast;
+    use warp::http::StatusCode;
+
+    use crate::config::{EventFilter, MastodonConfig, Visibility};
+    use crate::events::Event;
+    use crate::sinks::stand_in;
+
+    use super::{run, status};
+
+    fn config(url: String) -> MastodonConfig {
+        MastodonConfig {
+            url,
+            access_token: "token".to_string(),
+            events: EventFilter::All,
+            show_template: "Now on air: {title}".to_string(),
+            spin_template: "{artist} - {song}".to_string(),
+            visibility: Visibility::Unlisted,
+            hashtags: vec![],
+            hashtag_fields: vec![],
+            min_interval: 0,
+            dry_run: false,
+        }
+    }
+
+    fn posted(request: &stand_in::Request) -> Value {
+        serde_json::from_slice(&request.body).unwrap()
+    }
+
+    #[test]
+    fn test_status() {
+        let mut config = config(String::new());
+        config.hashtags = vec!["NowPlaying".to_string(), "#kscu".to_string()];
+        config.hashtag_fields = vec!["genre".to_string(), "show.djs".to_string()];
+        let spin = json!({
+            "genre": "Hip-hop, nowplaying, 1990",
+            "show": {"djs": [{"name": "DJ Cool"}, {"name": "Sam"}]}
+        });
+
+        assert_eq!(
+            status(&config, "Nas - N.Y. State of Mind", &spin),
+            "Nas - N.Y. State of Mind\n\n#NowPlaying #kscu #HipHop #DJCool #Sam"
+        );
+
+        let long = "a".repeat(600);
+        let status = status(&config, &long, &json!({}));
+        assert_eq!(status.chars().count(), 500);
+        assert!(status.ends_with("…\n\n#NowPlaying #kscu"));
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "{}");
+        let (tx, rx) = broadcast::channel(4);
+        tokio::spawn(run(config(url), rx));
+
+        tx.send(Event::SpinChanged(
+            json!({"id": 2, "artist": "Nina Simone", "song": "Sinnerman"}),
+        ))
+        .unwrap();
+        let request = requests.recv().await.unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/v1/statuses");
+        assert_eq!(request.authorization.as_deref(), Some("Bearer token"));
+        assert_eq!(
+            posted(&request),
+            json!({"status": "Nina Simone - Sinnerman", "visibility": "unlisted"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_min_interval() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "{}");
+        let (tx, rx) = broadcast::channel(4);
+        let mut config = config(url);
+        config.min_interval = 1;
+        tokio::spawn(run(config, rx));
+
+        tx.send(Event::ShowChanged(
+            json!({"id": 10, "title": "Morning Jazz"}),
+        ))
+        .unwrap();
+        tx.send(Event::SpinChanged(json!({"id": 2, "song": "Sinnerman"})))
+            .unwrap();
+        tx.send(Event::ShowChanged(json!({"id": 11, "title": "Overnight"})))
+            .unwrap();
+        let first = posted(&requests.recv().await.unwrap());
+        let posted_at = Instant::now();
+        let second = posted(&requests.recv().await.unwrap());
+
+        assert_eq!(first["status"], "Now on air: Morning Jazz");
+        // The spin is skipped and the next show waits
+        assert_eq!(second["status"], "Now on air: Overnight");
+        assert!(posted_at.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "{}");
+        let (tx, rx) = broadcast::channel(4);
+        let mut config = config(url);
+        config.dry_run = true;
+        tokio::spawn(run(config, rx));
+
+        tx.send(Event::ShowChanged(
+            json!({"id": 10, "title": "Morning Jazz"}),
+        ))
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert!(requests.try_recv().is_err());
+    }
+}
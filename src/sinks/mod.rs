@@ -0,0 +1,194 @@
+//! Outputs that push what's playing to other systems. Each configured sink
+//! runs as its own task, acting on events from `events`.
+
+use std::collections::HashSet;
+
+use tokio::sync::broadcast::{self, error::RecvError, Receiver};
+
+use crate::config::SinksConfig;
+use crate::events::{self, Event};
+use crate::models::Db;
+
+mod chat;
+mod http_push;
+mod mastodon;
+mod mqtt;
+mod rds;
+mod scrobble;
+mod stream_metadata;
+pub mod template;
+
+/// Starts every sink in the config. Call after the first fetch from
+/// Spinitron: sinks that show what's on now, like the stream title, are
+/// brought up to date from the caches, while the rest only act on changes.
+pub fn start(config: &SinksConfig, spin_db: &Db, show_db: &Db) {
+    // Subscribed before the caches are read, so a change in between is
+    // seen after the state it replaces rather than lost
+    let live = events::subscribe();
+    let initial = events::current(&spin_db.load().v2.value, &show_db.load().v2.value);
+    let (seeded, _) = broadcast::channel(initial.len() + events::CAPACITY);
+    for sink in &config.stream_metadata {
+        info!("Updating stream metadata at {}", sink.url());
+        tokio::spawn(stream_metadata::run(sink.clone(), seeded.subscribe()));
+    }
+    for sink in &config.rds {
+        info!("Sending RDS to {:?}", sink.target);
+        tokio::spawn(rds::run(sink.clone(), seeded.subscribe()));
+    }
+    for sink in &config.listenbrainz {
+        info!("Submitting listens to ListenBrainz at {}", sink.url);
+        let service = scrobble::Service::ListenBrainz(sink.clone());
+        tokio::spawn(scrobble::run(service, events::subscribe()));
+    }
+    for sink in &config.lastfm {
+        info!("Scrobbling to Last.fm at {}", sink.url);
+        let service = scrobble::Service::LastFm(sink.clone());
+        tokio::spawn(scrobble::run(service, events::subscribe()));
+    }
+    for sink in &config.chat {
+        let limit = sink.rate_limit;
+        if limit.per_second <= 0.0 || limit.burst == 0 {
+            panic!("Rate limit for a chat sink must have a positive per_second and burst.");
+        }
+        info!("Posting to a {:?} webhook", sink.service);
+        tokio::spawn(chat::run(sink.clone(), events::subscribe()));
+    }
+    for sink in &config.mastodon {
+        match sink.dry_run {
+            true => info!("Logging posts for {} (dry run)", sink.url),
+            false => info!("Posting to {}", sink.url),
+        }
+        tokio::spawn(mastodon::run(sink.clone(), events::subscribe()));
+    }
+    for sink in &config.mqtt {
+        let options = mqtt::options(sink).unwrap_or_else(|e| {
+            panic!(
+                "Couldn't read MQTT CA certificates from {:?}: {}",
+                sink.ca_path, e
+            )
+        });
+        info!(
+            "Publishing to MQTT broker {}:{}",
+            sink.broker.host, sink.broker.port
+        );
+        tokio::spawn(mqtt::run(sink.clone(), options, seeded.subscribe()));
+    }
+    if seeded.receiver_count() > 0 {
+        forward(initial, live, seeded);
+    }
+    let mut names = HashSet::new();
+    for sink in &config.http_push {
+        // They'd share counters
+        if !names.insert(sink.name()) {
+            panic!("More than one HTTP push sink is named \"{}\".", sink.name());
+        }
+        info!("Pushing spins to {}", sink.name());
+        tokio::spawn(http_push::run(sink.clone(), events::subscribe()));
+    }
+}
+
+/// Sends `initial` to `seeded`'s receivers, then every event `live` gets.
+fn forward(initial: Vec<Event>, mut live: Receiver<Event>, seeded: broadcast::Sender<Event>) {
+    for event in initial {
+        let _ = seeded.send(event);
+    }
+    tokio::spawn(async move {
+        while let Some(event) = next(&mut live).await {
+            // Fails once every receiver is gone
+            if seeded.send(event).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// The next event, or None once no more can arrive. A sink that falls
+/// behind skips the events it missed.
+async fn next(events: &mut Receiver<Event>) -> Option<Event> {
+    loop {
+        match events.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(missed)) => {
+                warn!("A sink fell behind and missed {} events", missed)
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// A local HTTP server standing in for the services sinks talk to.
+#[cfg(test)]
+pub mod stand_in {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use bytes::Bytes;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+    use warp::{http::StatusCode, path::FullPath, Filter};
+
+    #[derive(Debug)]
+    pub struct Request {
+        pub method: String,
+        pub path: String,
+        pub query: HashMap<String, String>,
+        pub authorization: Option<String>,
+        pub body: Bytes,
+    }
+
+    /// Starts a server answering every request with `status` and `response`.
+    /// Returns its base URL and the requests it receives.
+    pub fn serve(
+        status: StatusCode,
+        response: &'static str,
+    ) -> (String, UnboundedReceiver<Request>) {
+        serve_statuses(vec![status], response)
+    }
+
+    /// Like `serve`, answering the first request with the first status, the
+    /// second with the second, and so on. The last one is repeated.
+    pub fn serve_statuses(
+        statuses: Vec<StatusCode>,
+        response: &'static str,
+    ) -> (String, UnboundedReceiver<Request>) {
+        serve_with(move |_, n| (statuses[n.min(statuses.len() - 1)], response.to_string()))
+    }
+
+    /// Starts a server answering the `n`th request (from 0) with whatever
+    /// `respond` returns for it.
+    pub fn serve_with<F, R>(respond: F) -> (String, UnboundedReceiver<Request>)
+    where
+        F: Fn(&Request, usize) -> (StatusCode, R) + Clone + Send + Sync + 'static,
+        R: warp::Reply + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let answered = Arc::new(AtomicUsize::new(0));
+        let route = warp::method()
+            .and(warp::path::full())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::bytes())
+            .map(
+                move |method: warp::http::Method, path: FullPath, query, authorization, body| {
+                    let request = Request {
+                        method: method.to_string(),
+                        path: path.as_str().to_string(),
+                        query,
+                        authorization,
+                        body,
+                    };
+                    let n = answered.fetch_add(1, Ordering::Relaxed);
+                    let (status, response) = respond(&request, n);
+                    let _ = tx.send(request);
+                    warp::reply::with_status(response, status)
+                },
+            );
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", addr), rx)
+    }
+}
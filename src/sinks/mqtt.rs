@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, ConnectionError, EventLoop, MqttOptions, Packet, QoS, Transport};
+use tokio::sync::broadcast::Receiver;
+
+use crate::config::{MqttConfig, MqttQos};
+use crate::events::Event;
+
+// Messages held for the broker while it's unreachable. Publishing waits
+// when it's full, and the sink skips what it falls behind on, so the newest
+// spin and show always make it.
+const CAPACITY: usize = 16;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The connection options for `config`. Fails if the CA certificates can't
+/// be read.
+pub fn options(config: &MqttConfig) -> std::io::Result<MqttOptions> {
+    let broker = &config.broker;
+    let mut options = MqttOptions::new(&config.client_id, &broker.host, broker.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    if broker.tls {
+        options.set_transport(match &config.ca_path {
+            Some(path) => Transport::tls(std::fs::read(path)?, None, None),
+            None => Transport::tls_with_default_config(),
+        });
+    }
+    Ok(options)
+}
+
+/// Publishes each new spin and show, as JSON, to retained topics under the
+/// config's prefix, so devices get the current ones as soon as they
+/// subscribe.
+pub async fn run(config: MqttConfig, options: MqttOptions, mut events: Receiver<Event>) {
+    let (client, eventloop) = AsyncClient::new(options, CAPACITY);
+    tokio::spawn(connect(eventloop, config.broker.host.clone()));
+    let qos = match config.qos {
+        MqttQos(0) => QoS::AtMostOnce,
+        MqttQos(1) => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    };
+    let prefix = config.topic_prefix.trim_end_matches('/');
+    while let Some(event) = super::next(&mut events).await {
+        let (topic, record) = match event {
+            Event::SpinChanged(spin) => (format!("{}/now/spin", prefix), spin),
+            Event::ShowChanged(show) => (format!("{}/now/show", prefix), show),
+        };
+        if let Err(e) = client.publish(&topic, qos, true, record.to_string()).await {
+            error!("Couldn't publish to {}: {}", topic, e);
+        }
+    }
+}
+
+// Keeps the connection up, reconnecting after a delay when it drops, until
+// the client is dropped
+async fn connect(mut eventloop: EventLoop, broker: String) {
+    let mut connected = false;
+    loop {
+        match eventloop.poll().await {
+            Ok(rumqttc::Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}", broker);
+                connected = true;
+            }
+            Ok(_) => {}
+            Err(ConnectionError::RequestsDone) => return,
+            Err(e) => {
+                // Logged once per outage, not on every attempt
+                if connected {
+                    error!("Lost connection to MQTT broker {}: {}", broker, e);
+                } else {
+                    debug!("Couldn't connect to MQTT broker {}: {}", broker, e);
+                }
+                connected = false;
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::{
+            broadcast,
+            mpsc::{self, UnboundedReceiver, UnboundedSender},
+        },
+    };
+
+    use crate::config::{MqttBroker, MqttConfig, MqttQos};
+    use crate::events::Event;
+
+    use super::{options, run};
+
+    #[derive(Debug)]
+    struct Publish {
+        topic: String,
+        qos: u8,
+        retain: bool,
+        payload: Value,
+    }
+
+    /// Just enough of an MQTT 3.1.1 broker to take connections and
+    /// publishes, acknowledging them at any QoS.
+    async fn broker() -> (u16, UnboundedReceiver<Publish>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(session(stream, tx.clone()));
+            }
+        });
+        (port, rx)
+    }
+
+    async fn session(mut stream: TcpStream, publishes: UnboundedSender<Publish>) {
+        while let Ok(header) = stream.read_u8().await {
+            // Remaining length: 7 bits a byte, least significant first
+            let (mut length, mut shift) = (0usize, 0);
+            loop {
+                let byte = stream.read_u8().await.unwrap();
+                length |= ((byte & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await.unwrap();
+
+            let reply = match header >> 4 {
+                // CONNECT
+                1 => vec![0x20, 0x02, 0x00, 0x00],
+                // PUBLISH
+                3 => {
+                    let qos = (header >> 1) & 0x03;
+                    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+                    let mut rest = &body[2 + topic_len..];
+                    let id = if qos > 0 {
+                        let id = [rest[0], rest[1]];
+                        rest = &rest[2..];
+                        id
+                    } else {
+                        [0, 0]
+                    };
+                    let _ = publishes.send(Publish {
+                        topic,
+                        qos,
+                        retain: header & 0x01 == 1,
+                        payload: serde_json::from_slice(rest).unwrap(),
+                    });
+                    match qos {
+                        0 => vec![],
+                        // PUBACK
+                        1 => vec![0x40, 0x02, id[0], id[1]],
+                        // PUBREC
+                        _ => vec![0x50, 0x02, id[0], id[1]],
+                    }
+                }
+                // PUBREL, answered with PUBCOMP
+                6 => vec![0x70, 0x02, body[0], body[1]],
+                // PINGREQ
+                12 => vec![0xd0, 0x00],
+                _ => return,
+            };
+            stream.write_all(&reply).await.unwrap();
+        }
+    }
+
+    fn config(port: u16, qos: u8) -> MqttConfig {
+        MqttConfig {
+            broker: MqttBroker {
+                host: "127.0.0.1".to_string(),
+                port,
+                tls: false,
+            },
+            client_id: "api-relay-test".to_string(),
+            username: Some("relay".to_string()),
+            password: Some("hackme".to_string()),
+            qos: MqttQos(qos),
+            topic_prefix: "kscu/".to_string(),
+            ca_path: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        for qos in 0..=2 {
+            let (port, mut publishes) = broker().await;
+            let (tx, rx) = broadcast::channel(4);
+            let config = config(port, qos);
+            let options = options(&config).unwrap();
+            tokio::spawn(run(config, options, rx));
+
+            let spin = json!({"id": 2, "artist": "Nina Simone", "song": "Sinnerman"});
+            tx.send(Event::SpinChanged(spin.clone())).unwrap();
+            tx.send(Event::ShowChanged(json!({"id": 10}))).unwrap();
+            let first = publishes.recv().await.unwrap();
+            let second = publishes.recv().await.unwrap();
+
+            assert_eq!(first.topic, "kscu/now/spin");
+            assert_eq!(first.qos, qos);
+            assert!(first.retain);
+            assert_eq!(first.payload, spin);
+            assert_eq!(second.topic, "kscu/now/show");
+            assert_eq!(second.payload, json!({"id": 10}));
+        }
+    }
+}
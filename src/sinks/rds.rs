@@ -0,0 +1,317 @@
+use std::{ops::Range, time::Duration};
+
+use serde_json::Value;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+    sync::broadcast::Receiver,
+    time::timeout,
+};
+
+use crate::config::{RdsConfig, RdsTarget};
+use crate::events::Event;
+
+use super::template;
+
+// Limits set by the RDS standard
+const RT_MAX: usize = 64;
+const PS_MAX: usize = 8;
+// The second RT+ tag's length field is only 5 bits, and holds the length
+// minus one
+const RT_PLUS_SECOND_MAX_LEN: usize = 32;
+
+// RT+ content types
+const RT_PLUS_DUMMY: u8 = 0;
+const RT_PLUS_TITLE: u8 = 1;
+const RT_PLUS_ARTIST: u8 = 4;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What's sent to the encoder.
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub ps: Option<String>,
+    pub rt: String,
+    // "04,00,09,01,13,08": content type, start and length minus one of the
+    // artist and the title in `rt`, as most encoders take it
+    pub rt_plus: String,
+}
+
+impl Message {
+    // One encoder command per line
+    fn commands(&self) -> String {
+        let mut commands = String::new();
+        if let Some(ps) = &self.ps {
+            commands.push_str(&format!("PS={}\r\n", ps));
+        }
+        commands.push_str(&format!("RT={}\r\nRTP={}\r\n", self.rt, self.rt_plus));
+        commands
+    }
+}
+
+/// Sends RadioText (with RT+ tags) and optionally PS to an RDS encoder
+/// whenever the spin or show changes.
+pub async fn run(config: RdsConfig, mut events: Receiver<Event>) {
+    let (mut spin, mut show) = (None, None);
+    while let Some(event) = super::next(&mut events).await {
+        match event {
+            Event::SpinChanged(changed) => spin = Some(changed),
+            Event::ShowChanged(changed) => show = Some(changed),
+        }
+        let message = format(&config, spin.as_ref(), show.as_ref());
+        if let Err(e) = send(&config.target, &message).await {
+            error!("Couldn't send RDS to {:?}: {}", config.target, e);
+        }
+    }
+}
+
+/// Formats the spin and show for the encoder. Before any spin is known, the
+/// RadioText comes from `idle_template`.
+pub fn format(config: &RdsConfig, spin: Option<&Value>, show: Option<&Value>) -> Message {
+    // Spin fields at the top level, the show's under "show"
+    let mut record = spin
+        .cloned()
+        .unwrap_or_else(|| Value::Object(Default::default()));
+    record["show"] = show.cloned().unwrap_or(Value::Null);
+
+    let rt_template = match spin {
+        Some(_) => &config.template,
+        None => &config.idle_template,
+    };
+    let (rt, spans) = template::render_with_spans(rt_template, &record);
+    let rt = truncate(&without_controls(&rt), RT_MAX);
+    let tag = |field: &str, content_type: u8| {
+        spans
+            .iter()
+            .find(|(path, _)| path == field)
+            .and_then(|(_, span)| rt_plus_tag(content_type, span))
+    };
+    let mut tags = [tag("artist", RT_PLUS_ARTIST), tag("song", RT_PLUS_TITLE)];
+    // Only the first tag can be longer than 32 characters. Both can't be, as
+    // RadioText is 64 at most, but the second is clamped all the same.
+    let len = |tag: &Option<RtPlusTag>| tag.as_ref().map_or(0, |tag| tag.len);
+    if len(&tags[1]) > RT_PLUS_SECOND_MAX_LEN && len(&tags[1]) > len(&tags[0]) {
+        tags.swap(0, 1);
+    }
+    if let Some(second) = &mut tags[1] {
+        second.len = second.len.min(RT_PLUS_SECOND_MAX_LEN);
+    }
+    let rt_plus = tags
+        .iter()
+        .map(|tag| match tag {
+            Some(tag) => format!(
+                "{:02},{:02},{:02}",
+                tag.content_type,
+                tag.start,
+                tag.len - 1
+            ),
+            None => format!("{:02},00,00", RT_PLUS_DUMMY),
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let ps = config.ps_template.as_ref().map(|ps| {
+        truncate(
+            without_controls(&template::render(ps, &record)).trim(),
+            PS_MAX,
+        )
+    });
+    Message { ps, rt, rt_plus }
+}
+
+struct RtPlusTag {
+    content_type: u8,
+    start: usize,
+    len: usize,
+}
+
+// Tags the part of the span that survived truncation. Empty spans aren't
+// tagged at all.
+fn rt_plus_tag(content_type: u8, span: &Range<usize>) -> Option<RtPlusTag> {
+    let end = span.end.min(RT_MAX);
+    if span.start >= end {
+        return None;
+    }
+    Some(RtPlusTag {
+        content_type,
+        start: span.start,
+        len: end - span.start,
+    })
+}
+
+// Each command is a line, so a CR or LF from Spinitron would start another.
+// Replaced rather than removed so the RT+ spans still line up.
+fn without_controls(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    text.chars().take(max).collect()
+}
+
+async fn send(target: &RdsTarget, message: &Message) -> std::io::Result<()> {
+    match target {
+        RdsTarget::Tcp(addr) => {
+            let mut stream = timeout(TIMEOUT, TcpStream::connect(addr)).await??;
+            stream.write_all(message.commands().as_bytes()).await?;
+            stream.shutdown().await
+        }
+        RdsTarget::Udp(addr) => {
+            let addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "no address found")
+            })?;
+            let local = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(local).await?;
+            socket
+                .send_to(message.commands().as_bytes(), addr)
+                .await
+                .map(|_| ())
+        }
+        // Encoders that poll a file take just the RadioText. It's replaced
+        // whole so they never read it half written.
+        RdsTarget::File(path) => {
+            let partial = path.with_file_name(format!(
+                ".{}.partial",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ));
+            tokio::fs::write(&partial, format!("{}\n", message.rt)).await?;
+            tokio::fs::rename(&partial, path).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use serde_json::json;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, UdpSocket},
+        sync::broadcast,
+    };
+
+    use crate::config::{RdsConfig, RdsTarget};
+    use crate::events::Event;
+
+    use super::{format, run, Message};
+
+    fn config(target: RdsTarget) -> RdsConfig {
+        RdsConfig {
+            target,
+            template: "{artist} - {song}".to_string(),
+            idle_template: "{show.title}".to_string(),
+            ps_template: Some("KSCU".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let config = config(RdsTarget::File("rt.txt".into()));
+        let spin = json!({"artist": "Nina Simone", "song": "Sinnerman"});
+        let show = json!({"title": "Morning Jazz"});
+
+        assert_eq!(
+            format(&config, Some(&spin), Some(&show)),
+            Message {
+                ps: Some("KSCU".to_string()),
+                rt: "Nina Simone - Sinnerman".to_string(),
+                rt_plus: "04,00,10,01,14,08".to_string(),
+            }
+        );
+        assert_eq!(format(&config, None, Some(&show)).rt, "Morning Jazz");
+        assert_eq!(
+            format(&config, None, Some(&show)).rt_plus,
+            "00,00,00,00,00,00"
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut config = config(RdsTarget::File("rt.txt".into()));
+        config.ps_template = Some("{show.title}".to_string());
+        let spin = json!({
+            "artist": "Godspeed You! Black Emperor",
+            "song": "Storm: Lift Yr. Skinny Fists Like Antennas to Heaven"
+        });
+        let show = json!({"title": "Morning Jazz"});
+        let message = format(&config, Some(&spin), Some(&show));
+
+        assert_eq!(message.rt.chars().count(), 64);
+        assert_eq!(message.ps.unwrap(), "Morning ");
+        // The title starts at 30 and is cut off after 34 characters, too long
+        // for the second tag, so it goes first
+        assert_eq!(message.rt_plus, "01,30,33,04,00,26");
+
+        let spin = json!({
+            "artist": "Godspeed You! Black Emperor and Friends of the Band",
+            "song": "Storm: Lift Yr. Skinny Fists"
+        });
+        let message = format(&config, Some(&spin), Some(&show));
+        // The artist is the longer, so it stays first
+        assert_eq!(message.rt_plus, "04,00,50,01,54,09");
+    }
+
+    #[test]
+    fn test_control_characters() {
+        let config = config(RdsTarget::File("rt.txt".into()));
+        let spin = json!({"artist": "Sun Ra\r\nPS=HACKED", "song": "Love\nin Outer Space"});
+        let message = format(&config, Some(&spin), None);
+
+        assert_eq!(message.rt, "Sun Ra  PS=HACKED - Love in Outer Space");
+        assert_eq!(message.commands().matches("\r\n").count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_and_udp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (tx, rx) = broadcast::channel(4);
+        let tcp_target = RdsTarget::Tcp(listener.local_addr().unwrap().to_string());
+        let udp_target = RdsTarget::Udp(udp.local_addr().unwrap().to_string());
+        tokio::spawn(run(config(tcp_target), rx));
+        tokio::spawn(run(config(udp_target), tx.subscribe()));
+
+        tx.send(Event::SpinChanged(
+            json!({"artist": "Sun Ra", "song": "Love in Outer Space"}),
+        ))
+        .unwrap();
+
+        let expected = "PS=KSCU\r\nRT=Sun Ra - Love in Outer Space\r\nRTP=04,00,05,01,09,18\r\n";
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+
+        let mut datagram = [0; 512];
+        let len = udp.recv(&mut datagram).await.unwrap();
+        assert_eq!(&datagram[..len], expected.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_file() {
+        let path = env::temp_dir().join(format!("relay-rds-{}.txt", process::id()));
+        let (tx, rx) = broadcast::channel(4);
+        tokio::spawn(run(config(RdsTarget::File(path.clone())), rx));
+
+        tx.send(Event::ShowChanged(json!({"title": "Morning Jazz"})))
+            .unwrap();
+        let mut contents = String::new();
+        for _ in 0..100 {
+            contents = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+            if !contents.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(contents, "Morning Jazz\n");
+    }
+}
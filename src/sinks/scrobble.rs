@@ -0,0 +1,460 @@
+use std::{collections::VecDeque, path::Path, time::Duration};
+
+use chrono::DateTime;
+use md5::{Digest, Md5};
+use serde_json::{json, Value};
+use tokio::{sync::broadcast::Receiver, time::Instant};
+
+use crate::config::{LastFmConfig, ListenBrainzConfig};
+use crate::events::Event;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Waits between attempts while a service is failing, doubling up to the max
+const RETRY_MIN: Duration = Duration::from_secs(30);
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+
+// Listens kept while a service is down; the oldest are dropped past this
+const MAX_QUEUED: usize = 500;
+
+// Listens sent per request. Last.fm takes at most 50.
+const BATCH: usize = 50;
+
+// Spin IDs remembered so a spin is never submitted twice
+const REMEMBERED: usize = 100;
+
+/// A spin to submit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listen {
+    pub spin_id: String,
+    pub artist: String,
+    pub track: String,
+    pub album: Option<String>,
+    // Unix time the spin started
+    pub timestamp: i64,
+}
+
+impl Listen {
+    /// None for spins missing an artist, song or start time.
+    pub fn from_spin(spin: &Value) -> Option<Listen> {
+        let text = |field: &str| {
+            spin[field]
+                .as_str()
+                .filter(|text| !text.is_empty())
+                .map(str::to_string)
+        };
+        Some(Listen {
+            spin_id: spin["id"].to_string(),
+            artist: text("artist")?,
+            track: text("song")?,
+            album: text("release"),
+            timestamp: DateTime::parse_from_rfc3339(spin["start"].as_str()?)
+                .ok()?
+                .timestamp(),
+        })
+    }
+}
+
+pub enum Service {
+    ListenBrainz(ListenBrainzConfig),
+    LastFm(LastFmConfig),
+}
+
+// Why a submission failed
+#[derive(Debug)]
+enum Failure {
+    // Worth trying again later, e.g. the service is down
+    Retry(String),
+    // Won't ever succeed, e.g. the service rejected the data
+    Drop(String),
+}
+
+impl Service {
+    fn name(&self) -> &'static str {
+        match self {
+            Service::ListenBrainz(_) => "ListenBrainz",
+            Service::LastFm(_) => "Last.fm",
+        }
+    }
+
+    fn state_path(&self) -> Option<&Path> {
+        match self {
+            Service::ListenBrainz(config) => config.state_path.as_deref(),
+            Service::LastFm(config) => config.state_path.as_deref(),
+        }
+    }
+
+    async fn submit(&self, client: &reqwest::Client, listens: &[Listen]) -> Result<(), Failure> {
+        let request = match self {
+            Service::ListenBrainz(config) => client
+                .post(format!(
+                    "{}/1/submit-listens",
+                    config.url.trim_end_matches('/')
+                ))
+                .header("Authorization", format!("Token {}", config.token))
+                .json(&listenbrainz_payload(listens)),
+            Service::LastFm(config) => client
+                .post(format!("{}/2.0/", config.url.trim_end_matches('/')))
+                .form(&lastfm_params(config, listens)),
+        };
+        let resp = request
+            .send()
+            .await
+            .map_err(|e| Failure::Retry(e.to_string()))?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = format!("{}: {}", status, resp.text().await.unwrap_or_default());
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Retry(message))
+        } else {
+            Err(Failure::Drop(message))
+        }
+    }
+}
+
+fn listenbrainz_payload(listens: &[Listen]) -> Value {
+    let payload: Vec<Value> = listens
+        .iter()
+        .map(|listen| {
+            let mut metadata = json!({
+                "artist_name": listen.artist,
+                "track_name": listen.track,
+            });
+            if let Some(album) = &listen.album {
+                metadata["release_name"] = json!(album);
+            }
+            json!({"listened_at": listen.timestamp, "track_metadata": metadata})
+        })
+        .collect();
+    // "single" is for exactly one listen
+    let listen_type = if listens.len() == 1 {
+        "single"
+    } else {
+        "import"
+    };
+    json!({"listen_type": listen_type, "payload": payload})
+}
+
+fn lastfm_params(config: &LastFmConfig, listens: &[Listen]) -> Vec<(String, String)> {
+    let mut params = vec![
+        ("method".to_string(), "track.scrobble".to_string()),
+        ("api_key".to_string(), config.api_key.clone()),
+        ("sk".to_string(), config.session_key.clone()),
+    ];
+    for (i, listen) in listens.iter().enumerate() {
+        params.push((format!("artist[{}]", i), listen.artist.clone()));
+        params.push((format!("track[{}]", i), listen.track.clone()));
+        params.push((format!("timestamp[{}]", i), listen.timestamp.to_string()));
+        if let Some(album) = &listen.album {
+            params.push((format!("album[{}]", i), album.clone()));
+        }
+    }
+    let signature = lastfm_signature(&params, &config.api_secret);
+    params.push(("api_sig".to_string(), signature));
+    params.push(("format".to_string(), "json".to_string()));
+    params
+}
+
+/// Last.fm's request signature: the MD5 of every parameter's name and value
+/// in name order, then the shared secret.
+fn lastfm_signature(params: &[(String, String)], secret: &str) -> String {
+    let mut sorted: Vec<&(String, String)> = params.iter().collect();
+    sorted.sort();
+    let mut hasher = Md5::new();
+    for (name, value) in sorted {
+        hasher.update(name.as_bytes());
+        hasher.update(value.as_bytes());
+    }
+    hasher.update(secret.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Submits each new spin to a scrobbling service. Spins are queued while the
+/// service is failing and retried with backoff.
+pub async fn run(service: Service, events: Receiver<Event>) {
+    run_with_retry(service, events, RETRY_MIN).await
+}
+
+async fn run_with_retry(service: Service, mut events: Receiver<Event>, retry_min: Duration) {
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap();
+    let mut submitted = load_submitted(service.state_path());
+    let mut queue: VecDeque<Listen> = VecDeque::new();
+    let mut retry = retry_min;
+    // While the service is failing, nothing is submitted before this
+    let mut retry_at = Instant::now();
+    loop {
+        tokio::select! {
+            event = super::next(&mut events) => match event {
+                Some(Event::SpinChanged(spin)) => {
+                    let Some(listen) = Listen::from_spin(&spin) else {
+                        continue;
+                    };
+                    let duplicate = submitted.contains(&listen.spin_id)
+                        || queue.iter().any(|queued| queued.spin_id == listen.spin_id);
+                    if duplicate {
+                        continue;
+                    }
+                    queue.push_back(listen);
+                    if queue.len() > MAX_QUEUED {
+                        warn!("{} queue is full, dropping the oldest listen", service.name());
+                        queue.pop_front();
+                    }
+                }
+                Some(_) => continue,
+                None => return,
+            },
+            _ = tokio::time::sleep_until(retry_at), if !queue.is_empty() => {}
+        }
+        // A spin arriving while backing off waits with the rest of the queue
+        if Instant::now() < retry_at {
+            continue;
+        }
+
+        while !queue.is_empty() {
+            let batch: Vec<Listen> = queue.iter().take(BATCH).cloned().collect();
+            match service.submit(&client, &batch).await {
+                Err(Failure::Retry(e)) => {
+                    error!(
+                        "Couldn't submit listens to {}, retrying in {:?}: {}",
+                        service.name(),
+                        retry,
+                        e
+                    );
+                    retry_at = Instant::now() + retry;
+                    retry = (retry * 2).min(RETRY_MAX);
+                    break;
+                }
+                result => {
+                    if let Err(Failure::Drop(e)) = result {
+                        error!("{} rejected {} listens: {}", service.name(), batch.len(), e);
+                    }
+                    for listen in queue.drain(..batch.len()) {
+                        submitted.push_back(listen.spin_id);
+                    }
+                    while submitted.len() > REMEMBERED {
+                        submitted.pop_front();
+                    }
+                    save_submitted(service.state_path(), &submitted);
+                    retry = retry_min;
+                }
+            }
+        }
+    }
+}
+
+// Spin IDs already submitted, kept in the state file across restarts
+fn load_submitted(path: Option<&Path>) -> VecDeque<String> {
+    let Some(path) = path else {
+        return VecDeque::new();
+    };
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring invalid scrobble state {}: {}", path.display(), e);
+            VecDeque::new()
+        }),
+        Err(_) => VecDeque::new(),
+    }
+}
+
+fn save_submitted(path: Option<&Path>, submitted: &VecDeque<String>) {
+    if let Some(path) = path {
+        let contents = serde_json::to_string(submitted).unwrap();
+        if let Err(e) = std::fs::write(path, contents) {
+            error!("Couldn't save scrobble state {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, process,
+        time::{Duration, Instant},
+    };
+
+    use serde_json::json;
+    use tokio::sync::broadcast;
+    use warp::http::StatusCode;
+
+    use crate::config::{LastFmConfig, ListenBrainzConfig};
+    use crate::events::Event;
+    use crate::sinks::stand_in;
+
+    use super::{lastfm_signature, run_with_retry, Listen, Service};
+
+    fn spin(id: u64, song: &str) -> serde_json::Value {
+        json!({
+            "id": id,
+            "artist": "Nina Simone",
+            "song": song,
+            "release": "Pastel Blues",
+            "start": "2024-03-01T12:03:00-08:00"
+        })
+    }
+
+    #[test]
+    fn test_listen() {
+        assert_eq!(
+            Listen::from_spin(&spin(2, "Sinnerman")),
+            Some(Listen {
+                spin_id: "2".to_string(),
+                artist: "Nina Simone".to_string(),
+                track: "Sinnerman".to_string(),
+                album: Some("Pastel Blues".to_string()),
+                timestamp: 1709323380,
+            })
+        );
+        assert_eq!(
+            Listen::from_spin(&json!({"id": 3, "song": "Untitled"})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lastfm_signature() {
+        let params = vec![
+            ("method".to_string(), "track.scrobble".to_string()),
+            ("api_key".to_string(), "key".to_string()),
+        ];
+        // md5("api_keykeymethodtrack.scrobblesecret")
+        assert_eq!(
+            lastfm_signature(&params, "secret"),
+            "d7a2d80e182cf1fea315ddc2d0bbfe44"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_listenbrainz_retries_and_dedupes() {
+        let state_path = env::temp_dir().join(format!("relay-scrobble-{}.json", process::id()));
+        let (url, mut requests) = stand_in::serve_statuses(
+            vec![StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK],
+            r#"{"status": "ok"}"#,
+        );
+        let service = Service::ListenBrainz(ListenBrainzConfig {
+            token: "token".to_string(),
+            url,
+            state_path: Some(state_path.clone()),
+        });
+        let (tx, rx) = broadcast::channel(8);
+        tokio::spawn(run_with_retry(service, rx, Duration::from_millis(50)));
+
+        tx.send(Event::SpinChanged(spin(2, "Sinnerman"))).unwrap();
+        let failed = requests.recv().await.unwrap();
+        let retried = requests.recv().await.unwrap();
+
+        assert_eq!(failed.body, retried.body);
+        assert_eq!(retried.path, "/1/submit-listens");
+        assert_eq!(retried.authorization.unwrap(), "Token token");
+        let body: serde_json::Value = serde_json::from_slice(&retried.body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "listen_type": "single",
+                "payload": [{
+                    "listened_at": 1709323380,
+                    "track_metadata": {
+                        "artist_name": "Nina Simone",
+                        "track_name": "Sinnerman",
+                        "release_name": "Pastel Blues"
+                    }
+                }]
+            })
+        );
+
+        // Already submitted, so only the new spin goes out
+        tx.send(Event::SpinChanged(spin(2, "Sinnerman"))).unwrap();
+        tx.send(Event::SpinChanged(spin(3, "Feeling Good")))
+            .unwrap();
+        let next = requests.recv().await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&next.body).unwrap();
+        assert_eq!(
+            body["payload"][0]["track_metadata"]["track_name"],
+            "Feeling Good"
+        );
+
+        // Saved once the submission's been answered
+        let mut state = String::new();
+        for _ in 0..100 {
+            state = std::fs::read_to_string(&state_path).unwrap_or_default();
+            if state.contains("3") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_file(&state_path).unwrap();
+        assert_eq!(state, r#"["2","3"]"#);
+    }
+
+    #[tokio::test]
+    async fn test_new_spin_waits_out_backoff() {
+        let (url, mut requests) = stand_in::serve_statuses(
+            vec![StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK],
+            r#"{"status": "ok"}"#,
+        );
+        let service = Service::ListenBrainz(ListenBrainzConfig {
+            token: "token".to_string(),
+            url,
+            state_path: None,
+        });
+        let (tx, rx) = broadcast::channel(8);
+        tokio::spawn(run_with_retry(service, rx, Duration::from_millis(300)));
+
+        tx.send(Event::SpinChanged(spin(2, "Sinnerman"))).unwrap();
+        requests.recv().await.unwrap();
+        let failed_at = Instant::now();
+        tx.send(Event::SpinChanged(spin(3, "Feeling Good")))
+            .unwrap();
+        let retried = requests.recv().await.unwrap();
+
+        // Sent together once the backoff is over, not as soon as the spin came
+        assert!(failed_at.elapsed() >= Duration::from_millis(250));
+        let body: serde_json::Value = serde_json::from_slice(&retried.body).unwrap();
+        assert_eq!(body["payload"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_lastfm() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, r#"{"scrobbles": {}}"#);
+        let service = Service::LastFm(LastFmConfig {
+            api_key: "key".to_string(),
+            api_secret: "secret".to_string(),
+            session_key: "session".to_string(),
+            url,
+            state_path: None,
+        });
+        let (tx, rx) = broadcast::channel(8);
+        tokio::spawn(run_with_retry(service, rx, Duration::from_millis(50)));
+
+        tx.send(Event::SpinChanged(spin(2, "Sinnerman"))).unwrap();
+        let request = requests.recv().await.unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/2.0/");
+        let body = String::from_utf8(request.body.to_vec()).unwrap();
+        let form: Vec<(String, String)> = reqwest::Url::parse(&format!("http://relay/?{}", body))
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+        let param = |name: &str| {
+            form.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(param("method"), Some("track.scrobble"));
+        assert_eq!(param("artist[0]"), Some("Nina Simone"));
+        assert_eq!(param("timestamp[0]"), Some("1709323380"));
+        assert_eq!(param("sk"), Some("session"));
+        let signed: Vec<(String, String)> = form
+            .iter()
+            .filter(|(key, _)| key != "api_sig" && key != "format")
+            .cloned()
+            .collect();
+        assert_eq!(
+            param("api_sig"),
+            Some(lastfm_signature(&signed, "secret").as_str())
+        );
+    }
+}
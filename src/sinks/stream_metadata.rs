@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::sync::broadcast::Receiver;
+
+use crate::config::StreamMetadataConfig;
+use crate::events::Event;
+
+use super::template;
+
+// Streaming servers answer at once or not at all
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Shoutcast 2 always takes the admin password as user "admin"
+const SHOUTCAST_ADMIN: &str = "admin";
+
+/// Sets the stream title on an Icecast or Shoutcast server to each new spin.
+pub async fn run(config: StreamMetadataConfig, mut events: Receiver<Event>) {
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build().unwrap();
+    while let Some(event) = super::next(&mut events).await {
+        if let Event::SpinChanged(spin) = event {
+            if let Err(e) = update(&client, &config, &spin).await {
+                error!("Couldn't update stream metadata at {}: {}", config.url(), e);
+            }
+        }
+    }
+}
+
+async fn update(
+    client: &reqwest::Client,
+    config: &StreamMetadataConfig,
+    spin: &Value,
+) -> Result<(), reqwest::Error> {
+    let request = match config {
+        StreamMetadataConfig::Icecast {
+            url,
+            mount,
+            username,
+            password,
+            template,
+        } => client
+            .get(format!("{}/admin/metadata", url.trim_end_matches('/')))
+            .query(&[
+                ("mount", mount.as_str()),
+                ("mode", "updinfo"),
+                ("song", &template::render(template, spin)),
+                ("charset", "UTF-8"),
+            ])
+            .basic_auth(username, Some(password)),
+        // Shoutcast 1 has a single stream and takes the password in the query
+        StreamMetadataConfig::Shoutcast {
+            url,
+            password,
+            sid: None,
+            template,
+        } => client
+            .get(format!("{}/admin.cgi", url.trim_end_matches('/')))
+            .query(&[
+                ("mode", "updinfo"),
+                ("song", &template::render(template, spin)),
+                ("pass", password.as_str()),
+            ]),
+        StreamMetadataConfig::Shoutcast {
+            url,
+            password,
+            sid: Some(sid),
+            template,
+        } => client
+            .get(format!("{}/admin.cgi", url.trim_end_matches('/')))
+            .query(&[
+                ("sid", sid.to_string().as_str()),
+                ("mode", "updinfo"),
+                ("song", &template::render(template, spin)),
+            ])
+            .basic_auth(SHOUTCAST_ADMIN, Some(password)),
+    };
+    request.send().await?.error_for_status()?;
+    debug!("Updated stream metadata at {}", config.url());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::broadcast;
+    use warp::http::StatusCode;
+
+    use crate::config::StreamMetadataConfig;
+    use crate::events::Event;
+    use crate::sinks::stand_in;
+
+    use super::run;
+
+    fn spin() -> serde_json::Value {
+        json!({"id": 2, "artist": "Nina Simone", "song": "Sinnerman"})
+    }
+
+    #[tokio::test]
+    async fn test_icecast() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "<return>1</return>");
+        let (tx, rx) = broadcast::channel(4);
+        tokio::spawn(run(
+            StreamMetadataConfig::Icecast {
+                url,
+                mount: "/live".to_string(),
+                username: "admin".to_string(),
+                password: "hackme".to_string(),
+                template: "{artist} - {song}".to_string(),
+            },
+            rx,
+        ));
+
+        tx.send(Event::ShowChanged(json!({"id": 10}))).unwrap();
+        tx.send(Event::SpinChanged(spin())).unwrap();
+        let request = requests.recv().await.unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/admin/metadata");
+        assert_eq!(request.query["mount"], "/live");
+        assert_eq!(request.query["mode"], "updinfo");
+        assert_eq!(request.query["song"], "Nina Simone - Sinnerman");
+        // admin:hackme
+        assert_eq!(request.authorization.unwrap(), "Basic YWRtaW46aGFja21l");
+    }
+
+    #[tokio::test]
+    async fn test_shoutcast() {
+        let (url, mut requests) = stand_in::serve(StatusCode::OK, "");
+        let (tx, rx) = broadcast::channel(4);
+        tokio::spawn(run(
+            StreamMetadataConfig::Shoutcast {
+                url: url.clone(),
+                password: "hackme".to_string(),
+                sid: None,
+                template: "{song}".to_string(),
+            },
+            rx,
+        ));
+        tokio::spawn(run(
+            StreamMetadataConfig::Shoutcast {
+                url,
+                password: "hackme".to_string(),
+                sid: Some(2),
+                template: "{song}".to_string(),
+            },
+            tx.subscribe(),
+        ));
+
+        tx.send(Event::SpinChanged(spin())).unwrap();
+        let mut requests = [
+            requests.recv().await.unwrap(),
+            requests.recv().await.unwrap(),
+        ];
+        requests.sort_by_key(|request| request.query.contains_key("sid"));
+
+        assert_eq!(requests[0].path, "/admin.cgi");
+        assert_eq!(requests[0].query["pass"], "hackme");
+        assert_eq!(requests[0].query["song"], "Sinnerman");
+        assert!(requests[0].authorization.is_none());
+        assert_eq!(requests[1].query["sid"], "2");
+        assert!(requests[1].authorization.is_some());
+        assert!(!requests[1].query.contains_key("pass"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::ops::Range;
+
+use serde_json::Value;
+
+/// Replaces each `{field}` in `template` with that field of `record`, e.g.
+/// "{artist} - {song}". Nested fields are reached with dots, like
+/// `{show.title}`. Missing and null fields become empty, and lists of names
+/// (like a show's `djs`) are joined with commas.
+pub fn render(template: &str, record: &Value) -> String {
+    render_with_spans(template, record).0
+}
+
+/// Like `render`, also returning where each field ended up in the result,
+/// as character (not byte) ranges.
+pub fn render_with_spans(template: &str, record: &Value) -> (String, Vec<(String, Range<usize>)>) {
+    let mut rendered = String::with_capacity(template.len());
+    let mut spans = Vec::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        match after.find('}') {
+            Some(close) if is_path(&after[..close]) => {
+                let path = &after[..close];
+                let start = rendered.chars().count();
+                rendered.push_str(&text(lookup(record, path)));
+                spans.push((path.to_string(), start..rendered.chars().count()));
+                rest = &after[close + 1..];
+            }
+            // Not a placeholder, e.g. a brace in JSON
+            _ => {
+                rendered.push('{');
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    (rendered, spans)
+}
+
+/// One field of `record`, as `render` would put it in the text.
+pub fn field(record: &Value, path: &str) -> String {
+    text(lookup(record, path))
+}
+
+/// The field `template` consists of, as it is in `record`, if it's a single
+/// placeholder like "{duration}".
+pub fn whole_field<'a>(template: &str, record: &'a Value) -> Option<&'a Value> {
+    let path = template.strip_prefix('{')?.strip_suffix('}')?;
+    is_path(path).then(|| lookup(record, path))
+}
+
+fn is_path(path: &str) -> bool {
+    !path.is_empty()
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn lookup<'a>(record: &'a Value, path: &str) -> &'a Value {
+    path.split('.').fold(record, |value, field| &value[field])
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::Object(_) => text(&item["name"]),
+                _ => text(item),
+            })
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join(", "),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{render, render_with_spans};
+
+    #[test]
+    fn test_render() {
+        let record = json!({
+            "id": 2,
+            "artist": "Nina Simone",
+            "song": "Sinnerman",
+            "label": null,
+            "show": {"title": "Morning Jazz", "djs": [{"name": "DJ Cool"}, {"name": "Sam"}]}
+        });
+
+        assert_eq!(
+            render("{artist} - {song}", &record),
+            "Nina Simone - Sinnerman"
+        );
+        assert_eq!(
+            render(
+                "#{id} {label}{release} on {show.title} with {show.djs}",
+                &record
+            ),
+            "#2  on Morning Jazz with DJ Cool, Sam"
+        );
+        assert_eq!(
+            render(r#"{"text": "{song}"} {unclosed"#, &record),
+            r#"{"text": "Sinnerman"} {unclosed"#
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let record = json!({"artist": "Sigur Rós", "song": "Hoppípolla"});
+        let (rendered, spans) = render_with_spans("♪ {artist} – {song}", &record);
+
+        assert_eq!(rendered, "♪ Sigur Rós – Hoppípolla");
+        assert_eq!(
+            spans,
+            vec![("artist".to_string(), 2..11), ("song".to_string(), 14..24)]
+        );
+    }
+}
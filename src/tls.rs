@@ -0,0 +1,235 @@
+use std::{fs, io, net::SocketAddr, time::Duration};
+
+use tokio::sync::oneshot;
+use warp::{
+    filters::path::FullPath,
+    http::{StatusCode, Uri},
+    Filter, Reply,
+};
+
+use crate::config::TlsConfig;
+
+// How many times to retry binding while the previous listener shuts down
+const BIND_ATTEMPTS: u32 = 20;
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+#[derive(PartialEq)]
+struct CertPair {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+}
+
+// Reads and sanity-checks the PEM files. warp happily serves an empty certificate
+// chain, so a half-written file has to be caught here.
+fn read_pair(tls: &TlsConfig) -> io::Result<CertPair> {
+    let pair = CertPair {
+        cert: fs::read(&tls.cert_path)?,
+        key: fs::read(&tls.key_path)?,
+    };
+
+    let certs = rustls_pemfile::certs(&mut pair.cert.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no certificates found in cert_path",
+        ));
+    }
+    if rustls_pemfile::private_key(&mut pair.key.as_slice())?.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no private key found in key_path",
+        ));
+    }
+    Ok(pair)
+}
+
+/// Serves `filter` over HTTPS forever. The certificate and key are re-read every
+/// `reload_interval_secs`; once a changed pair has been seen on two consecutive
+/// checks (so a renewal that writes the files one at a time isn't caught halfway),
+/// the listener is restarted with it. Existing connections (e.g. SSE streams) finish
+/// on the old certificate. If the new pair can't be loaded the old one stays in service.
+pub async fn serve<F>(filter: F, tls: &TlsConfig, addr: SocketAddr)
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let mut current =
+        read_pair(tls).unwrap_or_else(|e| panic!("Couldn't read TLS certificate or key: {}", e));
+    let mut shutdown = bind(&filter, &current, addr)
+        .unwrap_or_else(|e| panic!("Couldn't start HTTPS listener on {}: {}", addr, e));
+    info!("Serving HTTPS on {}", addr);
+
+    let mut pending: Option<CertPair> = None;
+    // Remember a pair that failed to load so it isn't retried every interval
+    let mut rejected: Option<CertPair> = None;
+    let mut interval = tokio::time::interval(Duration::from_secs(tls.reload_interval_secs));
+    loop {
+        interval.tick().await;
+
+        let pair = match read_pair(tls) {
+            Ok(pair) => pair,
+            Err(e) => {
+                warn!(
+                    "Couldn't read TLS files, keeping current certificate: {}",
+                    e
+                );
+                continue;
+            }
+        };
+        if pair == current || rejected.as_ref() == Some(&pair) {
+            pending = None;
+            continue;
+        }
+        if pending.as_ref() != Some(&pair) {
+            pending = Some(pair);
+            continue;
+        }
+        pending = None;
+
+        info!("TLS certificate changed on disk, reloading.");
+        let _ = shutdown.send(());
+        match bind_with_retry(&filter, &pair, addr).await {
+            Ok(tx) => {
+                shutdown = tx;
+                current = pair;
+                rejected = None;
+                info!("TLS certificate reloaded.");
+            }
+            Err(e) => {
+                error!(
+                    "Couldn't load new TLS certificate, keeping the old one: {}",
+                    e
+                );
+                shutdown = bind_with_retry(&filter, &current, addr)
+                    .await
+                    .unwrap_or_else(|e| panic!("Couldn't restore HTTPS listener: {}", e));
+                rejected = Some(pair);
+            }
+        }
+    }
+}
+
+// Starts a TLS listener in the background, returning the sender that shuts it down
+fn bind<F>(
+    filter: &F,
+    pair: &CertPair,
+    addr: SocketAddr,
+) -> Result<oneshot::Sender<()>, warp::Error>
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let (tx, rx) = oneshot::channel::<()>();
+    let (_, server) = warp::serve(filter.clone())
+        .tls()
+        .cert(&pair.cert)
+        .key(&pair.key)
+        .try_bind_with_graceful_shutdown(addr, async {
+            rx.await.ok();
+        })?;
+    tokio::spawn(server);
+    Ok(tx)
+}
+
+// The old listener only releases its port once its task observes the shutdown signal
+async fn bind_with_retry<F>(
+    filter: &F,
+    pair: &CertPair,
+    addr: SocketAddr,
+) -> Result<oneshot::Sender<()>, warp::Error>
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let mut attempt = 1;
+    loop {
+        match bind(filter, pair, addr) {
+            Ok(tx) => return Ok(tx),
+            Err(e) if attempt >= BIND_ATTEMPTS => return Err(e),
+            Err(_) => {
+                attempt += 1;
+                tokio::time::sleep(BIND_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Plain-HTTP filter that sends every request to the same host and path over HTTPS.
+pub fn redirect(
+    https_port: u16,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("host")
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(move |host: Option<String>, path: FullPath, query: String| {
+            match host.and_then(|host| https_uri(&host, https_port, path.as_str(), &query)) {
+                Some(uri) => warp::redirect::permanent(uri).into_response(),
+                None => {
+                    warp::reply::with_status("Bad Request", StatusCode::BAD_REQUEST).into_response()
+                }
+            }
+        })
+}
+
+fn https_uri(host: &str, port: u16, path: &str, query: &str) -> Option<Uri> {
+    // Drop any port from the Host header, leaving IPv6 literals like [::1] intact
+    let hostname = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    if hostname.is_empty() {
+        return None;
+    }
+
+    let mut uri = format!("https://{}", hostname);
+    if port != 443 {
+        uri += &format!(":{}", port);
+    }
+    uri += path;
+    if !query.is_empty() {
+        uri.push('?');
+        uri += query;
+    }
+    uri.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+    use warp::test::request;
+
+    use super::redirect;
+
+    #[tokio::test]
+    async fn test_redirect_keeps_path_and_query() {
+        let resp = request()
+            .path("/spins/get?count=5")
+            .header("Host", "relay.example.org:80")
+            .reply(&redirect(443))
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            resp.headers()["location"],
+            "https://relay.example.org/spins/get?count=5"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redirect_nonstandard_port() {
+        let resp = request()
+            .path("/healthCheck")
+            .header("Host", "[::1]:8080")
+            .reply(&redirect(8443))
+            .await;
+
+        assert_eq!(resp.headers()["location"], "https://[::1]:8443/healthCheck");
+    }
+
+    #[tokio::test]
+    async fn test_redirect_requires_host() {
+        let resp = request().path("/spins/get").reply(&redirect(443)).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,126 @@
+use chrono::DateTime;
+use serde_json::{json, Map, Value};
+
+// Spinitron sends times like "2024-03-01T12:00:00+0000"
+const SPINITRON_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%z";
+
+// Fields holding a point in time, rewritten as RFC 3339 ("2024-03-01T12:00:00+00:00")
+const TIMESTAMP_FIELDS: &[&str] = &["start", "end"];
+
+/// Builds the /v2/spins document from Spinitron's raw spins response:
+/// `{"spins": [...]}`, most recent first.
+pub fn spins(raw: &Value) -> Value {
+    let spins: Vec<Value> = items(raw).iter().map(clean).collect();
+    json!({ "spins": spins })
+}
+
+/// Builds the /v2/shows document from Spinitron's raw shows response and the
+/// DJs fetched for each show, in the same order: `{"shows": [{..., "djs": [...]}]}`.
+pub fn shows(raw: &Value, djs: &[Vec<Value>]) -> Value {
+    let shows: Vec<Value> = items(raw)
+        .iter()
+        .enumerate()
+        .map(|(i, show)| {
+            let mut show = clean(show);
+            let show_djs: Vec<Value> = djs.get(i).into_iter().flatten().map(clean).collect();
+            show["djs"] = Value::Array(show_djs);
+            show
+        })
+        .collect();
+    json!({ "shows": shows })
+}
+
+fn items(raw: &Value) -> &[Value] {
+    raw["items"].as_array().map(Vec::as_slice).unwrap_or(&[])
+}
+
+// Drops Spinitron's hypermedia links and normalizes timestamps
+fn clean(item: &Value) -> Value {
+    let mut cleaned = Map::new();
+    if let Some(fields) = item.as_object() {
+        for (key, value) in fields {
+            if key == "_links" {
+                continue;
+            }
+            let value = match value.as_str() {
+                Some(time) if TIMESTAMP_FIELDS.contains(&key.as_str()) => {
+                    match DateTime::parse_from_str(time, SPINITRON_TIME_FORMAT) {
+                        Ok(time) => Value::String(time.to_rfc3339()),
+                        Err(_) => value.clone(),
+                    }
+                }
+                _ => value.clone(),
+            };
+            cleaned.insert(key.clone(), value);
+        }
+    }
+    Value::Object(cleaned)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{shows, spins};
+
+    #[test]
+    fn test_spins() {
+        let raw = json!({
+            "items": [
+                {
+                    "id": 2,
+                    "artist": "Nina Simone",
+                    "start": "2024-03-01T12:03:00-0800",
+                    "_links": {"self": {"href": "https://spinitron.com/api/spins/2"}}
+                },
+                {"id": 1, "artist": "Sun Ra", "start": "not a time"}
+            ],
+            "_links": {}
+        });
+
+        assert_eq!(
+            spins(&raw),
+            json!({
+                "spins": [
+                    {"id": 2, "artist": "Nina Simone", "start": "2024-03-01T12:03:00-08:00"},
+                    {"id": 1, "artist": "Sun Ra", "start": "not a time"}
+                ]
+            })
+        );
+        assert_eq!(spins(&json!({})), json!({"spins": []}));
+    }
+
+    #[test]
+    fn test_shows_with_djs() {
+        let raw = json!({
+            "items": [
+                {
+                    "id": 10,
+                    "title": "Morning Jazz",
+                    "end": "2024-03-01T14:00:00+0000",
+                    "_links": {}
+                },
+                {"id": 11, "title": "Afternoon Drive"}
+            ]
+        });
+        let djs = vec![
+            vec![json!({"id": 5, "name": "DJ Cool", "_links": {}})],
+            vec![],
+        ];
+
+        assert_eq!(
+            shows(&raw, &djs),
+            json!({
+                "shows": [
+                    {
+                        "id": 10,
+                        "title": "Morning Jazz",
+                        "end": "2024-03-01T14:00:00+00:00",
+                        "djs": [{"id": 5, "name": "DJ Cool"}]
+                    },
+                    {"id": 11, "title": "Afternoon Drive", "djs": []}
+                ]
+            })
+        );
+    }
+}
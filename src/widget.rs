@@ -0,0 +1,229 @@
+use std::{collections::HashMap, fs, sync::OnceLock};
+
+use warp::{reject::Reject, Filter, Rejection};
+
+use crate::config::{self, WidgetConfig};
+
+/// The widget markup used when no `template_path` is configured.
+pub const DEFAULT_TEMPLATE: &str = include_str!("widget.html");
+
+// The spin cache only ever holds this many
+const MAX_RECENT: u8 = 10;
+
+// Shown above the spin, depending on /now's `on_air`
+const ON_AIR_LABEL: &str = "Now playing";
+const OFF_AIR_LABEL: &str = "Off air";
+
+static TEMPLATE: OnceLock<String> = OnceLock::new();
+
+/// How a partner site wants the widget to look, from the query string.
+#[derive(Debug, PartialEq)]
+pub struct Theme {
+    pub background: String,
+    pub text: String,
+    pub accent: String,
+    pub compact: bool,
+    // Recent spins listed under the current one
+    pub recent: u8,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            background: "#ffffff".to_string(),
+            text: "#222222".to_string(),
+            accent: "#b30738".to_string(),
+            compact: false,
+            recent: 0,
+        }
+    }
+}
+
+/// Rejection for a widget query parameter that isn't valid. Turned into a
+/// 400 by `filters::handle_rejection`.
+#[derive(Debug)]
+pub struct InvalidTheme(pub String);
+
+impl Reject for InvalidTheme {}
+
+impl Theme {
+    /// Parses the theme parameters, ignoring any others (like `api_key`).
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Theme, InvalidTheme> {
+        let mut theme = Theme::default();
+        for (name, value) in query {
+            match name.as_str() {
+                "bg" => theme.background = color(name, value, true)?,
+                "fg" => theme.text = color(name, value, false)?,
+                "accent" => theme.accent = color(name, value, false)?,
+                "compact" => {
+                    theme.compact = match value.as_str() {
+                        "" | "1" | "true" => true,
+                        "0" | "false" => false,
+                        _ => return Err(invalid(name, value)),
+                    }
+                }
+                "recent" => {
+                    theme.recent = value
+                        .parse()
+                        .ok()
+                        .filter(|recent| *recent <= MAX_RECENT)
+                        .ok_or_else(|| invalid(name, value))?
+                }
+                _ => {}
+            }
+        }
+        Ok(theme)
+    }
+}
+
+fn invalid(name: &str, value: &str) -> InvalidTheme {
+    InvalidTheme(format!("Invalid value for {}: \"{}\"", name, value))
+}
+
+// "fff", "#fff" or "1a2b3c", returned as "#1a2b3c". Backgrounds may also be
+// "transparent", so the widget takes on the page's own background.
+fn color(name: &str, value: &str, transparent_ok: bool) -> Result<String, InvalidTheme> {
+    if transparent_ok && value == "transparent" {
+        return Ok(value.to_string());
+    }
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if !matches!(hex.len(), 3 | 6) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid(name, value));
+    }
+    let hex = if hex.len() == 3 {
+        hex.chars().flat_map(|c| [c, c]).collect()
+    } else {
+        hex.to_string()
+    };
+    Ok(format!("#{}", hex.to_ascii_lowercase()))
+}
+
+/// Fills in the template's `{{placeholders}}`. Every value has been
+/// validated, so none of them need escaping.
+pub fn render(template: &str, theme: &Theme) -> String {
+    let mode = if theme.compact { "compact" } else { "full" };
+    let recent = theme.recent.to_string();
+    [
+        ("{{background}}", theme.background.as_str()),
+        ("{{text}}", theme.text.as_str()),
+        ("{{accent}}", theme.accent.as_str()),
+        ("{{mode}}", mode),
+        ("{{recent}}", recent.as_str()),
+        ("{{on_air_label}}", status_label(true)),
+        ("{{off_air_label}}", status_label(false)),
+    ]
+    .iter()
+    .fold(template.to_string(), |page, (placeholder, value)| {
+        page.replace(placeholder, value)
+    })
+}
+
+/// The status label for whether a show is on air, as opposed to
+/// automation or dead air.
+pub fn status_label(on_air: bool) -> &'static str {
+    match on_air {
+        true => ON_AIR_LABEL,
+        false => OFF_AIR_LABEL,
+    }
+}
+
+/// The configured template, or the built-in one. Read once, at startup.
+pub fn template() -> &'static str {
+    TEMPLATE.get_or_init(|| load(&config::get().widget))
+}
+
+fn load(config: &WidgetConfig) -> String {
+    match &config.template_path {
+        Some(path) => fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Couldn't read widget template {}: {}", path.display(), e)),
+        None => DEFAULT_TEMPLATE.to_string(),
+    }
+}
+
+pub fn theme() -> impl Filter<Extract = (Theme,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and_then(|query| async move { Theme::from_query(&query).map_err(warp::reject::custom) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{render, status_label, Theme, DEFAULT_TEMPLATE};
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_theme() {
+        let theme = Theme::from_query(&query(&[
+            ("bg", "transparent"),
+            ("fg", "#FFF"),
+            ("accent", "1a2b3c"),
+            ("compact", ""),
+            ("recent", "5"),
+            ("api_key", "abc"),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            theme,
+            Theme {
+                background: "transparent".to_string(),
+                text: "#ffffff".to_string(),
+                accent: "#1a2b3c".to_string(),
+                compact: true,
+                recent: 5,
+            }
+        );
+        assert_eq!(Theme::from_query(&query(&[])).unwrap(), Theme::default());
+    }
+
+    #[test]
+    fn test_invalid_theme() {
+        for (name, value) in [
+            ("fg", "red"),
+            ("fg", "transparent"),
+            ("accent", "#12345"),
+            ("bg", "fff;}body{display:none"),
+            ("compact", "yes"),
+            ("recent", "11"),
+            ("recent", "-1"),
+        ] {
+            let err = Theme::from_query(&query(&[(name, value)])).unwrap_err();
+            assert!(err.0.contains(name), "{}", err.0);
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let theme = Theme {
+            compact: true,
+            recent: 3,
+            ..Theme::default()
+        };
+
+        assert_eq!(
+            render("{{mode}} {{accent}} {{recent}} {{unknown}}", &theme),
+            "compact #b30738 3 {{unknown}}"
+        );
+        let page = render(DEFAULT_TEMPLATE, &theme);
+        assert!(
+            !page.contains("{{"),
+            "placeholder left in the default template"
+        );
+    }
+
+    #[test]
+    fn test_status_labels() {
+        assert_eq!(status_label(true), "Now playing");
+        assert_eq!(status_label(false), "Off air");
+
+        let page = render(DEFAULT_TEMPLATE, &Theme::default());
+        assert!(page.contains(r#"now.on_air ? "Now playing" : "Off air""#));
+    }
+}